@@ -12,7 +12,11 @@ pub const IPFS_HASH_LEN: usize = 46;
 pub static NIP_MAGIC: &[u8] = b"NIPNIP";
 
 /// Current protocol version; must be bumped for every breaking format change
-pub const NIP_PROTOCOL_VERSION: u16 = 2; // Bump on breaking data structure changes
+pub const NIP_PROTOCOL_VERSION: u16 = 3; // Bump on breaking data structure changes
+
+/// The oldest protocol version whose objects are still read as they are. Version 3 only changed
+/// the index format, so objects and shards written by version 2 are read alongside current ones.
+pub const NIP_MIN_OBJECT_VERSION: u16 = 2;
 
 #[allow(missing_docs)]
 pub const NIP_HEADER_LEN: usize = 8;
@@ -21,3 +25,17 @@ pub const NIP_HEADER_LEN: usize = 8;
 /// Locally git knows a commit is a submodule tip because it's the only case when a tree entry is a
 /// commit. However, this relationship is impossible to express in a NIP index implicitly.
 pub static SUBMODULE_TIP_MARKER: &str = "submodule-tip";
+
+/// How many content store requests push and fetch keep in flight by default
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+/// The default size limit of a repository's object cache, in bytes
+pub const DEFAULT_CACHE_MAX_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Indices with more objects than this keep them in a trie of separately uploaded shards of at most
+/// this many objects each, so that publishing re-uploads only the shards that changed
+pub const MAX_SHARD_OBJECTS: usize = 4096;
+
+/// Publishing writes a full index instead of a delta once this many deltas follow the last full
+/// one, which bounds the number of downloads needed to read an index
+pub const MAX_DELTA_CHAIN: usize = 16;
@@ -2,30 +2,46 @@
 use super::serde_cbor;
 
 use failure::Error;
-use git2::{Object, ObjectType, Oid, Repository};
-use ipfs_api::IpfsClient;
+use futures::{
+    future::{self, Loop},
+    stream, Future, Stream,
+};
+use git2::{Object, ObjectType, Odb, Oid, Repository};
 use tokio::runtime::current_thread;
 
 use std::{
     cmp::Ordering,
-    collections::{BTreeMap, HashSet},
-    io::Cursor,
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt, fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    slice,
     time::Instant,
 };
 
 use crate::{
-    constants::{NIP_HEADER_LEN, NIP_PROTOCOL_VERSION, SUBMODULE_TIP_MARKER},
+    constants::{
+        MAX_DELTA_CHAIN, MAX_SHARD_OBJECTS, NIP_HEADER_LEN, NIP_PROTOCOL_VERSION,
+        SUBMODULE_TIP_MARKER,
+    },
     error::NIPError,
+    journal::PushJournal,
     object::{NIPObject, NIPObjectMetadata},
     remote::NIPRemote,
-    util::{gen_nip_header, ipfs_cat, ipns_deref, parse_nip_header},
+    shard::{self, ShardCache},
+    store::{bare_hash, ContentStore, FsStore, StoreFuture},
+    transfer::{PhaseTracker, TransferOptions, TransferPhase},
+    util::{gen_nip_header, parse_nip_header, reopen_repo, write_atomic},
 };
 
 /// The entrypoint data structure for every nip repo.
 ///
 /// Every top-level nip IPFS link points at a `NIPIndex`. nip indices store information about all
 /// git objects contained within a given nip repository.
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+///
+/// Comparisons and hashing only look at what the index contains, i.e. `refs`, `objects`,
+/// `prev_idx_hash` and `merged_idx_hash`; where it was downloaded from doesn't matter.
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct NIPIndex {
     /// All refs this repository knows; a {name -> sha1} mapping
     pub refs: BTreeMap<String, String>,
@@ -33,6 +49,262 @@ pub struct NIPIndex {
     pub objects: BTreeMap<String, String>,
     /// The IPFS hash of the previous index
     pub prev_idx_hash: Option<String>,
+    /// The IPFS hash of the second parent of an index produced by `merge()`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merged_idx_hash: Option<String>,
+    /// The IPFS link this index was downloaded from or last uploaded to, if any. It's never
+    /// serialized nor compared; `ipfs_add()` uses it to detect other writers publishing to the same IPNS name.
+    #[serde(skip)]
+    pub base_idx_hash: Option<String>,
+    /// The shards `objects` was last stored in, for indices too big to upload in one piece
+    #[serde(skip)]
+    pub(crate) shards: ShardCache,
+    /// What `ipfs_add()` needs to know about the index at `base_idx_hash` to publish a delta
+    /// against it
+    #[serde(skip)]
+    pub(crate) base: IndexBase,
+    /// The directory of the file remote the index was read from or written to, if any. Pushes and
+    /// fetches keep objects in the `FsStore` there instead of the store they're given.
+    #[serde(skip)]
+    pub(crate) file_store: Option<PathBuf>,
+}
+
+/// What an index was like when it was last downloaded or uploaded. Instead of its contents,
+/// only the base values of the refs and objects changed through `apply()` or `merge()` since are
+/// kept; a fingerprint of the base tells whether anything else changed, in which case
+/// `ipfs_add()` publishes a full index.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IndexBase(Option<BaseState>);
+
+#[derive(Clone, Debug)]
+struct BaseState {
+    merged_idx_hash: Option<String>,
+    /// The number of deltas between the base and the last full index
+    delta_depth: usize,
+    /// The `fingerprint()` of the base's refs and objects
+    fingerprint: u64,
+    /// The base values of the refs changed since, `None` for refs the base didn't have
+    refs_touched: BTreeMap<String, Option<String>>,
+    /// The base values of the objects changed since, `None` for objects the base didn't have
+    objects_touched: BTreeMap<String, Option<String>>,
+}
+
+impl IndexBase {
+    fn of(idx: &NIPIndex, delta_depth: usize) -> Self {
+        IndexBase(Some(BaseState {
+            merged_idx_hash: idx.merged_idx_hash.clone(),
+            delta_depth,
+            fingerprint: fingerprint(&idx.refs, &idx.objects),
+            refs_touched: BTreeMap::new(),
+            objects_touched: BTreeMap::new(),
+        }))
+    }
+
+    /// Record that `ref_name` held `old` before being changed
+    fn touch_ref(&mut self, ref_name: &str, old: Option<String>) {
+        if let Some(ref mut base) = self.0 {
+            base.refs_touched.entry(ref_name.to_owned()).or_insert(old);
+        }
+    }
+
+    /// Record that `git_hash` was linked to `old` before being changed
+    fn touch_object(&mut self, git_hash: &str, old: Option<String>) {
+        if let Some(ref mut base) = self.0 {
+            base.objects_touched
+                .entry(git_hash.to_owned())
+                .or_insert(old);
+        }
+    }
+}
+
+impl PartialEq for NIPIndex {
+    fn eq(&self, other: &Self) -> bool {
+        self.refs == other.refs
+            && self.objects == other.objects
+            && self.prev_idx_hash == other.prev_idx_hash
+            && self.merged_idx_hash == other.merged_idx_hash
+    }
+}
+
+impl Eq for NIPIndex {}
+
+impl Hash for NIPIndex {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.refs.hash(state);
+        self.objects.hash(state);
+        self.prev_idx_hash.hash(state);
+        self.merged_idx_hash.hash(state);
+    }
+}
+
+/// The serialized form of an index. Indices with over `MAX_SHARD_OBJECTS` objects keep them in a
+/// trie of shards; `objects` is then left out and `object_root` links to the trie instead, which
+/// also keeps nip versions unaware of sharding from misreading such an index as empty.
+///
+/// Deltas store neither; their `delta` holds the changes made to the index at `prev_idx_hash`.
+#[derive(Deserialize, Serialize)]
+struct StoredIndex {
+    refs: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    objects: Option<BTreeMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    object_root: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delta: Option<IndexDelta>,
+    prev_idx_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    merged_idx_hash: Option<String>,
+}
+
+/// The changes an index makes to the one at its `prev_idx_hash`
+#[derive(Debug, Deserialize, Serialize)]
+struct IndexDelta {
+    /// The number of deltas leading back to a full index, this one included
+    depth: usize,
+    /// New and moved refs
+    refs_set: BTreeMap<String, String>,
+    refs_removed: BTreeSet<String>,
+    objects_added: BTreeMap<String, String>,
+    objects_removed: BTreeSet<String>,
+}
+
+impl IndexDelta {
+    /// Returns the number of entries the delta changes
+    fn len(&self) -> usize {
+        self.refs_set.len()
+            + self.refs_removed.len()
+            + self.objects_added.len()
+            + self.objects_removed.len()
+    }
+
+    fn apply_to(self, idx: &mut NIPIndex) {
+        for ref_name in &self.refs_removed {
+            idx.refs.remove(ref_name);
+        }
+        idx.refs.extend(self.refs_set);
+        for git_hash in &self.objects_removed {
+            idx.objects.remove(git_hash);
+        }
+        idx.objects.extend(self.objects_added);
+    }
+}
+
+/// Index modifications staged by a push. Uploads are recorded in the change set as they complete
+/// and `NIPIndex::apply()` merges it into the index once nothing is pending, so a failed push
+/// never leaves the index claiming partial history.
+///
+/// Saving a change set before uploading it makes the push resumable after a crash:
+///
+/// ```rust,no_run
+/// # extern crate failure;
+/// # extern crate git2;
+/// # extern crate ipfs_api;
+/// # extern crate nip_core;
+/// # use failure::Error;
+/// # use git2::Repository;
+/// # use ipfs_api::IpfsClient;
+/// # use nip_core::{ChangeSet, NIPIndex, NIPRemote, TransferOptions};
+/// # fn main() -> Result<(), Error> {
+/// # let repo = Repository::open_from_env()?;
+/// # let mut ipfs = IpfsClient::default();
+/// # let opts = TransferOptions::default();
+/// # let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs)?;
+/// let staged_path = repo.path().join("nip").join("staged");
+///
+/// let mut changes = idx.stage_ref_push(
+///     "refs/heads/master",
+///     "refs/heads/master",
+///     false,
+///     &repo,
+///     &mut ipfs,
+///     &opts,
+/// )?;
+/// changes.save(&staged_path)?;
+/// changes.upload(&repo, &mut ipfs, &opts)?;
+/// idx.apply(changes)?;
+///
+/// // After a crash, the same index picks up where the upload left off. `apply()` refuses with
+/// // `NIPIndexError::StaleRef` if any of the pushed refs moved since staging.
+/// let mut changes = ChangeSet::load(&staged_path)?;
+/// changes.upload(&repo, &mut ipfs, &opts)?;
+/// idx.apply(changes)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ChangeSet {
+    /// Staged ref updates; a {name -> sha1} map, `None` meaning deletion
+    pub refs: BTreeMap<String, Option<String>>,
+    /// Objects ready to be added to the index; a {sha1 -> IPFS hash} map
+    pub objects: BTreeMap<String, String>,
+    /// Objects still waiting for upload; a set of sha1s
+    pub pending: BTreeSet<String>,
+    /// The values refs must have for the change set to apply; a {name -> sha1} map, `None`
+    /// meaning the ref must not exist. Staging a push leases every ref it updates, on the
+    /// expected value of a leased push or on the ref's value at staging time otherwise.
+    #[serde(default)]
+    pub leases: BTreeMap<String, Option<String>>,
+    /// The directory of the file remote the objects are uploaded to, like `NIPIndex::file_store`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) file_store: Option<PathBuf>,
+}
+
+/// What a push did to a single ref, mirroring the statuses reported by `git push`. Rejected refs
+/// are left untouched in the index.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[must_use]
+pub enum PushOutcome {
+    /// The ref didn't exist before
+    Created,
+    /// The ref was moved forward
+    FastForwarded,
+    /// The ref was overwritten by a forced push
+    Forced,
+    /// The ref was removed
+    Deleted,
+    /// The ref already pointed at the pushed object
+    UpToDate,
+    /// The ref holds history missing from the local repository; fetch first or force the push
+    RejectedFetchFirst,
+    /// The pushed object doesn't descend from the ref; merge first or force the push
+    RejectedNonFastForward,
+    /// The local ref to push doesn't exist
+    RejectedMissingSrc,
+    /// The ref to delete doesn't exist
+    RejectedMissingDst,
+    /// The ref doesn't have the value the push was leased on
+    RejectedStale,
+}
+
+impl PushOutcome {
+    /// Returns true if the ref was left alone because of a problem
+    pub fn is_rejected(self) -> bool {
+        matches!(
+            self,
+            PushOutcome::RejectedFetchFirst
+                | PushOutcome::RejectedNonFastForward
+                | PushOutcome::RejectedMissingSrc
+                | PushOutcome::RejectedMissingDst
+                | PushOutcome::RejectedStale
+        )
+    }
+
+    /// Returns the line a git remote helper reports for `ref_dst` in response to a `push`
+    /// command, e.g. `ok refs/heads/master` or `error refs/heads/master non-fast-forward`
+    pub fn helper_status(self, ref_dst: &str) -> String {
+        match self {
+            PushOutcome::RejectedFetchFirst => format!("error {} fetch first", ref_dst),
+            PushOutcome::RejectedNonFastForward => format!("error {} non-fast-forward", ref_dst),
+            PushOutcome::RejectedMissingSrc => {
+                format!("error {} src refspec does not match any", ref_dst)
+            }
+            PushOutcome::RejectedMissingDst => {
+                format!("error {} remote ref does not exist", ref_dst)
+            }
+            PushOutcome::RejectedStale => format!("error {} stale info", ref_dst),
+            _ => format!("ok {}", ref_dst),
+        }
+    }
 }
 
 #[derive(Debug, Fail)]
@@ -41,35 +313,332 @@ pub enum NIPIndexError {
     /// There's objects in the index not present in the local repo - a pull is needed
     #[fail(display = "fetch first")]
     FetchFirst,
+    /// The pushed object doesn't descend from the ref it's meant to replace
+    #[fail(display = "non-fast-forward")]
+    NonFastForward,
+    /// A ref no longer has the value a push was leased on
+    #[fail(display = "stale info for {}", _0)]
+    StaleRef(String),
+    /// Another writer published to the IPNS name since the index was downloaded
+    #[fail(display = "remote moved from {} to {}, fetch first", expected, found)]
+    #[allow(missing_docs)]
+    ConcurrentWrite { expected: String, found: String },
+    /// A change set was applied before all of its objects were uploaded
+    #[fail(display = "{} object(s) still pending upload", _0)]
+    IncompleteChangeSet(usize),
+    /// The index keeps its objects in shards, which `from_slice()` can't download
+    #[fail(display = "index objects are sharded under {}, use decode()", _0)]
+    ShardedIndex(String),
+    /// The index is a delta on top of another one, which `from_slice()` can't download
+    #[fail(display = "index is a delta on top of {}, use decode()", _0)]
+    DeltaIndex(String),
+    /// The serialized index has neither an object map nor a shard trie
+    #[fail(display = "index has no objects")]
+    MissingObjects,
+    /// The index was never published nor downloaded, so there's no link to record for it
+    #[fail(display = "index has no link, publish it first")]
+    UnpublishedIndex,
 }
 
 impl NIPIndex {
-    /// Download from IPFS and instantiate a NIPIndex
-    pub fn from_nip_remote(remote: &NIPRemote, ipfs: &mut IpfsClient) -> Result<Self, Error> {
+    /// Download from `ipfs` and instantiate a NIPIndex. `ExistingFile` remotes are read straight
+    /// from their `FsStore` directory instead; pushes and fetches on an index of a file remote
+    /// keep using that directory for its objects, whatever store they're given.
+    pub fn from_nip_remote<S: ContentStore>(
+        remote: &NIPRemote,
+        ipfs: &mut S,
+    ) -> Result<Self, Error> {
+        current_thread::block_on_all(Self::from_nip_remote_async(remote, ipfs))
+    }
+
+    /// `from_nip_remote()` with custom transfer options. The download is cached according to
+    /// `opts.cache`, with `repo` standing in for the local repository.
+    pub fn from_nip_remote_with_opts<S: ContentStore>(
+        remote: &NIPRemote,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<Self, Error> {
+        let mut ipfs = opts.cache.wrap(ipfs.clone(), repo)?;
+        Self::from_nip_remote(remote, &mut ipfs)
+    }
+
+    /// A non-blocking version of `from_nip_remote()`
+    pub fn from_nip_remote_async<S: ContentStore>(
+        remote: &NIPRemote,
+        ipfs: &mut S,
+    ) -> StoreFuture<Self> {
         match remote {
             NIPRemote::ExistingIPFS(ref hash) => {
                 debug!("Fetching NIPIndex from /ipfs/{}", hash);
-                let bytes = ipfs_cat(hash, ipfs)?;
+                let base_idx_hash = ipfs_link(hash);
+                let mut store = ipfs.clone();
+                Box::new(
+                    ipfs.cat(hash)
+                        .and_then(move |bytes| Self::decode_stored_async(&bytes[..], &mut store))
+                        .map(|(mut idx, delta_depth)| {
+                            idx.base_idx_hash = Some(base_idx_hash);
+                            idx.base = IndexBase::of(&idx, delta_depth);
+                            idx
+                        }),
+                )
+            }
+            NIPRemote::ExistingIPNS(ref hash) => {
+                let resolve_req = ipfs.name_resolve(hash);
+                let mut ipfs = ipfs.clone();
 
-                Ok(Self::from_slice(&bytes[..])?)
+                Box::new(
+                    resolve_req
+                        .and_then(|ipfs_link| ipfs_link.parse::<NIPRemote>())
+                        .and_then(move |remote| Self::from_nip_remote_async(&remote, &mut ipfs)),
+                )
             }
-            NIPRemote::ExistingIPNS(ref hash) => Ok(Self::from_nip_remote(
-                &ipns_deref(hash.as_str(), ipfs)?.parse()?,
-                ipfs,
-            )?),
-            NIPRemote::NewIPFS | NIPRemote::NewIPNS => {
+            NIPRemote::ExistingFile(ref path, ref hash) => {
+                debug!("Fetching NIPIndex from {}", remote.to_string());
+                match FsStore::open(path) {
+                    Ok(mut store) => {
+                        let base_idx_hash = ipfs_link(hash);
+                        let file_store = path.clone();
+                        Box::new(
+                            store
+                                .cat(hash)
+                                .and_then(move |bytes| {
+                                    Self::decode_stored_async(&bytes[..], &mut store)
+                                })
+                                .map(|(mut idx, delta_depth)| {
+                                    idx.base_idx_hash = Some(base_idx_hash);
+                                    idx.base = IndexBase::of(&idx, delta_depth);
+                                    idx.file_store = Some(file_store);
+                                    idx
+                                }),
+                        )
+                    }
+                    Err(e) => Box::new(future::err(e)),
+                }
+            }
+            NIPRemote::NewIPFS | NIPRemote::NewIPNS | NIPRemote::NewFile(_) => {
                 debug!("Creating new index");
-                Ok(NIPIndex {
+                Box::new(future::ok(NIPIndex {
                     refs: BTreeMap::new(),
                     objects: BTreeMap::new(),
                     prev_idx_hash: None,
-                })
+                    merged_idx_hash: None,
+                    base_idx_hash: None,
+                    shards: ShardCache::default(),
+                    base: IndexBase::default(),
+                    file_store: remote.file_path().map(Path::to_owned),
+                }))
             }
         }
     }
 
-    /// Take raw index bytes and build a `NIPIndex` from it
+    /// Walk the history of the index at `remote` following `prev_idx_hash`, newest first. Every
+    /// item is an index along with its `/ipfs/` link; `New*` remotes have no history.
+    ///
+    /// ```rust,no_run
+    /// # extern crate failure;
+    /// # extern crate ipfs_api;
+    /// # extern crate nip_core;
+    /// # use failure::Error;
+    /// # use ipfs_api::IpfsClient;
+    /// # use nip_core::{NIPIndex, NIPRemote};
+    /// # fn main() -> Result<(), Error> {
+    /// let remote: NIPRemote = "/ipns/QmdT2sVhj8UicZsGY7x687FgdJPrzR9idGyavi5282CPH3".parse()?;
+    /// for entry in NIPIndex::history(&remote, &mut IpfsClient::default()) {
+    ///     let (link, idx) = entry?;
+    ///     println!("{}: {:?}", link, idx.refs.get("refs/heads/master"));
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn history<S: ContentStore>(remote: &NIPRemote, ipfs: &mut S) -> IndexHistory {
+        IndexHistory {
+            entries: Some(Self::history_async(remote, ipfs)),
+        }
+    }
+
+    /// A non-blocking version of `history()`
+    pub fn history_async<S: ContentStore>(
+        remote: &NIPRemote,
+        ipfs: &mut S,
+    ) -> Box<dyn Stream<Item = (String, NIPIndex), Error = Error> + Send> {
+        let first = match remote {
+            NIPRemote::NewIPFS | NIPRemote::NewIPNS | NIPRemote::NewFile(_) => None,
+            NIPRemote::ExistingIPFS(_)
+            | NIPRemote::ExistingIPNS(_)
+            | NIPRemote::ExistingFile(..) => Some(remote.clone()),
+        };
+        // Predecessors of file-based indices live in the same store directory
+        let file_path = remote.file_path().map(Path::to_owned);
+        let mut ipfs = ipfs.clone();
+
+        Box::new(stream::unfold(first, move |next| {
+            let file_path = file_path.clone();
+            next.map(|remote| {
+                Self::from_nip_remote_async(&remote, &mut ipfs).and_then(move |idx| {
+                    let link = idx.base_idx_hash.clone().ok_or_else(|| {
+                        NIPError::InternalError(format!(
+                            "No link for index at {}",
+                            remote.to_string()
+                        ))
+                    })?;
+                    let prev = idx.prev_idx_hash.as_ref().map(|prev_idx_hash| {
+                        let hash = bare_hash(prev_idx_hash).to_owned();
+                        match file_path {
+                            Some(path) => NIPRemote::ExistingFile(path, hash),
+                            None => NIPRemote::ExistingIPFS(hash),
+                        }
+                    });
+                    trace!("History: {} -> {:?}", link, idx.prev_idx_hash);
+
+                    Ok(((link, idx), prev))
+                })
+            })
+        }))
+    }
+
+    /// Reconstruct the reflog of every ref that appears in the history of the index at `remote`,
+    /// deleted refs included. Each ref gets a list of changes, newest first, so that e.g. a
+    /// branch that was force-pushed away can be found again in the `old` of its latest entry.
+    pub fn reflog<S: ContentStore>(
+        remote: &NIPRemote,
+        ipfs: &mut S,
+    ) -> Result<BTreeMap<String, Vec<RefLogEntry>>, Error> {
+        current_thread::block_on_all(Self::reflog_async(remote, ipfs))
+    }
+
+    /// A non-blocking version of `reflog()`
+    pub fn reflog_async<S: ContentStore>(
+        remote: &NIPRemote,
+        ipfs: &mut S,
+    ) -> StoreFuture<BTreeMap<String, Vec<RefLogEntry>>> {
+        Box::new(
+            Self::history_async(remote, ipfs)
+                .fold(
+                    (BTreeMap::new(), None),
+                    |(mut reflog, newer), (link, idx)| -> Result<_, Error> {
+                        if let Some((newer_link, newer_refs)) = newer {
+                            record_ref_changes(&mut reflog, newer_link, &newer_refs, &idx.refs);
+                        }
+                        Ok((reflog, Some((link, idx.refs))))
+                    },
+                )
+                .map(|(mut reflog, oldest)| {
+                    // Everything in the first index ever published was created by it
+                    if let Some((link, refs)) = oldest {
+                        record_ref_changes(&mut reflog, link, &refs, &BTreeMap::new());
+                    }
+                    reflog
+                }),
+        )
+    }
+
+    /// Take raw index bytes and build a `NIPIndex` from it.
+    ///
+    /// Deprecated: sharded and delta indices need a content store to be read, so they fail here
+    /// with `NIPIndexError::ShardedIndex` and `NIPIndexError::DeltaIndex` respectively. Use
+    /// `decode()`, which reads everything `from_nip_remote()` can.
+    #[deprecated(note = "can't read sharded or delta indices, use NIPIndex::decode() instead")]
     pub fn from_slice(bytes: &[u8]) -> Result<Self, Error> {
+        let stored = Self::parse_stored(bytes)?;
+        if stored.delta.is_some() {
+            let prev_idx_hash = stored.prev_idx_hash.unwrap_or_default();
+            return Err(NIPIndexError::DeltaIndex(prev_idx_hash).into());
+        }
+        match stored.object_root {
+            Some(root) if stored.objects.is_none() => Err(NIPIndexError::ShardedIndex(root).into()),
+            _ => Self::from_stored(stored),
+        }
+    }
+
+    /// Take raw index bytes and build a `NIPIndex` from it, downloading the objects of sharded
+    /// indices and the chain behind delta indices from `ipfs`
+    pub fn decode<S: ContentStore>(bytes: &[u8], ipfs: &mut S) -> Result<Self, Error> {
+        current_thread::block_on_all(Self::decode_async(bytes, ipfs))
+    }
+
+    /// A non-blocking version of `decode()`
+    pub fn decode_async<S: ContentStore>(bytes: &[u8], ipfs: &mut S) -> StoreFuture<Self> {
+        Box::new(Self::decode_stored_async(bytes, ipfs).map(|(idx, _)| idx))
+    }
+
+    /// `decode_async()` resolving to the index and its number of deltas since the last full
+    /// index
+    fn decode_stored_async<S: ContentStore>(
+        bytes: &[u8],
+        ipfs: &mut S,
+    ) -> StoreFuture<(Self, usize)> {
+        let mut stored = match Self::parse_stored(bytes) {
+            Ok(stored) => stored,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        if let Some(delta) = stored.delta.take() {
+            let prev_idx_hash = match stored.prev_idx_hash.clone() {
+                Some(prev_idx_hash) => prev_idx_hash,
+                None => return Box::new(future::err(format_err!("Delta index has no base"))),
+            };
+            debug!(
+                "Index is delta number {} on top of {}",
+                delta.depth, prev_idx_hash
+            );
+
+            let mut store = ipfs.clone();
+            return Box::new(
+                ipfs.cat(bare_hash(&prev_idx_hash))
+                    .and_then(move |bytes| Self::decode_stored_async(&bytes[..], &mut store))
+                    .map(move |(mut idx, _)| {
+                        let delta_depth = delta.depth;
+                        delta.apply_to(&mut idx);
+                        idx.prev_idx_hash = stored.prev_idx_hash;
+                        idx.merged_idx_hash = stored.merged_idx_hash;
+                        (idx, delta_depth)
+                    }),
+            );
+        }
+
+        match stored.object_root.clone() {
+            Some(root) if stored.objects.is_none() => {
+                debug!("Loading index objects from shards under {}", root);
+                Box::new(
+                    shard::load_objects(&root, ipfs).and_then(move |(objects, shards)| {
+                        let mut idx = Self::from_stored(StoredIndex {
+                            objects: Some(objects),
+                            ..stored
+                        })?;
+                        idx.shards = shards;
+                        Ok((idx, 0))
+                    }),
+                )
+            }
+            _ => Box::new(future::result(
+                Self::from_stored(stored).map(|idx| (idx, 0)),
+            )),
+        }
+    }
+
+    fn from_stored(stored: StoredIndex) -> Result<Self, Error> {
+        Ok(Self {
+            refs: stored.refs,
+            objects: stored.objects.ok_or(NIPIndexError::MissingObjects)?,
+            prev_idx_hash: stored.prev_idx_hash,
+            merged_idx_hash: stored.merged_idx_hash,
+            base_idx_hash: None,
+            shards: ShardCache::default(),
+            base: IndexBase::default(),
+            file_store: None,
+        })
+    }
+
+    /// Check the header of raw index bytes and deserialize what follows
+    fn parse_stored(bytes: &[u8]) -> Result<StoredIndex, Error> {
+        if bytes.len() < NIP_HEADER_LEN {
+            return Err(NIPError::InternalError(format!(
+                "Index is {} byte(s) long, too short for a nip header",
+                bytes.len()
+            ))
+            .into());
+        }
         let protocol_version = parse_nip_header(&bytes[..NIP_HEADER_LEN])?;
 
         debug!("Index protocol version {}", protocol_version);
@@ -79,7 +648,7 @@ impl NIPIndex {
                     "nip index is {} protocol version(s) behind, please rebuild with \"migrations\" enabled to migrate it",
                     NIP_PROTOCOL_VERSION - protocol_version
                     );
-                return Err(NIPError::InvalidVersion(protocol_version).into());
+                Err(NIPError::InvalidVersion(protocol_version).into())
             }
             Ordering::Equal => Ok(serde_cbor::from_slice(&bytes[NIP_HEADER_LEN..])?),
             Ordering::Greater => {
@@ -87,532 +656,4829 @@ impl NIPIndex {
                     "nip index is {} protocol version(s) ahead, upgrade nip to use it",
                     protocol_version - NIP_PROTOCOL_VERSION
                 );
-                return Err(NIPError::InvalidVersion(protocol_version).into());
+                Err(NIPError::InvalidVersion(protocol_version).into())
             }
         }
     }
 
+    /// Serialize `self` into header-prefixed bytes understood by `decode()`
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        let mut self_buf = gen_nip_header(None)?;
+
+        self_buf.extend_from_slice(&serde_cbor::to_vec(self)?);
+
+        Ok(self_buf)
+    }
+
     /// Figure out what git hash `ref_src` points to in `repo` and add it to the index as
     /// `ref_dst`. If `ref_src` is an empty string, `ref_dst` is deleted from the index (only the
-    /// ref, the objects aren't touched).
-    pub fn push_ref_from_str(
+    /// ref, the objects aren't touched); deleting a ref the index doesn't have is rejected. A
+    /// rejected push fails like `stage_ref_push()` does and leaves the index untouched;
+    /// `push_ref_from_str_with_opts()` reports rejections as a `PushOutcome` instead.
+    pub fn push_ref_from_str<S: ContentStore>(
         &mut self,
         ref_src: &str,
         ref_dst: &str,
         force: bool,
         repo: &mut Repository,
-        ipfs: &mut IpfsClient,
+        ipfs: &mut S,
     ) -> Result<(), Error> {
-        // Deleting `ref_dst` was requested
-        if ref_src == "" {
-            debug!("Removing ref {} from index", ref_dst);
-            if self.refs.remove(ref_dst).is_none() {
-                warn!(
-                    "Nothing to delete, ref {} not part of the index ref set",
-                    ref_dst
-                );
-                debug!("Available refs:\n{:#?}", self.refs);
-            }
-            return Ok(());
+        // Unlike in the outcome-reporting variants, a missing `ref_src` is an error
+        if !ref_src.is_empty() {
+            Self::resolve_push_src(ref_src, repo)?;
         }
-        let reference = repo.find_reference(ref_src)?.resolve()?;
 
-        // Differentiate between annotated tags and their commit representation
-        let obj = reference
-            .peel(ObjectType::Tag)
-            .unwrap_or(reference.peel(ObjectType::Commit)?);
+        let outcome = self.push_ref_from_str_with_opts(
+            ref_src,
+            ref_dst,
+            force,
+            repo,
+            ipfs,
+            &TransferOptions::default(),
+        )?;
+        Self::ensure_accepted(outcome, ref_dst)
+    }
 
-        debug!(
-            "{:?} dereferenced to {:?} {}",
-            reference.shorthand(),
-            obj.kind(),
-            obj.id()
-        );
+    /// `push_ref_from_str()` with custom transfer options, reporting rejected pushes through the
+    /// returned `PushOutcome` rather than as errors
+    pub fn push_ref_from_str_with_opts<S: ContentStore>(
+        &mut self,
+        ref_src: &str,
+        ref_dst: &str,
+        force: bool,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<PushOutcome, Error> {
+        let mut report = self.push_refs(&[(ref_src, ref_dst, force)], repo, ipfs, opts)?;
 
-        if force {
-            warn!("This push will be forced");
-        } else {
-            debug!("Checking for work ahead of us...");
+        Ok(report.remove(0).1)
+    }
+
+    /// Do all the checks and counting `push_ref_from_str()` does and return a `ChangeSet` with the
+    /// objects to upload and the resulting ref update. The index itself stays untouched. A
+    /// rejected push fails with `NIPIndexError::FetchFirst` if the remote ref holds history
+    /// missing from `repo`, or with `NIPIndexError::NonFastForward` if that history isn't part of
+    /// `ref_src`.
+    pub fn stage_ref_push<S: ContentStore>(
+        &self,
+        ref_src: &str,
+        ref_dst: &str,
+        force: bool,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<ChangeSet, Error> {
+        // Unlike in a push, a missing `ref_src` is an error
+        if !ref_src.is_empty() {
+            Self::resolve_push_src(ref_src, repo)?;
+        }
 
-            if let Some(dst_git_hash) = self.refs.get(ref_dst) {
-                let mut missing_objects = HashSet::new();
-                self.enumerate_for_fetch(dst_git_hash.parse()?, &mut missing_objects, repo, ipfs)?;
+        let specs = vec![PushSpec::new(ref_src, ref_dst, force)];
+        let (changes, mut report) = self.stage_specs_push(specs, repo, ipfs, opts)?;
+        Self::ensure_accepted(report.remove(0).1, ref_dst)?;
 
-                if !missing_objects.is_empty() {
-                    error!(
-                        "There's {} objects in {} not present locally. Please fetch first or force-push.",
-                        missing_objects.len(),
-                        ref_dst
-                        );
+        Ok(changes)
+    }
 
-                    debug!("Missing objects:\n{:#?}", missing_objects);
-                    return Err(NIPIndexError::FetchFirst.into());
-                }
+    /// Turn a rejected `outcome` of a single-ref push to `ref_dst` back into the error the
+    /// single-ref push APIs fail with
+    fn ensure_accepted(outcome: PushOutcome, ref_dst: &str) -> Result<(), Error> {
+        match outcome {
+            PushOutcome::RejectedFetchFirst => Err(NIPIndexError::FetchFirst.into()),
+            PushOutcome::RejectedNonFastForward => Err(NIPIndexError::NonFastForward.into()),
+            PushOutcome::RejectedStale => Err(NIPIndexError::StaleRef(ref_dst.to_owned()).into()),
+            PushOutcome::RejectedMissingSrc => Err(format_err!(
+                "src refspec for {} does not match any",
+                ref_dst
+            )),
+            PushOutcome::RejectedMissingDst => {
+                Err(format_err!("Unable to delete {}: no such ref", ref_dst))
             }
+            _ => Ok(()),
         }
+    }
 
-        let mut objs_for_push = HashSet::new();
-        let mut submodules_for_push = HashSet::new();
-
-        let start = Instant::now();
-        self.enumerate_for_push(
-            &obj.clone(),
-            &mut objs_for_push,
-            &mut submodules_for_push,
-            repo,
-        )?;
-        let dur = start.elapsed();
+    /// Push several refs at once, each described by a `(ref_src, ref_dst, force)` triple with
+    /// the same meaning as in `push_ref_from_str()`. The history behind all refs is counted in one
+    /// pass and uploaded once; the refs that weren't rejected are then updated in one go.
+    ///
+    /// Rejected refs don't fail the push and are reported along with the accepted ones, in
+    /// `refspecs` order. Errors that affect the whole push (e.g. a failed upload or two updates of
+    /// the same ref) leave the index untouched.
+    pub fn push_refs<S: ContentStore>(
+        &mut self,
+        refspecs: &[(&str, &str, bool)],
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<Vec<(String, PushOutcome)>, Error> {
+        self.push_specs(PushSpec::batch(refspecs), repo, ipfs, opts)
+    }
 
-        debug!(
-            "Counting objects took {}.{}s",
-            dur.as_secs(),
-            dur.subsec_micros()
-        );
+    /// Push `ref_src` to `ref_dst` like a forced `push_ref_from_str()`, but only if `ref_dst`
+    /// currently points at `expected` (or doesn't exist, if `expected` is `None`). A ref that
+    /// moved in the meantime is reported as `PushOutcome::RejectedStale`. The lease is recorded
+    /// in the staged `ChangeSet` and checked once more when it's applied.
+    pub fn push_ref_with_lease<S: ContentStore>(
+        &mut self,
+        ref_src: &str,
+        ref_dst: &str,
+        expected: Option<&str>,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<PushOutcome, Error> {
+        let spec = PushSpec::with_lease(ref_src, ref_dst, expected)?;
 
-        debug!(
-            "Counted {} object(s) for push (took {}.{}):\n{:#?}",
-            dur.as_secs(),
-            dur.subsec_micros(),
-            objs_for_push.len(),
-            objs_for_push
-        );
-        debug!(
-            "Counted {} submodule stub(s) for push:\n{:#?}",
-            submodules_for_push.len(),
-            submodules_for_push
-        );
+        let mut report = self.push_specs(vec![spec], repo, ipfs, opts)?;
+        Ok(report.remove(0).1)
+    }
 
-        self.push_git_objects(&objs_for_push, repo, ipfs)?;
+    fn push_specs<S: ContentStore>(
+        &mut self,
+        specs: Vec<PushSpec>,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<Vec<(String, PushOutcome)>, Error> {
+        let (mut changes, report) = self.stage_specs_push(specs, repo, ipfs, opts)?;
+        changes.upload(repo, ipfs, opts)?;
 
-        // Add all submodule tips to the index
-        for submod_oid in submodules_for_push {
-            self.objects
-                .insert(submod_oid.to_string(), SUBMODULE_TIP_MARKER.to_owned());
-        }
+        self.apply_journaled(changes, opts)?;
+        Ok(report)
+    }
 
-        self.refs
-            .insert(ref_dst.to_owned(), format!("{}", obj.id()));
-        Ok(())
+    /// The `push_refs()` counterpart of `stage_ref_push()`; also returns the per-ref report.
+    pub fn stage_refs_push<S: ContentStore>(
+        &self,
+        refspecs: &[(&str, &str, bool)],
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(ChangeSet, Vec<(String, PushOutcome)>), Error> {
+        self.stage_specs_push(PushSpec::batch(refspecs), repo, ipfs, opts)
     }
 
-    /// Iteratively fill two hash sets: `obj`'s children present in `repo` but missing from `self`
-    /// (`push_todo`), and `obj`'s children recognized as submodule tips. (`submodules`).
-    pub fn enumerate_for_push(
+    fn stage_specs_push<S: ContentStore>(
         &self,
-        obj: &Object,
-        push_todo: &mut HashSet<Oid>,
-        submodules: &mut HashSet<Oid>,
+        specs: Vec<PushSpec>,
         repo: &Repository,
-    ) -> Result<(), Error> {
-        // Object tree traversal state
-        let mut stack = vec![obj.clone()];
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(ChangeSet, Vec<(String, PushOutcome)>), Error> {
+        Self::ensure_distinct_dsts(&specs)?;
+        let ipfs = &mut self.object_store(ipfs)?;
 
-        let mut obj_cnt = 1;
-        while let Some(obj) = stack.pop() {
-            if self.objects.contains_key(&obj.id().to_string()) {
-                trace!("Object {} already in nip index", obj.id());
-                continue;
-            }
+        let mut staged = StagedPush::default();
+        for spec in specs {
+            let outcome = match self.precheck_spec(&spec, repo)? {
+                SpecCheck::Done(outcome) => outcome,
+                SpecCheck::Pending(outcome, src_oid) => {
+                    match self.check_ref_push(
+                        &spec.ref_dst,
+                        src_oid,
+                        spec.force,
+                        repo,
+                        ipfs,
+                        opts,
+                    )? {
+                        Some(rejection) => (rejection, None),
+                        None => (outcome, Some(src_oid)),
+                    }
+                }
+            };
+            self.record_staged(&mut staged, spec, outcome);
+        }
 
-            if push_todo.contains(&obj.id()) {
-                trace!("Object {} already in state", obj.id());
-                continue;
-            }
+        self.finish_staging(staged, repo, opts)
+    }
 
-            let obj_type = obj.kind().ok_or_else(|| {
-                let msg = format!("Cannot determine type of object {}", obj.id());
-                error!("{}", msg);
-                format_err!("{}", msg)
-            })?;
+    /// A non-blocking version of `push_ref_from_str_with_opts()`. The index is handed back along
+    /// with the outcome once the push completes; on error it is dropped along with the push.
+    pub fn push_ref_from_str_async<S: ContentStore>(
+        self,
+        ref_src: &str,
+        ref_dst: &str,
+        force: bool,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<(Self, PushOutcome)> {
+        let specs = vec![PushSpec::new(ref_src, ref_dst, force)];
 
-            push_todo.insert(obj.id());
+        Box::new(
+            self.push_specs_async(specs, repo, ipfs, opts)
+                .map(|(idx, mut report)| (idx, report.remove(0).1)),
+        )
+    }
 
-            match obj_type {
-                ObjectType::Commit => {
-                    let commit = obj
-                        .as_commit()
-                        .ok_or_else(|| format_err!("Could not view {:?} as a commit", obj))?;
-                    debug!("[{}] Counting commit {:?}", obj_cnt, commit);
+    /// A non-blocking version of `push_refs()`. The index is handed back along with the report
+    /// once the push completes; on error it is dropped along with the push.
+    pub fn push_refs_async<S: ContentStore>(
+        self,
+        refspecs: &[(&str, &str, bool)],
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<(Self, Vec<(String, PushOutcome)>)> {
+        self.push_specs_async(PushSpec::batch(refspecs), repo, ipfs, opts)
+    }
 
-                    let tree_obj = obj.peel(ObjectType::Tree)?;
-                    trace!("Commit {}: Handling tree {}", commit.id(), tree_obj.id());
+    /// A non-blocking version of `push_ref_with_lease()`. The index is handed back along with the
+    /// outcome once the push completes; on error it is dropped along with the push.
+    pub fn push_ref_with_lease_async<S: ContentStore>(
+        self,
+        ref_src: &str,
+        ref_dst: &str,
+        expected: Option<&str>,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<(Self, PushOutcome)> {
+        let spec = match PushSpec::with_lease(ref_src, ref_dst, expected) {
+            Ok(spec) => spec,
+            Err(e) => return Box::new(future::err(e)),
+        };
 
-                    stack.push(tree_obj);
+        Box::new(
+            self.push_specs_async(vec![spec], repo, ipfs, opts)
+                .map(|(idx, mut report)| (idx, report.remove(0).1)),
+        )
+    }
 
-                    for parent in commit.parents() {
-                        trace!(
-                            "Commit {}: Pushing parent commit {}",
-                            commit.id(),
-                            parent.id()
-                        );
-                        stack.push(parent.into_object());
-                    }
-                }
-                ObjectType::Tree => {
-                    let tree = obj
-                        .as_tree()
-                        .ok_or_else(|| format_err!("Could not view {:?} as a tree", obj))?;
-                    debug!("[{}] Counting tree {:?}", obj_cnt, tree);
+    /// Stage `specs`, upload the objects they need and apply the result; every push goes through
+    /// here
+    fn push_specs_async<S: ContentStore>(
+        self,
+        specs: Vec<PushSpec>,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<(Self, Vec<(String, PushOutcome)>)> {
+        let prepared = reopen_repo(repo).and_then(|repo| Ok((repo, self.object_store(ipfs)?)));
+        let (repo, mut ipfs) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => return Box::new(future::err(e)),
+        };
 
-                    for entry in tree.into_iter() {
-                        // Weed out submodules (Implicitly known as commit children of tree objects)
-                        if let Some(ObjectType::Commit) = entry.kind() {
-                            debug!("Skipping submodule at {}", entry.id());
+        let mut upload_ipfs = ipfs.clone();
+        let opts = opts.clone();
 
-                            submodules.insert(entry.id());
+        Box::new(
+            self.stage_specs_push_async(specs, repo, &mut ipfs, &opts)
+                .and_then(move |(idx, repo, changes, report)| {
+                    let upload_opts = opts.clone();
+                    changes
+                        .upload_owned(repo, &mut upload_ipfs, &upload_opts)
+                        .and_then(move |changes| {
+                            let mut idx = idx;
+                            idx.apply_journaled(changes, &opts)?;
+                            Ok((idx, report))
+                        })
+                }),
+        )
+    }
 
-                            continue;
-                        }
+    /// Check `specs` one by one and stage the accepted ones; resolves to the index, the repo, the
+    /// staged changes and the per-ref report
+    fn stage_specs_push_async<S: ContentStore>(
+        self,
+        specs: Vec<PushSpec>,
+        repo: Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<(Self, Repository, ChangeSet, PushReport)> {
+        if let Err(e) = Self::ensure_distinct_dsts(&specs) {
+            return Box::new(future::err(e));
+        }
 
-                        trace!(
-                            "Tree {}: Pushing tree entry {} ({:?})",
-                            tree.id(),
-                            entry.id(),
-                            entry.kind()
-                        );
+        let mut ipfs = ipfs.clone();
+        let opts = opts.clone();
+        let stage_opts = opts.clone();
 
-                        stack.push(entry.to_object(&repo)?);
-                    }
-                }
-                ObjectType::Blob => {
-                    let blob = obj
-                        .as_blob()
-                        .ok_or_else(|| format_err!("Could not view {:?} as a blob", obj))?;
-                    debug!("[{}] Counting blob {:?}", obj_cnt, blob);
-                }
-                ObjectType::Tag => {
-                    let tag = obj
-                        .as_tag()
-                        .ok_or_else(|| format_err!("Could not view {:?} as a tag", obj))?;
-                    debug!("[{}] Counting tag {:?}", obj_cnt, tag);
+        Box::new(
+            future::loop_fn(
+                (self, repo, StagedPush::default(), specs.into_iter()),
+                move |(idx, repo, staged, mut specs)| -> StoreFuture<_> {
+                    let spec = match specs.next() {
+                        Some(spec) => spec,
+                        None => return Box::new(future::ok(Loop::Break((idx, repo, staged)))),
+                    };
 
-                    stack.push(tag.target()?);
-                }
-                other => {
-                    return Err(NIPError::InternalError(format!(
-                        "Don't know how to traverse a {}",
-                        other
-                    ))
-                    .into());
-                }
-            }
+                    Box::new(
+                        idx.stage_spec_async(spec, repo, staged, &mut ipfs, &opts)
+                            .map(move |(idx, repo, staged)| {
+                                Loop::Continue((idx, repo, staged, specs))
+                            }),
+                    )
+                },
+            )
+            .and_then(move |(idx, repo, staged)| {
+                let (changes, report) = idx.finish_staging(staged, &repo, &stage_opts)?;
+                Ok((idx, repo, changes, report))
+            }),
+        )
+    }
 
-            obj_cnt += 1;
+    /// Fail if more than one of `specs` updates the same ref
+    fn ensure_distinct_dsts(specs: &[PushSpec]) -> Result<(), Error> {
+        let mut seen = HashSet::new();
+        if let Some(spec) = specs.iter().find(|spec| !seen.insert(&spec.ref_dst)) {
+            bail!("Multiple updates for ref {}", spec.ref_dst);
         }
+
         Ok(())
     }
 
-    /// Take `oids` and upload underlying `repo` git objects to IPFS. for `submodules` the
-    /// `SUBMODULE_TIP_MARKER` is inserted.
-    pub fn push_git_objects(
-        &mut self,
-        oids: &HashSet<Oid>,
+    /// Turn everything `staged` accepted into a change set; returns it along with the report
+    fn finish_staging(
+        &self,
+        staged: StagedPush,
         repo: &Repository,
-        ipfs: &mut IpfsClient,
-    ) -> Result<(), Error> {
-        let oid_count = oids.len();
-        for (i, oid) in oids.iter().enumerate() {
-            let obj = repo.find_object(*oid, None)?;
-            trace!("Current object: {:?} at {}", obj.kind(), obj.id());
+        opts: &TransferOptions,
+    ) -> Result<(ChangeSet, PushReport), Error> {
+        let mut changes = self.stage_ref_updates(&staged.updates, repo, opts)?;
+        for ref_dst in staged.deletions {
+            changes.refs.insert(ref_dst, None);
+        }
+        changes.leases = staged.leases;
+        changes.file_store = self.file_store.clone();
 
-            if self.objects.contains_key(&obj.id().to_string()) {
-                warn!("push_objects: Object {} already in nip index", obj.id());
-                continue;
+        Ok((changes, staged.report))
+    }
+
+    /// Check a single `spec` and add it to `staged`
+    fn stage_spec_async<S: ContentStore>(
+        self,
+        spec: PushSpec,
+        repo: Repository,
+        mut staged: StagedPush,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<(Self, Repository, StagedPush)> {
+        // Resolves to the index, the repo, the outcome and the object to point `ref_dst` at
+        let checked: StoreFuture<(Self, Repository, (PushOutcome, Option<Oid>))> =
+            match self.precheck_spec(&spec, &repo) {
+                Ok(SpecCheck::Done(outcome)) => Box::new(future::ok((self, repo, outcome))),
+                Ok(SpecCheck::Pending(outcome, src_oid)) => Box::new(
+                    self.check_ref_push_async(&spec.ref_dst, src_oid, spec.force, repo, ipfs, opts)
+                        .map(move |(idx, repo, rejection)| match rejection {
+                            Some(rejection) => (idx, repo, (rejection, None)),
+                            None => (idx, repo, (outcome, Some(src_oid))),
+                        }),
+                ),
+                Err(e) => Box::new(future::err(e)),
+            };
+
+        Box::new(checked.map(move |(idx, repo, outcome)| {
+            idx.record_staged(&mut staged, spec, outcome);
+            (idx, repo, staged)
+        }))
+    }
+
+    /// Work out as much of `spec`'s outcome as possible without looking at the remote history.
+    /// Returns `SpecCheck::Pending` with the outcome to report if `check_ref_push()` finds
+    /// nothing to reject.
+    fn precheck_spec(&self, spec: &PushSpec, repo: &Repository) -> Result<SpecCheck, Error> {
+        let PushSpec {
+            ref ref_src,
+            ref ref_dst,
+            force,
+            ref lease,
+        } = *spec;
+
+        if let Some(expected) = lease {
+            let expected = expected.map(|oid| oid.to_string());
+            if self.refs.get(ref_dst) != expected.as_ref() {
+                debug!(
+                    "{} is at {:?}, the push expected {:?}",
+                    ref_dst,
+                    self.refs.get(ref_dst),
+                    expected
+                );
+                return Ok(SpecCheck::Done((PushOutcome::RejectedStale, None)));
             }
+        }
 
-            let obj_type = obj.kind().ok_or_else(|| {
-                let msg = format!("Cannot determine type of object {}", obj.id());
-                error!("{}", msg);
-                format_err!("{}", msg)
-            })?;
+        if ref_src.is_empty() {
+            if !self.refs.contains_key(ref_dst) {
+                debug!("Nothing to delete, ref {} not part of the index", ref_dst);
+                return Ok(SpecCheck::Done((PushOutcome::RejectedMissingDst, None)));
+            }
+            debug!("Staging removal of ref {}", ref_dst);
+            return Ok(SpecCheck::Done((PushOutcome::Deleted, None)));
+        }
 
-            match obj_type {
-                ObjectType::Commit => {
-                    let commit = obj
-                        .as_commit()
-                        .ok_or_else(|| format_err!("Could not view {:?} as a commit", obj))?;
-                    trace!("Pushing commit {:?}", commit);
-
-                    let nip_object_hash =
-                        NIPObject::from_git_commit(&commit, &repo.odb()?, ipfs)?.ipfs_add(ipfs)?;
-
-                    self.objects
-                        .insert(format!("{}", obj.id()), nip_object_hash.clone());
-                    debug!(
-                        "[{}/{}] Commit {} uploaded to {}",
-                        i + 1,
-                        oid_count,
-                        obj.id(),
-                        nip_object_hash
-                    );
-                }
-                ObjectType::Tree => {
-                    let tree = obj
-                        .as_tree()
-                        .ok_or_else(|| format_err!("Could not view {:?} as a tree", obj))?;
-                    trace!("Pushing tree {:?}", tree);
-
-                    let nip_object_hash =
-                        NIPObject::from_git_tree(&tree, &repo.odb()?, ipfs)?.ipfs_add(ipfs)?;
-
-                    self.objects
-                        .insert(format!("{}", obj.id()), nip_object_hash.clone());
-                    debug!(
-                        "[{}/{}] Tree {} uploaded to {}",
-                        i + 1,
-                        oid_count,
-                        obj.id(),
-                        nip_object_hash
-                    );
-                }
-                ObjectType::Blob => {
-                    let blob = obj
-                        .as_blob()
-                        .ok_or_else(|| format_err!("Could not view {:?} as a blob", obj))?;
-                    trace!("Pushing blob {:?}", blob);
-
-                    let nip_object_hash =
-                        NIPObject::from_git_blob(&blob, &repo.odb()?, ipfs)?.ipfs_add(ipfs)?;
-
-                    self.objects
-                        .insert(format!("{}", obj.id()), nip_object_hash.clone());
-                    debug!(
-                        "[{}/{}] Blob {} uploaded to {}",
-                        i + 1,
-                        oid_count,
-                        obj.id(),
-                        nip_object_hash
-                    );
-                }
-                ObjectType::Tag => {
-                    let tag = obj
-                        .as_tag()
-                        .ok_or_else(|| format_err!("Could not view {:?} as a tag", obj))?;
-                    trace!("Pushing tag {:?}", tag);
+        let src_oid = match Self::resolve_push_src(ref_src, repo) {
+            Ok(src_oid) => src_oid,
+            Err(e) => {
+                debug!("Could not resolve {}: {}", ref_src, e);
+                return Ok(SpecCheck::Done((PushOutcome::RejectedMissingSrc, None)));
+            }
+        };
 
-                    let nip_object_hash =
-                        NIPObject::from_git_tag(&tag, &repo.odb()?, ipfs)?.ipfs_add(ipfs)?;
+        Ok(
+            match self.accepted_outcome(ref_dst, src_oid, force, repo)? {
+                PushOutcome::UpToDate => SpecCheck::Done((PushOutcome::UpToDate, None)),
+                outcome => SpecCheck::Pending(outcome, src_oid),
+            },
+        )
+    }
 
-                    self.objects
-                        .insert(format!("{}", obj.id()), nip_object_hash.clone());
+    /// Add the `outcome` of `spec`, along with the object to point its `ref_dst` at, if any, to
+    /// `staged`
+    fn record_staged(
+        &self,
+        staged: &mut StagedPush,
+        spec: PushSpec,
+        (outcome, update): (PushOutcome, Option<Oid>),
+    ) {
+        let PushSpec { ref_dst, lease, .. } = spec;
 
-                    debug!(
-                        "[{}/{}] Tag {} uploaded to {}",
-                        i + 1,
-                        oid_count,
-                        obj.id(),
-                        nip_object_hash
-                    );
+        if outcome.is_rejected() {
+            warn!("Rejecting push to {}: {:?}", ref_dst, outcome);
+        } else {
+            // Refs without an explicit lease are leased on their value at staging time, so
+            // that the change set can't be applied over someone else's update
+            let moves_ref = update.is_some() || outcome == PushOutcome::Deleted;
+            match lease {
+                Some(expected) => {
+                    staged
+                        .leases
+                        .insert(ref_dst.clone(), expected.map(|oid| oid.to_string()));
                 }
-                other => {
-                    return Err(NIPError::InternalError(format!(
-                        "Don't know how to traverse a {}",
-                        other
-                    ))
-                    .into());
+                None if moves_ref => {
+                    let current = self.refs.get(&ref_dst).cloned();
+                    staged.leases.insert(ref_dst.clone(), current);
                 }
+                None => {}
             }
         }
-        Ok(())
+
+        match update {
+            Some(src_oid) => staged.updates.push((ref_dst.clone(), src_oid)),
+            None if outcome == PushOutcome::Deleted => staged.deletions.push(ref_dst.clone()),
+            None => {}
+        }
+        staged.report.push((ref_dst, outcome));
     }
 
-    /// Fetch `git_hash` from `self` to `repo`'s `ref_name` ref.
-    pub fn fetch_to_ref_from_str(
-        &self,
-        git_hash: &str,
-        ref_name: &str,
-        repo: &mut Repository,
-        ipfs: &mut IpfsClient,
-    ) -> Result<(), Error> {
-        debug!("Fetching {} for {}", git_hash, ref_name);
+    /// Returns the `PushOutcome` a `FetchFirst` or `NonFastForward` error stands for, or the
+    /// error itself if it's anything else
+    fn rejected_outcome(e: Error) -> Result<PushOutcome, Error> {
+        match e.downcast::<NIPIndexError>()? {
+            NIPIndexError::FetchFirst => Ok(PushOutcome::RejectedFetchFirst),
+            NIPIndexError::NonFastForward => Ok(PushOutcome::RejectedNonFastForward),
+            other => Err(other.into()),
+        }
+    }
 
-        let git_hash_oid = Oid::from_str(git_hash)?;
-        let mut oids_for_fetch = HashSet::new();
+    /// Returns what pointing `ref_dst` at `src_oid` amounts to if the push isn't rejected
+    fn accepted_outcome(
+        &self,
+        ref_dst: &str,
+        src_oid: Oid,
+        force: bool,
+        repo: &Repository,
+    ) -> Result<PushOutcome, Error> {
+        let dst_oid = match self.refs.get(ref_dst) {
+            Some(dst_git_hash) => dst_git_hash.parse().ok(),
+            None => return Ok(PushOutcome::Created),
+        };
 
-        let start = Instant::now();
-        self.enumerate_for_fetch(git_hash_oid, &mut oids_for_fetch, repo, ipfs)?;
-        let dur = start.elapsed();
-        debug!(
-            "Counting objects took {}.{}s",
-            dur.as_secs(),
-            dur.subsec_micros()
-        );
-        debug!(
-            "Counted {} object(s) for fetch:\n{:#?}",
-            oids_for_fetch.len(),
-            oids_for_fetch
-        );
+        Ok(match dst_oid {
+            Some(dst_oid) if dst_oid == src_oid => PushOutcome::UpToDate,
+            Some(dst_oid) if Self::is_fast_forward(dst_oid, src_oid, repo)? => {
+                PushOutcome::FastForwarded
+            }
+            _ if force => PushOutcome::Forced,
+            _ => PushOutcome::FastForwarded,
+        })
+    }
 
-        self.fetch_nip_objects(&oids_for_fetch, repo, ipfs)?;
+    /// Returns true if `new_oid` is `old_oid` or has it in its history, with tags peeled to the
+    /// commits they point at. Objects unknown to `repo` never make a fast-forward.
+    fn is_fast_forward(old_oid: Oid, new_oid: Oid, repo: &Repository) -> Result<bool, Error> {
+        let peel = |oid| -> Result<Oid, git2::Error> {
+            Ok(repo.find_object(oid, None)?.peel(ObjectType::Commit)?.id())
+        };
 
-        match repo.odb()?.read_header(git_hash_oid)?.1 {
-            ObjectType::Commit if ref_name.starts_with("refs/tags") => {
-                debug!("Not setting ref for lightweight tag {}", ref_name);
-            }
-            ObjectType::Commit => {
-                repo.reference(ref_name, git_hash_oid, true, "nip fetch")?;
-            }
-            // Somehow git is upset when we set tag refs for it
-            ObjectType::Tag => {
-                debug!("Not setting ref for tag {}", ref_name);
-            }
-            other_type => {
-                let msg = format!("New tip turned out to be a {} after fetch", other_type);
-                error!("{}", msg);
-                return Err(NIPError::InternalError(msg).into());
+        match (peel(old_oid), peel(new_oid)) {
+            (Ok(old_commit), Ok(new_commit)) => {
+                Ok(old_commit == new_commit || repo.graph_descendant_of(new_commit, old_commit)?)
             }
+            _ => Ok(old_oid == new_oid),
+        }
+    }
+
+    /// Fail with `NonFastForward` unless `src_oid` descends from `dst_oid`, the current value of
+    /// `ref_dst`
+    fn ensure_fast_forward(
+        ref_dst: &str,
+        dst_oid: Oid,
+        src_oid: Oid,
+        repo: &Repository,
+    ) -> Result<(), Error> {
+        if !Self::is_fast_forward(dst_oid, src_oid, repo)? {
+            error!(
+                "{} doesn't descend from {} ({}). Please merge first or force-push.",
+                src_oid, dst_oid, ref_dst
+            );
+            return Err(NIPIndexError::NonFastForward.into());
         }
 
-        debug!("Fetched {} for {} OK.", git_hash, ref_name);
         Ok(())
     }
 
-    /// Fill a hash set with `oid`'s children that are present in `self` but missing in `repo`.
-    pub fn enumerate_for_fetch(
+    /// Unless `force` is set, check whether `ref_dst` points at history missing from `repo` or
+    /// whether pointing it at `src_oid` would drop some of that history. Returns the resulting
+    /// rejection, if any.
+    fn check_ref_push<S: ContentStore>(
         &self,
-        oid: Oid,
-        fetch_todo: &mut HashSet<Oid>,
+        ref_dst: &str,
+        src_oid: Oid,
+        force: bool,
         repo: &Repository,
-        ipfs: &mut IpfsClient,
-    ) -> Result<(), Error> {
-        let mut stack = vec![oid];
-        let mut obj_cnt = 1;
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<Option<PushOutcome>, Error> {
+        let dst_oid = match self.ref_push_dst(ref_dst, force)? {
+            Some(dst_oid) => dst_oid,
+            None => return Ok(None),
+        };
 
-        while let Some(oid) = stack.pop() {
-            if repo.odb()?.read_header(oid).is_ok() {
-                trace!("Object {} already present locally!", oid);
-                continue;
-            }
+        let mut missing_objects = HashMap::new();
+        self.walk_tips_for_fetch(&[dst_oid], &mut missing_objects, repo, ipfs, opts)?;
 
-            if fetch_todo.contains(&oid) {
-                trace!("Object {} already present in state!", oid);
-                continue;
-            }
+        Self::ref_push_rejection(ref_dst, dst_oid, src_oid, &missing_objects, repo)
+    }
 
-            let nip_obj_ipfs_hash = self
-                .objects
-                .get(&format!("{}", oid))
-                .ok_or_else(|| {
-                    let msg = format!("Could not find object {} in the index", oid);
-                    error!("{}", msg);
-                    format_err!("{}", msg)
-                })?
-                .clone();
+    /// A non-blocking version of `check_ref_push()` working on owned state; resolves to the
+    /// index, the repo and the resulting rejection, if any.
+    fn check_ref_push_async<S: ContentStore>(
+        self,
+        ref_dst: &str,
+        src_oid: Oid,
+        force: bool,
+        repo: Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<(Self, Repository, Option<PushOutcome>)> {
+        let dst_oid = match self.ref_push_dst(ref_dst, force) {
+            Ok(Some(dst_oid)) => dst_oid,
+            Ok(None) => return Box::new(future::ok((self, repo, None))),
+            Err(e) => return Box::new(future::err(e)),
+        };
 
-            if nip_obj_ipfs_hash == SUBMODULE_TIP_MARKER {
-                debug!("Ommitting submodule {}", oid.to_string());
-                return Ok(());
-            }
+        let ref_dst = ref_dst.to_owned();
+        Box::new(
+            self.enumerate_for_fetch_async(vec![dst_oid], repo, ipfs, opts)
+                .and_then(move |(idx, repo, missing_objects)| {
+                    let rejection = Self::ref_push_rejection(
+                        &ref_dst,
+                        dst_oid,
+                        src_oid,
+                        &missing_objects,
+                        &repo,
+                    )?;
 
-            fetch_todo.insert(oid);
+                    Ok((idx, repo, rejection))
+                }),
+        )
+    }
 
-            let nip_obj = NIPObject::ipfs_get(&nip_obj_ipfs_hash, ipfs)?;
+    /// Returns the current value of `ref_dst` if a push to it needs checking, i.e. unless it's
+    /// forced or creates the ref
+    fn ref_push_dst(&self, ref_dst: &str, force: bool) -> Result<Option<Oid>, Error> {
+        if force {
+            warn!("This push will be forced");
+            return Ok(None);
+        }
 
-            match nip_obj.clone().metadata {
-                NIPObjectMetadata::Commit {
-                    parent_git_hashes,
-                    tree_git_hash,
-                } => {
-                    debug!("[{}] Counting nip commit {}", obj_cnt, nip_obj_ipfs_hash);
+        debug!("Checking for work ahead of us...");
+        match self.refs.get(ref_dst) {
+            Some(git_hash) => Ok(Some(git_hash.parse()?)),
+            None => Ok(None),
+        }
+    }
 
-                    stack.push(Oid::from_str(&tree_git_hash)?);
+    /// Returns the rejection a push of `src_oid` over `dst_oid` earns given the `missing_objects`
+    /// behind `dst_oid`, if any
+    fn ref_push_rejection(
+        ref_dst: &str,
+        dst_oid: Oid,
+        src_oid: Oid,
+        missing_objects: &HashMap<Oid, NIPObject>,
+        repo: &Repository,
+    ) -> Result<Option<PushOutcome>, Error> {
+        let checked = Self::ensure_nothing_missing(ref_dst, missing_objects)
+            .and_then(|()| Self::ensure_fast_forward(ref_dst, dst_oid, src_oid, repo));
 
-                    for parent_git_hash in parent_git_hashes {
-                        stack.push(Oid::from_str(&parent_git_hash)?);
-                    }
-                }
-                NIPObjectMetadata::Tag { target_git_hash } => {
-                    debug!("[{}] Counting nip tag {}", obj_cnt, nip_obj_ipfs_hash);
+        match checked {
+            Ok(()) => Ok(None),
+            Err(e) => Ok(Some(Self::rejected_outcome(e)?)),
+        }
+    }
 
-                    stack.push(Oid::from_str(&target_git_hash)?);
-                }
-                NIPObjectMetadata::Tree { entry_git_hashes } => {
-                    debug!("[{}] Counting nip tree {}", obj_cnt, nip_obj_ipfs_hash);
+    /// Merge `changes` into the index. Fails without touching the index if any of the objects
+    /// are still pending upload, or with `NIPIndexError::StaleRef` if any of the leased refs
+    /// moved, e.g. because the index isn't the one the changes were staged against.
+    pub fn apply(&mut self, changes: ChangeSet) -> Result<(), Error> {
+        if !changes.is_complete() {
+            error!(
+                "Refusing to apply a change set with {} object(s) pending upload",
+                changes.pending.len()
+            );
+            return Err(NIPIndexError::IncompleteChangeSet(changes.pending.len()).into());
+        }
+        self.check_leases(&changes)?;
 
-                    for entry_git_hash in entry_git_hashes {
-                        stack.push(Oid::from_str(&entry_git_hash)?);
-                    }
-                }
-                NIPObjectMetadata::Blob => {
-                    debug!("[{}] Counting nip blob {}", obj_cnt, nip_obj_ipfs_hash);
-                }
+        for (git_hash, link) in changes.objects {
+            self.set_object(git_hash, link);
+        }
+        for (ref_name, git_hash) in changes.refs {
+            match git_hash {
+                Some(git_hash) => self.set_ref(ref_name, git_hash),
+                None => self.remove_ref(&ref_name),
             }
-            obj_cnt += 1;
         }
 
         Ok(())
     }
 
-    /// Download git objects in `oids` from IPFS and instantiate them in `repo`.
-    pub fn fetch_nip_objects(
-        &self,
-        oids: &HashSet<Oid>,
-        repo: &mut Repository,
-        ipfs: &mut IpfsClient,
-    ) -> Result<(), Error> {
-        for (i, &oid) in oids.iter().enumerate() {
-            debug!("[{}/{}] Fetching object {}", i + 1, oids.len(), oid);
-
-            let nip_obj_ipfs_hash = self.objects.get(&format!("{}", oid)).ok_or_else(|| {
-                let msg = format!("Could not find object {} in nip index", oid);
-                error!("{}", msg);
-                format_err!("{}", msg)
-            })?;
-
-            let nip_obj = NIPObject::ipfs_get(nip_obj_ipfs_hash, ipfs)?;
-
-            trace!("nip object at {}:\n{:#?}", nip_obj_ipfs_hash, nip_obj,);
-
-            if repo.odb()?.read_header(oid).is_ok() {
-                warn!("fetch_nip_objects: Object {} already present locally!", oid);
-                continue;
-            }
-
-            let written_oid = nip_obj.write_raw_data(&mut repo.odb()?, ipfs)?;
-            if written_oid != oid {
-                let msg = format!("Object tree inconsistency detected: fetched {} from {}, but write result hashes to {}", oid, nip_obj_ipfs_hash, written_oid);
-                error!("{}", msg);
-                return Err(NIPError::InternalError(msg).into());
+    /// Fail with `NIPIndexError::StaleRef` if any of the refs leased by `changes` moved
+    fn check_leases(&self, changes: &ChangeSet) -> Result<(), Error> {
+        for (ref_name, expected) in &changes.leases {
+            if self.refs.get(ref_name) != expected.as_ref() {
+                error!(
+                    "Refusing to apply a change set leased on {} = {:?}, the index has {:?}",
+                    ref_name,
+                    expected,
+                    self.refs.get(ref_name)
+                );
+                return Err(NIPIndexError::StaleRef(ref_name.clone()).into());
             }
-            trace!("Fetched object {} to {}", nip_obj_ipfs_hash, written_oid);
         }
+
         Ok(())
     }
 
-    /// Upload `self` to IPFS and return the IPFS/IPNS link. Plain/IPNS link use is determined as
-    /// per `prev_remote` variant (IPNS is used for both `NewIPNS` and `ExistingIPNS`, `None`
-    /// assumes IPFS); `prev_remote` is later put in the `prev_idx_hash` field just before upload.
-    pub fn ipfs_add(
+    /// Finish the push journaled in `opts.journal` by uploading whatever it still lists as
+    /// pending and applying the result. Returns false if there was no interrupted push to resume.
+    ///
+    /// The index must hold the refs the push updates as they were when it was staged. If another
+    /// writer moved any of them in the meantime, the push fails with `NIPIndexError::StaleRef`
+    /// before uploading anything and the journal is kept; `PushJournal::clear()` discards it.
+    ///
+    /// Blocking only; inside a tokio runtime, `PushJournal::load()` the change set,
+    /// `ChangeSet::upload_async()` it and `apply()` the result.
+    pub fn resume_push<S: ContentStore>(
         &mut self,
-        ipfs: &mut IpfsClient,
-        prev_remote: Option<&NIPRemote>,
-    ) -> Result<NIPRemote, Error> {
-        self.prev_idx_hash = match prev_remote {
-            Some(remote) => match remote {
-                NIPRemote::ExistingIPFS(_) => Some(remote.to_string()),
-                NIPRemote::ExistingIPNS(hash) => Some(ipns_deref(&hash, ipfs)?),
-                NIPRemote::NewIPFS | NIPRemote::NewIPNS => None,
-            },
-            None => None,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<bool, Error> {
+        let dir = match opts.journal {
+            Some(ref dir) => dir,
+            None => bail!("Resuming a push requires a journal directory"),
         };
 
-        // Encode
-        let mut self_buf = gen_nip_header(None)?;
-        self_buf.extend_from_slice(&serde_cbor::to_vec(self)?);
+        let mut changes = match PushJournal::load(dir)? {
+            Some(changes) => changes,
+            None => {
+                debug!("No push journal in {}, nothing to resume", dir.display());
+                return Ok(false);
+            }
+        };
+        info!(
+            "Resuming push: {} object(s) already uploaded, {} to go",
+            changes.objects.len(),
+            changes.pending.len()
+        );
 
-        // Upload
-        let add_req = ipfs.add(Cursor::new(self_buf));
-        let mut new_hash = format!("/ipfs/{}", current_thread::block_on_all(add_req)?.hash);
+        // Don't bother uploading what couldn't be applied anyway
+        self.check_leases(&changes)?;
+        changes.upload(repo, ipfs, opts)?;
 
-        // Publish on IPNS if applicable; prev_remote == None means no IPNS
-        if prev_remote.map(|remote| remote.is_ipns()).unwrap_or(false) {
-            debug!("Previous remote {:?} was IPNS, republishing", prev_remote);
+        self.apply_journaled(changes, opts)?;
+        Ok(true)
+    }
 
-            let publish_req = ipfs.name_publish(&new_hash, true, None, None, None);
+    /// `apply()` `changes` and drop the push journal kept for them, if any
+    fn apply_journaled(&mut self, changes: ChangeSet, opts: &TransferOptions) -> Result<(), Error> {
+        self.apply(changes)?;
+
+        if let Some(ref dir) = opts.journal {
+            PushJournal::clear(dir)?;
+        }
+
+        Ok(())
+    }
 
-            new_hash = format!("/ipns/{}", current_thread::block_on_all(publish_req)?.name);
+    /// Drop `ref_dst` from the index (only the ref, the objects aren't touched)
+    fn remove_ref(&mut self, ref_dst: &str) {
+        debug!("Removing ref {} from index", ref_dst);
+        match self.refs.remove(ref_dst) {
+            Some(old) => self.base.touch_ref(ref_dst, Some(old)),
+            None => {
+                warn!(
+                    "Nothing to delete, ref {} not part of the index ref set",
+                    ref_dst
+                );
+                debug!("Available refs:\n{:#?}", self.refs);
+            }
         }
+    }
+
+    /// Point `ref_name` at `git_hash`, keeping track of the change for `ipfs_add()`
+    fn set_ref(&mut self, ref_name: String, git_hash: String) {
+        let old = self.refs.insert(ref_name.clone(), git_hash);
+        self.base.touch_ref(&ref_name, old);
+    }
+
+    /// Link `git_hash` to `link`, keeping track of the change for `ipfs_add()`
+    fn set_object(&mut self, git_hash: String, link: String) {
+        let old = self.objects.insert(git_hash.clone(), link);
+        self.base.touch_object(&git_hash, old);
+    }
+
+    /// Find the object `ref_src` stands for in `repo`; annotated tags resolve to the tag object
+    /// itself.
+    fn resolve_push_src(ref_src: &str, repo: &Repository) -> Result<Oid, Error> {
+        let reference = repo.find_reference(ref_src)?.resolve()?;
+
+        // Differentiate between annotated tags and their commit representation
+        let obj = reference
+            .peel(ObjectType::Tag)
+            .unwrap_or(reference.peel(ObjectType::Commit)?);
+
+        debug!(
+            "{:?} dereferenced to {:?} {}",
+            reference.shorthand(),
+            obj.kind(),
+            obj.id()
+        );
+
+        Ok(obj.id())
+    }
+
+    /// Fail with `FetchFirst` if `ref_dst` points at objects `missing_objects` says aren't
+    /// present locally
+    fn ensure_nothing_missing(
+        ref_dst: &str,
+        missing_objects: &HashMap<Oid, NIPObject>,
+    ) -> Result<(), Error> {
+        if !missing_objects.is_empty() {
+            error!(
+                "There's {} objects in {} not present locally. Please fetch first or force-push.",
+                missing_objects.len(),
+                ref_dst
+            );
+
+            debug!("Missing objects:\n{:#?}", missing_objects.keys());
+            return Err(NIPIndexError::FetchFirst.into());
+        }
+
+        Ok(())
+    }
+
+    /// Count the objects behind all `(ref_dst, src_oid)` pairs of `updates` in one pass and stage
+    /// them along with pointing each `ref_dst` at its `src_oid`
+    fn stage_ref_updates(
+        &self,
+        updates: &[(String, Oid)],
+        repo: &Repository,
+        opts: &TransferOptions,
+    ) -> Result<ChangeSet, Error> {
+        let src_oids: Vec<Oid> = updates.iter().map(|(_, src_oid)| *src_oid).collect();
+        let (objs_for_push, submodules_for_push) = self.count_for_push(&src_oids, repo, opts)?;
+
+        let mut changes = ChangeSet::new();
+        changes.pending = self.unindexed(&objs_for_push);
+
+        // Submodule tips don't need uploading
+        for submod_oid in submodules_for_push {
+            changes
+                .objects
+                .insert(submod_oid.to_string(), SUBMODULE_TIP_MARKER.to_owned());
+        }
+
+        for (ref_dst, src_oid) in updates {
+            changes
+                .refs
+                .insert(ref_dst.clone(), Some(format!("{}", src_oid)));
+        }
+
+        Ok(changes)
+    }
+
+    /// Run `enumerate_tips_for_push()` on `src_oids`, returning the objects and submodule tips to
+    /// push
+    fn count_for_push(
+        &self,
+        src_oids: &[Oid],
+        repo: &Repository,
+        opts: &TransferOptions,
+    ) -> Result<(HashSet<Oid>, HashSet<Oid>), Error> {
+        let mut objs_for_push = HashSet::new();
+        let mut submodules_for_push = HashSet::new();
+
+        let tips = src_oids
+            .iter()
+            .map(|src_oid| repo.find_object(*src_oid, None))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let start = Instant::now();
+        self.enumerate_tips_for_push(
+            &tips,
+            &mut objs_for_push,
+            &mut submodules_for_push,
+            repo,
+            opts,
+        )?;
+        let dur = start.elapsed();
+
+        debug!(
+            "Counting objects took {}.{}s",
+            dur.as_secs(),
+            dur.subsec_micros()
+        );
+
+        debug!(
+            "Counted {} object(s) for push (took {}.{}):\n{:#?}",
+            dur.as_secs(),
+            dur.subsec_micros(),
+            objs_for_push.len(),
+            objs_for_push
+        );
+        debug!(
+            "Counted {} submodule stub(s) for push:\n{:#?}",
+            submodules_for_push.len(),
+            submodules_for_push
+        );
+
+        Ok((objs_for_push, submodules_for_push))
+    }
+
+    /// Iteratively fill two hash sets: `obj`'s children present in `repo` but missing from `self`
+    /// (`push_todo`), and `obj`'s children recognized as submodule tips. (`submodules`).
+    pub fn enumerate_for_push(
+        &self,
+        obj: &Object,
+        push_todo: &mut HashSet<Oid>,
+        submodules: &mut HashSet<Oid>,
+        repo: &Repository,
+    ) -> Result<(), Error> {
+        self.enumerate_for_push_with_opts(
+            obj,
+            push_todo,
+            submodules,
+            repo,
+            &TransferOptions::default(),
+        )
+    }
+
+    /// `enumerate_for_push()` with custom transfer options
+    pub fn enumerate_for_push_with_opts(
+        &self,
+        obj: &Object,
+        push_todo: &mut HashSet<Oid>,
+        submodules: &mut HashSet<Oid>,
+        repo: &Repository,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        self.enumerate_tips_for_push(slice::from_ref(obj), push_todo, submodules, repo, opts)
+    }
+
+    /// Like `enumerate_for_push_with_opts()`, but walks the history behind all of `tips` in a single pass so
+    /// that shared objects are only visited once.
+    pub fn enumerate_tips_for_push(
+        &self,
+        tips: &[Object],
+        push_todo: &mut HashSet<Oid>,
+        submodules: &mut HashSet<Oid>,
+        repo: &Repository,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        // Object tree traversal state
+        let mut stack = tips.to_vec();
+
+        let mut counting = PhaseTracker::start(TransferPhase::Counting, None, opts);
+        while let Some(obj) = stack.pop() {
+            opts.cancellation.check()?;
+
+            if self.objects.contains_key(&obj.id().to_string()) {
+                trace!("Object {} already in nip index", obj.id());
+                continue;
+            }
+
+            if push_todo.contains(&obj.id()) {
+                trace!("Object {} already in state", obj.id());
+                continue;
+            }
+
+            let obj_type = obj.kind().ok_or_else(|| {
+                let msg = format!("Cannot determine type of object {}", obj.id());
+                error!("{}", msg);
+                format_err!("{}", msg)
+            })?;
+
+            push_todo.insert(obj.id());
+            let obj_cnt = counting.objects() + 1;
+
+            match obj_type {
+                ObjectType::Commit => {
+                    let commit = obj
+                        .as_commit()
+                        .ok_or_else(|| format_err!("Could not view {:?} as a commit", obj))?;
+                    debug!("[{}] Counting commit {:?}", obj_cnt, commit);
+
+                    let tree_obj = obj.peel(ObjectType::Tree)?;
+                    trace!("Commit {}: Handling tree {}", commit.id(), tree_obj.id());
+
+                    stack.push(tree_obj);
+
+                    for parent in commit.parents() {
+                        trace!(
+                            "Commit {}: Pushing parent commit {}",
+                            commit.id(),
+                            parent.id()
+                        );
+                        stack.push(parent.into_object());
+                    }
+                }
+                ObjectType::Tree => {
+                    let tree = obj
+                        .as_tree()
+                        .ok_or_else(|| format_err!("Could not view {:?} as a tree", obj))?;
+                    debug!("[{}] Counting tree {:?}", obj_cnt, tree);
+
+                    for entry in tree.into_iter() {
+                        // Weed out submodules (Implicitly known as commit children of tree objects)
+                        if let Some(ObjectType::Commit) = entry.kind() {
+                            debug!("Skipping submodule at {}", entry.id());
+
+                            submodules.insert(entry.id());
+
+                            continue;
+                        }
+
+                        trace!(
+                            "Tree {}: Pushing tree entry {} ({:?})",
+                            tree.id(),
+                            entry.id(),
+                            entry.kind()
+                        );
+
+                        stack.push(entry.to_object(repo)?);
+                    }
+                }
+                ObjectType::Blob => {
+                    let blob = obj
+                        .as_blob()
+                        .ok_or_else(|| format_err!("Could not view {:?} as a blob", obj))?;
+                    debug!("[{}] Counting blob {:?}", obj_cnt, blob);
+                }
+                ObjectType::Tag => {
+                    let tag = obj
+                        .as_tag()
+                        .ok_or_else(|| format_err!("Could not view {:?} as a tag", obj))?;
+                    debug!("[{}] Counting tag {:?}", obj_cnt, tag);
+
+                    stack.push(tag.target()?);
+                }
+                other => {
+                    return Err(NIPError::InternalError(format!(
+                        "Don't know how to traverse a {}",
+                        other
+                    ))
+                    .into());
+                }
+            }
+
+            counting.object(0);
+        }
+        counting.finish();
+
+        Ok(())
+    }
+
+    /// Take `oids` and upload underlying `repo` git objects to IPFS. The first failed upload
+    /// aborts the push; the index only learns about the new objects once all of them are
+    /// uploaded.
+    pub fn push_git_objects<S: ContentStore>(
+        &mut self,
+        oids: &HashSet<Oid>,
+        repo: &Repository,
+        ipfs: &mut S,
+    ) -> Result<(), Error> {
+        self.push_git_objects_with_opts(oids, repo, ipfs, &TransferOptions::default())
+    }
+
+    /// `push_git_objects()` with custom transfer options, keeping at most `opts.max_in_flight`
+    /// objects in transfer at once.
+    ///
+    /// Blocking only; the `push_*_async()` functions cover it inside a tokio runtime.
+    pub fn push_git_objects_with_opts<S: ContentStore>(
+        &mut self,
+        oids: &HashSet<Oid>,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        let mut changes = ChangeSet::new();
+        changes.pending = self.unindexed(oids);
+        changes.file_store = self.file_store.clone();
+
+        changes.upload(repo, ipfs, opts)?;
+
+        self.apply_journaled(changes, opts)
+    }
+
+    /// Returns the subset of `oids` not present in the index yet
+    fn unindexed(&self, oids: &HashSet<Oid>) -> BTreeSet<String> {
+        oids.iter()
+            .map(|oid| oid.to_string())
+            .filter(|git_hash| {
+                if self.objects.contains_key(git_hash) {
+                    warn!("push_objects: Object {} already in nip index", git_hash);
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect()
+    }
+
+    /// Upload every object in `oids` with up to `opts.max_in_flight` objects in transfer at
+    /// once; yields a (sha1, NIPObject link) pair as each object is done.
+    fn upload_git_objects<S: ContentStore>(
+        oids: Vec<Oid>,
+        repo: Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Box<dyn Stream<Item = (String, String), Error = Error> + Send> {
+        let oid_count = oids.len();
+        let mut ipfs = ipfs.clone();
+        let cancellation = opts.cancellation.clone();
+
+        let uploads = stream::iter_ok(oids)
+            .map(move |oid| -> StoreFuture<_> {
+                if let Err(e) = cancellation.check() {
+                    return Box::new(future::err(e));
+                }
+
+                Self::upload_git_object(oid, &repo, &mut ipfs)
+            })
+            .buffer_unordered(opts.max_in_flight.max(1));
+
+        let mut uploading = PhaseTracker::start(TransferPhase::Uploading, Some(oid_count), opts);
+        Box::new(uploads.map(move |(nip_obj, nip_object_hash, bytes)| {
+            uploading.object(bytes);
+            debug!(
+                "[{}/{}] {} {} uploaded to {}",
+                uploading.objects(),
+                oid_count,
+                nip_obj.metadata.object_type(),
+                nip_obj.git_hash,
+                nip_object_hash
+            );
+
+            (nip_obj.git_hash, nip_object_hash)
+        }))
+    }
+
+    /// Upload the raw data of `oid` followed by its `NIPObject`; resolves to the uploaded object,
+    /// its link and the number of bytes uploaded.
+    fn upload_git_object<S: ContentStore>(
+        oid: Oid,
+        repo: &Repository,
+        ipfs: &mut S,
+    ) -> StoreFuture<(NIPObject, String, u64)> {
+        let prepared = repo.find_object(oid, None).and_then(|obj| {
+            trace!("Current object: {:?} at {}", obj.kind(), obj.id());
+            Ok((obj, repo.odb()?.read(oid)?.data().to_vec()))
+        });
+        let (raw_data, metadata) = match prepared {
+            Ok((obj, raw_data)) => match NIPObjectMetadata::from_git_object(&obj) {
+                Ok(metadata) => (raw_data, metadata),
+                Err(e) => return Box::new(future::err(e)),
+            },
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+
+        let raw_data_len = raw_data.len() as u64;
+        let raw_data_req = ipfs.add(raw_data);
+        let mut ipfs = ipfs.clone();
+        Box::new(raw_data_req.and_then(move |raw_data_ipfs_hash| {
+            let nip_obj = NIPObject {
+                git_hash: oid.to_string(),
+                raw_data_ipfs_hash,
+                metadata,
+            };
+
+            future::result(nip_obj.to_vec()).and_then(move |nip_obj_buf| {
+                let bytes = raw_data_len + nip_obj_buf.len() as u64;
+                ipfs.add(nip_obj_buf)
+                    .map(move |nip_object_hash| (nip_obj, nip_object_hash, bytes))
+            })
+        }))
+    }
+
+    /// Returns the store the index keeps its objects in, i.e. `ipfs` or the `FsStore` of the
+    /// file remote the index belongs to
+    fn object_store<S: ContentStore>(&self, ipfs: &S) -> Result<IndexStore<S>, Error> {
+        IndexStore::new(self.file_store.as_ref(), ipfs)
+    }
+
+    /// Fetch `git_hash` from `self` to `repo`'s `ref_name` ref.
+    pub fn fetch_to_ref_from_str<S: ContentStore>(
+        &self,
+        git_hash: &str,
+        ref_name: &str,
+        repo: &mut Repository,
+        ipfs: &mut S,
+    ) -> Result<(), Error> {
+        self.fetch_to_ref_from_str_with_opts(
+            git_hash,
+            ref_name,
+            repo,
+            ipfs,
+            &TransferOptions::default(),
+        )
+    }
+
+    /// `fetch_to_ref_from_str()` with custom transfer options
+    pub fn fetch_to_ref_from_str_with_opts<S: ContentStore>(
+        &self,
+        git_hash: &str,
+        ref_name: &str,
+        repo: &mut Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        self.fetch_refs(&[(git_hash, ref_name)], repo, ipfs, opts)
+    }
+
+    /// Fetch several `(git_hash, ref_name)` tips at once. The history behind all of them is
+    /// counted in one pass and every missing object is downloaded once; the local refs are only
+    /// updated after that. Downloads are cached according to `opts.cache`.
+    pub fn fetch_refs<S: ContentStore>(
+        &self,
+        tips: &[(&str, &str)],
+        repo: &mut Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        let tips = Self::parse_fetch_tips(tips)?;
+        let oids: Vec<Oid> = tips.iter().map(|(oid, _)| *oid).collect();
+        let mut oids_for_fetch = HashMap::new();
+        let mut ipfs = opts.cache.wrap(self.object_store(ipfs)?, repo)?;
+
+        let start = Instant::now();
+        self.walk_tips_for_fetch(&oids, &mut oids_for_fetch, repo, &mut ipfs, opts)?;
+        Self::log_fetch_count(start, &oids_for_fetch);
+
+        Self::fetch_raw_objects(&oids_for_fetch, repo, &mut ipfs, opts)?;
+
+        Self::set_fetched_refs(&tips, repo)
+    }
+
+    /// A non-blocking version of `fetch_to_ref_from_str()`. The index is handed back once the
+    /// fetch completes.
+    pub fn fetch_to_ref_from_str_async<S: ContentStore>(
+        self,
+        git_hash: &str,
+        ref_name: &str,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<Self> {
+        self.fetch_refs_async(&[(git_hash, ref_name)], repo, ipfs, opts)
+    }
+
+    /// A non-blocking version of `fetch_refs()`. The index is handed back once the fetch
+    /// completes.
+    pub fn fetch_refs_async<S: ContentStore>(
+        self,
+        tips: &[(&str, &str)],
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<Self> {
+        let prepared = Self::parse_fetch_tips(tips).and_then(|tips| {
+            let ipfs = opts.cache.wrap(self.object_store(ipfs)?, repo)?;
+            Ok((tips, reopen_repo(repo)?, ipfs))
+        });
+        let (tips, repo, mut ipfs) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let oids = tips.iter().map(|(oid, _)| *oid).collect();
+
+        let opts = opts.clone();
+
+        let start = Instant::now();
+        Box::new(
+            self.enumerate_for_fetch_async(oids, repo, &mut ipfs, &opts)
+                .and_then(move |(idx, repo, oids_for_fetch)| {
+                    Self::log_fetch_count(start, &oids_for_fetch);
+
+                    let todo = repo
+                        .odb()
+                        .map(|odb| Self::raw_data_todo(&oids_for_fetch, &odb));
+                    future::result(todo)
+                        .map_err(Error::from)
+                        .and_then(move |todo| {
+                            Self::download_raw_objects(todo, repo, &mut ipfs, &opts)
+                        })
+                        .and_then(move |repo| {
+                            Self::set_fetched_refs(&tips, &repo)?;
+                            Ok(idx)
+                        })
+                }),
+        )
+    }
+
+    fn log_fetch_count(start: Instant, oids_for_fetch: &HashMap<Oid, NIPObject>) {
+        let dur = start.elapsed();
+        debug!(
+            "Counting objects took {}.{}s",
+            dur.as_secs(),
+            dur.subsec_micros()
+        );
+        debug!(
+            "Counted {} object(s) for fetch:\n{:#?}",
+            oids_for_fetch.len(),
+            oids_for_fetch.keys()
+        );
+    }
+
+    /// Parse the git hashes of `fetch_refs()` tips
+    fn parse_fetch_tips(tips: &[(&str, &str)]) -> Result<Vec<(Oid, String)>, Error> {
+        tips.iter()
+            .map(|(git_hash, ref_name)| {
+                debug!("Fetching {} for {}", git_hash, ref_name);
+                Ok((Oid::from_str(git_hash)?, (*ref_name).to_owned()))
+            })
+            .collect()
+    }
+
+    /// Set the refs of all fetched `tips`; nothing is set unless all of them are there
+    fn set_fetched_refs(tips: &[(Oid, String)], repo: &Repository) -> Result<(), Error> {
+        let odb = repo.odb()?;
+        if let Some((oid, ref_name)) = tips.iter().find(|(oid, _)| !odb.exists(*oid)) {
+            let msg = format!("Tip {} for {} missing after fetch", oid, ref_name);
+            error!("{}", msg);
+            return Err(NIPError::InternalError(msg).into());
+        }
+
+        for (git_hash_oid, ref_name) in tips {
+            Self::set_fetched_ref(*git_hash_oid, ref_name, repo)?;
+            debug!("Fetched {} for {} OK.", git_hash_oid, ref_name);
+        }
+
+        Ok(())
+    }
+
+    /// Point `ref_name` at the freshly fetched `git_hash_oid` where git expects us to
+    fn set_fetched_ref(git_hash_oid: Oid, ref_name: &str, repo: &Repository) -> Result<(), Error> {
+        match repo.odb()?.read_header(git_hash_oid)?.1 {
+            ObjectType::Commit if ref_name.starts_with("refs/tags") => {
+                debug!("Not setting ref for lightweight tag {}", ref_name);
+            }
+            ObjectType::Commit => {
+                repo.reference(ref_name, git_hash_oid, true, "nip fetch")?;
+            }
+            // Somehow git is upset when we set tag refs for it
+            ObjectType::Tag => {
+                debug!("Not setting ref for tag {}", ref_name);
+            }
+            other_type => {
+                let msg = format!("New tip turned out to be a {} after fetch", other_type);
+                error!("{}", msg);
+                return Err(NIPError::InternalError(msg).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fill a hash set with `oid`'s children that are present in `self` but missing in `repo`.
+    pub fn enumerate_for_fetch<S: ContentStore>(
+        &self,
+        oid: Oid,
+        fetch_todo: &mut HashSet<Oid>,
+        repo: &Repository,
+        ipfs: &mut S,
+    ) -> Result<(), Error> {
+        let mut nip_objs = HashMap::new();
+        self.enumerate_for_fetch_with_opts(
+            oid,
+            &mut nip_objs,
+            repo,
+            ipfs,
+            &TransferOptions::default(),
+        )?;
+        fetch_todo.extend(nip_objs.keys());
+        Ok(())
+    }
+
+    /// Fill a hash map with `oid`'s children that are present in `self` but missing in `repo`,
+    /// along with their already downloaded `NIPObject`s. The object graph is walked
+    /// breadth-first, downloading each frontier with up to `opts.max_in_flight` requests in
+    /// flight.
+    ///
+    /// Blocking only; `fetch_refs_async()` covers it inside a tokio runtime.
+    pub fn enumerate_for_fetch_with_opts<S: ContentStore>(
+        &self,
+        oid: Oid,
+        fetch_todo: &mut HashMap<Oid, NIPObject>,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        self.enumerate_tips_for_fetch(&[oid], fetch_todo, repo, ipfs, opts)
+    }
+
+    /// Like `enumerate_for_fetch_with_opts()`, but walks the history behind all of `oids` at once so that
+    /// shared objects are only downloaded once.
+    ///
+    /// Blocking only; `fetch_refs_async()` covers it inside a tokio runtime.
+    pub fn enumerate_tips_for_fetch<S: ContentStore>(
+        &self,
+        oids: &[Oid],
+        fetch_todo: &mut HashMap<Oid, NIPObject>,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        let mut ipfs = self.object_store(ipfs)?;
+        self.walk_tips_for_fetch(oids, fetch_todo, repo, &mut ipfs, opts)
+    }
+
+    /// `enumerate_tips_for_fetch()` with `ipfs` being the store the objects live in
+    fn walk_tips_for_fetch<S: ContentStore>(
+        &self,
+        oids: &[Oid],
+        fetch_todo: &mut HashMap<Oid, NIPObject>,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        let odb = repo.odb()?;
+        let mut frontier = oids.to_vec();
+        let mut queued = HashSet::new();
+        let mut counting = PhaseTracker::start(TransferPhase::Counting, None, opts);
+
+        while !frontier.is_empty() {
+            opts.cancellation.check()?;
+
+            let frontier_links =
+                self.plan_fetch_frontier(&mut frontier, fetch_todo, &mut queued, &odb)?;
+
+            let downloaded = current_thread::block_on_all(Self::download_nip_objects(
+                frontier_links,
+                ipfs,
+                opts,
+            ))?;
+
+            Self::expand_fetch_frontier(downloaded, &mut frontier, fetch_todo, &mut counting)?;
+        }
+        counting.finish();
+
+        Ok(())
+    }
+
+    /// A non-blocking version of `enumerate_tips_for_fetch()` working on owned state; resolves to
+    /// the index, the repo and the filled hash map.
+    fn enumerate_for_fetch_async<S: ContentStore>(
+        self,
+        oids: Vec<Oid>,
+        repo: Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<(Self, Repository, HashMap<Oid, NIPObject>)> {
+        let mut ipfs = ipfs.clone();
+        let opts = opts.clone();
+
+        let state = FetchEnumeration {
+            idx: self,
+            repo,
+            frontier: oids,
+            fetch_todo: HashMap::new(),
+            queued: HashSet::new(),
+            counting: PhaseTracker::start(TransferPhase::Counting, None, &opts),
+        };
+
+        Box::new(
+            future::loop_fn(
+                state,
+                move |mut state| -> StoreFuture<Loop<FetchEnumeration, FetchEnumeration>> {
+                    if state.frontier.is_empty() {
+                        return Box::new(future::ok(Loop::Break(state)));
+                    }
+                    if let Err(e) = opts.cancellation.check() {
+                        return Box::new(future::err(e));
+                    }
+
+                    let frontier_links = {
+                        let FetchEnumeration {
+                            ref idx,
+                            ref repo,
+                            ref mut frontier,
+                            ref fetch_todo,
+                            ref mut queued,
+                            ..
+                        } = state;
+
+                        repo.odb().map_err(Error::from).and_then(|odb| {
+                            idx.plan_fetch_frontier(frontier, fetch_todo, queued, &odb)
+                        })
+                    };
+                    let frontier_links = match frontier_links {
+                        Ok(frontier_links) => frontier_links,
+                        Err(e) => return Box::new(future::err(e)),
+                    };
+
+                    Box::new(
+                        Self::download_nip_objects(frontier_links, &mut ipfs, &opts).and_then(
+                            move |downloaded| {
+                                Self::expand_fetch_frontier(
+                                    downloaded,
+                                    &mut state.frontier,
+                                    &mut state.fetch_todo,
+                                    &mut state.counting,
+                                )?;
+                                Ok(Loop::Continue(state))
+                            },
+                        ),
+                    )
+                },
+            )
+            .map(|mut state| {
+                state.counting.finish();
+                (state.idx, state.repo, state.fetch_todo)
+            }),
+        )
+    }
+
+    /// Drain `frontier` and return the objects from it that still need downloading along with
+    /// their NIPObject links
+    fn plan_fetch_frontier(
+        &self,
+        frontier: &mut Vec<Oid>,
+        fetch_todo: &HashMap<Oid, NIPObject>,
+        queued: &mut HashSet<Oid>,
+        odb: &Odb,
+    ) -> Result<Vec<(Oid, String)>, Error> {
+        let mut frontier_links = Vec::new();
+
+        for oid in frontier.drain(..) {
+            if odb.read_header(oid).is_ok() {
+                trace!("Object {} already present locally!", oid);
+                continue;
+            }
+
+            if fetch_todo.contains_key(&oid) || queued.contains(&oid) {
+                trace!("Object {} already present in state!", oid);
+                continue;
+            }
+
+            let nip_obj_ipfs_hash = self
+                .objects
+                .get(&format!("{}", oid))
+                .ok_or_else(|| {
+                    let msg = format!("Could not find object {} in the index", oid);
+                    error!("{}", msg);
+                    format_err!("{}", msg)
+                })?
+                .clone();
+
+            if nip_obj_ipfs_hash == SUBMODULE_TIP_MARKER {
+                debug!("Ommitting submodule {}", oid);
+                continue;
+            }
+
+            queued.insert(oid);
+            frontier_links.push((oid, nip_obj_ipfs_hash));
+        }
+
+        Ok(frontier_links)
+    }
+
+    /// Download the NIPObjects behind `frontier_links` with up to `opts.max_in_flight` requests
+    /// in flight; every object comes with its size in bytes.
+    fn download_nip_objects<S: ContentStore>(
+        frontier_links: Vec<(Oid, String)>,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<Vec<(Oid, String, NIPObject, u64)>> {
+        let mut ipfs = ipfs.clone();
+        let cancellation = opts.cancellation.clone();
+
+        Box::new(
+            stream::iter_ok(frontier_links)
+                .map(move |(oid, nip_obj_ipfs_hash)| -> StoreFuture<_> {
+                    if let Err(e) = cancellation.check() {
+                        return Box::new(future::err(e));
+                    }
+
+                    Box::new(ipfs.cat(&nip_obj_ipfs_hash).and_then(move |bytes| {
+                        let nip_obj = NIPObject::from_slice(&bytes)?;
+                        Ok((oid, nip_obj_ipfs_hash, nip_obj, bytes.len() as u64))
+                    }))
+                })
+                .buffer_unordered(opts.max_in_flight.max(1))
+                .collect(),
+        )
+    }
+
+    /// Put the children of `downloaded` objects on the next `frontier` and move the objects to
+    /// `fetch_todo`
+    fn expand_fetch_frontier(
+        downloaded: Vec<(Oid, String, NIPObject, u64)>,
+        frontier: &mut Vec<Oid>,
+        fetch_todo: &mut HashMap<Oid, NIPObject>,
+        counting: &mut PhaseTracker,
+    ) -> Result<(), Error> {
+        for (oid, nip_obj_ipfs_hash, nip_obj, bytes) in downloaded {
+            let obj_cnt = counting.objects() + 1;
+            match nip_obj.metadata {
+                NIPObjectMetadata::Commit {
+                    ref parent_git_hashes,
+                    ref tree_git_hash,
+                } => {
+                    debug!("[{}] Counting nip commit {}", obj_cnt, nip_obj_ipfs_hash);
+
+                    frontier.push(Oid::from_str(tree_git_hash)?);
+
+                    for parent_git_hash in parent_git_hashes {
+                        frontier.push(Oid::from_str(parent_git_hash)?);
+                    }
+                }
+                NIPObjectMetadata::Tag {
+                    ref target_git_hash,
+                } => {
+                    debug!("[{}] Counting nip tag {}", obj_cnt, nip_obj_ipfs_hash);
+
+                    frontier.push(Oid::from_str(target_git_hash)?);
+                }
+                NIPObjectMetadata::Tree {
+                    ref entry_git_hashes,
+                } => {
+                    debug!("[{}] Counting nip tree {}", obj_cnt, nip_obj_ipfs_hash);
+
+                    for entry_git_hash in entry_git_hashes {
+                        frontier.push(Oid::from_str(entry_git_hash)?);
+                    }
+                }
+                NIPObjectMetadata::Blob => {
+                    debug!("[{}] Counting nip blob {}", obj_cnt, nip_obj_ipfs_hash);
+                }
+            }
+            fetch_todo.insert(oid, nip_obj);
+            counting.object(bytes);
+        }
+
+        Ok(())
+    }
+
+    /// Download git objects in `oids` from IPFS and instantiate them in `repo`.
+    pub fn fetch_nip_objects<S: ContentStore>(
+        &self,
+        oids: &HashSet<Oid>,
+        repo: &mut Repository,
+        ipfs: &mut S,
+    ) -> Result<(), Error> {
+        let opts = TransferOptions::default();
+        let mut ipfs = self.object_store(ipfs)?;
+
+        let links = oids
+            .iter()
+            .map(|&oid| {
+                let nip_obj_ipfs_hash = self.objects.get(&oid.to_string()).ok_or_else(|| {
+                    let msg = format!("Could not find object {} in nip index", oid);
+                    error!("{}", msg);
+                    format_err!("{}", msg)
+                })?;
+                Ok((oid, nip_obj_ipfs_hash.clone()))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let nip_objs =
+            current_thread::block_on_all(Self::download_nip_objects(links, &mut ipfs, &opts))?
+                .into_iter()
+                .map(|(oid, _, nip_obj, _)| (oid, nip_obj))
+                .collect();
+
+        Self::fetch_raw_objects(&nip_objs, repo, &mut ipfs, &opts)
+    }
+
+    /// Download the raw data behind `nip_objs` (as filled by `enumerate_for_fetch_with_opts()`)
+    /// and instantiate the git objects in `repo`, keeping at most `opts.max_in_flight` objects in
+    /// transfer at once.
+    ///
+    /// Blocking only; `fetch_refs_async()` covers it inside a tokio runtime.
+    pub fn fetch_nip_objects_with_opts<S: ContentStore>(
+        &self,
+        nip_objs: &HashMap<Oid, NIPObject>,
+        repo: &mut Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        Self::fetch_raw_objects(nip_objs, repo, &mut self.object_store(ipfs)?, opts)
+    }
+
+    /// `fetch_nip_objects()` with `ipfs` being the store the objects live in
+    fn fetch_raw_objects<S: ContentStore>(
+        nip_objs: &HashMap<Oid, NIPObject>,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        let todo = Self::raw_data_todo(nip_objs, &repo.odb()?);
+
+        current_thread::block_on_all(Self::download_raw_objects(
+            todo,
+            reopen_repo(repo)?,
+            ipfs,
+            opts,
+        ))?;
+
+        Ok(())
+    }
+
+    /// Returns the oid, raw data link and type of every object in `nip_objs` missing from `odb`
+    fn raw_data_todo(
+        nip_objs: &HashMap<Oid, NIPObject>,
+        odb: &Odb,
+    ) -> Vec<(Oid, String, ObjectType)> {
+        nip_objs
+            .iter()
+            .filter(|(oid, _)| {
+                if odb.read_header(**oid).is_ok() {
+                    warn!("fetch_nip_objects: Object {} already present locally!", oid);
+                    false
+                } else {
+                    true
+                }
+            })
+            .map(|(oid, nip_obj)| {
+                (
+                    *oid,
+                    nip_obj.raw_data_ipfs_hash.clone(),
+                    nip_obj.metadata.object_type(),
+                )
+            })
+            .collect()
+    }
+
+    /// Download and write the objects in `todo` to `repo` with up to `opts.max_in_flight`
+    /// requests in flight; resolves to `repo` when done.
+    fn download_raw_objects<S: ContentStore>(
+        todo: Vec<(Oid, String, ObjectType)>,
+        repo: Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<Repository> {
+        let oid_count = todo.len();
+        let mut ipfs = ipfs.clone();
+        let cancellation = opts.cancellation.clone();
+
+        let downloads = stream::iter_ok(todo)
+            .map(
+                move |(oid, raw_data_ipfs_hash, obj_type)| -> StoreFuture<_> {
+                    if let Err(e) = cancellation.check() {
+                        return Box::new(future::err(e));
+                    }
+
+                    Box::new(
+                        ipfs.cat(&raw_data_ipfs_hash)
+                            .map(move |raw_data| (oid, raw_data_ipfs_hash, obj_type, raw_data)),
+                    )
+                },
+            )
+            .buffer_unordered(opts.max_in_flight.max(1));
+
+        let downloading = PhaseTracker::start(TransferPhase::Downloading, Some(oid_count), opts);
+        Box::new(
+            downloads
+                .fold(
+                    (repo, downloading),
+                    move |(repo, mut downloading),
+                          (oid, raw_data_ipfs_hash, obj_type, raw_data)| {
+                        downloading.object(raw_data.len() as u64);
+                        debug!(
+                            "[{}/{}] Fetched object {}",
+                            downloading.objects(),
+                            oid_count,
+                            oid
+                        );
+
+                        Self::write_raw_object(
+                            oid,
+                            &raw_data_ipfs_hash,
+                            obj_type,
+                            &raw_data,
+                            &repo,
+                        )?;
+                        Ok::<_, Error>((repo, downloading))
+                    },
+                )
+                .map(|(repo, mut downloading)| {
+                    downloading.finish();
+                    repo
+                }),
+        )
+    }
+
+    /// Write `raw_data` downloaded from `raw_data_ipfs_hash` to `repo`, making sure it hashes to
+    /// `oid`
+    fn write_raw_object(
+        oid: Oid,
+        raw_data_ipfs_hash: &str,
+        obj_type: ObjectType,
+        raw_data: &[u8],
+        repo: &Repository,
+    ) -> Result<(), Error> {
+        let written_oid = repo.odb()?.write(obj_type, raw_data)?;
+        if written_oid != oid {
+            let msg = format!("Object tree inconsistency detected: fetched {} from {}, but write result hashes to {}", oid, raw_data_ipfs_hash, written_oid);
+            error!("{}", msg);
+            return Err(NIPError::InternalError(msg).into());
+        }
+        trace!("Fetched object {} to {}", raw_data_ipfs_hash, written_oid);
+
+        Ok(())
+    }
+
+    /// Upload `self` to IPFS and return the IPFS/IPNS link. Plain/IPNS link use is determined as
+    /// per `prev_remote` variant (IPNS is used for both `NewIPNS` and `ExistingIPNS`, `None`
+    /// assumes IPFS, `*File` variants yield an `ExistingFile` in the same directory);
+    /// `prev_remote` is later put in the `prev_idx_hash` field just before upload. Like
+    /// `from_nip_remote()`, `*File` remotes are written to their `FsStore` directory instead of
+    /// `ipfs`.
+    ///
+    /// Before publishing to an `ExistingIPNS` remote the name is resolved once more; if it no
+    /// longer points at the index `self` was downloaded from, the upload is refused with
+    /// `NIPIndexError::ConcurrentWrite` so that another writer's push isn't silently dropped. To
+    /// retry, download the remote's current index and `merge()` `self` into it.
+    ///
+    /// An index replacing the one it was downloaded from is published as a delta holding just the
+    /// refs and objects that changed, with a full checkpoint after every `MAX_DELTA_CHAIN` deltas.
+    /// `from_nip_remote()` follows `prev_idx_hash` back to the last checkpoint to rebuild it. Only
+    /// changes made with `apply()` or `merge()` are tracked for the delta; an index whose `refs` or
+    /// `objects` were edited directly is published in full.
+    pub fn ipfs_add<S: ContentStore>(
+        &mut self,
+        ipfs: &mut S,
+        prev_remote: Option<&NIPRemote>,
+    ) -> Result<NIPRemote, Error> {
+        match prev_remote.and_then(NIPRemote::file_path) {
+            Some(path) => self.add_to_store(&mut FsStore::open(path)?, prev_remote),
+            None => self.add_to_store(ipfs, prev_remote),
+        }
+    }
+
+    /// A non-blocking version of `ipfs_add()`; resolves to the updated index and its remote.
+    pub fn ipfs_add_async<S: ContentStore>(
+        self,
+        ipfs: &mut S,
+        prev_remote: Option<&NIPRemote>,
+    ) -> StoreFuture<(Self, NIPRemote)> {
+        match prev_remote.and_then(NIPRemote::file_path) {
+            Some(path) => match FsStore::open(path) {
+                Ok(mut store) => self.add_to_store_async(&mut store, prev_remote),
+                Err(e) => Box::new(future::err(e)),
+            },
+            None => self.add_to_store_async(ipfs, prev_remote),
+        }
+    }
+
+    /// `ipfs_add()` with `ipfs` being the store `prev_remote` lives in. The index is left as it
+    /// was if the upload fails.
+    fn add_to_store<S: ContentStore>(
+        &mut self,
+        ipfs: &mut S,
+        prev_remote: Option<&NIPRemote>,
+    ) -> Result<NIPRemote, Error> {
+        let prev_idx_hash =
+            current_thread::block_on_all(Self::resolve_prev_idx_hash(prev_remote, ipfs))?;
+        self.ensure_no_concurrent_write(prev_remote, prev_idx_hash.as_ref())?;
+
+        let saved = (self.prev_idx_hash.clone(), self.merged_idx_hash.clone());
+        self.prepare_upload(prev_idx_hash);
+
+        match self.upload_prepared(ipfs, prev_remote) {
+            Ok(((link, remote), shards, delta_depth)) => {
+                self.finish_upload(link, &remote, shards, delta_depth);
+                Ok(remote)
+            }
+            Err(e) => {
+                let (prev_idx_hash, merged_idx_hash) = saved;
+                self.prev_idx_hash = prev_idx_hash;
+                self.merged_idx_hash = merged_idx_hash;
+                Err(e)
+            }
+        }
+    }
+
+    /// Encode and upload the index readied by `prepare_upload()`; returns the upload's link and
+    /// remote along with what `finish_upload()` needs
+    fn upload_prepared<S: ContentStore>(
+        &self,
+        ipfs: &mut S,
+        prev_remote: Option<&NIPRemote>,
+    ) -> Result<((String, NIPRemote), ShardCache, usize), Error> {
+        let (self_buf, shards, delta_depth) =
+            current_thread::block_on_all(self.encode_async(ipfs))?;
+        let uploaded =
+            current_thread::block_on_all(Self::upload_self_buf(self_buf, ipfs, prev_remote))?;
+
+        Ok((uploaded, shards, delta_depth))
+    }
+
+    /// `ipfs_add_async()` with `ipfs` being the store `prev_remote` lives in
+    fn add_to_store_async<S: ContentStore>(
+        mut self,
+        ipfs: &mut S,
+        prev_remote: Option<&NIPRemote>,
+    ) -> StoreFuture<(Self, NIPRemote)> {
+        let prev_idx_hash_req = Self::resolve_prev_idx_hash(prev_remote, ipfs);
+        let mut ipfs = ipfs.clone();
+        let prev_remote = prev_remote.cloned();
+
+        Box::new(prev_idx_hash_req.and_then(move |prev_idx_hash| {
+            if let Err(e) =
+                self.ensure_no_concurrent_write(prev_remote.as_ref(), prev_idx_hash.as_ref())
+            {
+                return Box::new(future::err(e)) as StoreFuture<_>;
+            }
+            self.prepare_upload(prev_idx_hash);
+
+            let mut encode_ipfs = ipfs.clone();
+            Box::new(
+                self.encode_async(&mut encode_ipfs)
+                    .and_then(move |(self_buf, shards, delta_depth)| {
+                        Self::upload_self_buf(self_buf, &mut ipfs, prev_remote.as_ref())
+                            .map(move |uploaded| (uploaded, shards, delta_depth))
+                    })
+                    .map(move |((link, remote), shards, delta_depth)| {
+                        self.finish_upload(link, &remote, shards, delta_depth);
+                        (self, remote)
+                    }),
+            )
+        }))
+    }
+
+    /// Point the index at `prev_idx_hash` before it's encoded for upload
+    fn prepare_upload(&mut self, prev_idx_hash: Option<String>) {
+        self.prev_idx_hash = prev_idx_hash;
+        // Only the index a merge produced records the merged one, the next ones don't
+        if let Some(ref base) = self.base.0 {
+            if self.merged_idx_hash.is_some() && self.merged_idx_hash == base.merged_idx_hash {
+                self.merged_idx_hash = None;
+            }
+        }
+    }
+
+    /// Record that the index was uploaded to `link` in `remote` along with `shards`,
+    /// `delta_depth` deltas after the last full index
+    fn finish_upload(
+        &mut self,
+        link: String,
+        remote: &NIPRemote,
+        shards: ShardCache,
+        delta_depth: usize,
+    ) {
+        if let Some(path) = remote.file_path() {
+            self.file_store = Some(path.to_owned());
+        }
+        self.base_idx_hash = Some(link);
+        self.shards = shards;
+        self.base = IndexBase::of(self, delta_depth);
+    }
+
+    /// Serialize `self` for upload. Indices based on their `prev_idx_hash` are encoded as a delta
+    /// to it unless that would make the chain of deltas longer than `MAX_DELTA_CHAIN`; the objects
+    /// of big full indices are stored in shards first. Resolves to the index bytes, the shards
+    /// they refer to and the number of deltas since the last full index.
+    fn encode_async<S: ContentStore>(
+        &self,
+        ipfs: &mut S,
+    ) -> StoreFuture<(Vec<u8>, ShardCache, usize)> {
+        if let Some(delta) = self.delta_from_base() {
+            debug!(
+                "Encoding index as delta number {} with {} change(s)",
+                delta.depth,
+                delta.len()
+            );
+            let delta_depth = delta.depth;
+            let stored = StoredIndex {
+                refs: BTreeMap::new(),
+                objects: None,
+                object_root: None,
+                delta: Some(delta),
+                prev_idx_hash: self.prev_idx_hash.clone(),
+                merged_idx_hash: self.merged_idx_hash.clone(),
+            };
+            let shards = self.shards.clone();
+            return Box::new(future::result(
+                Self::encode_stored(&stored).map(|self_buf| (self_buf, shards, delta_depth)),
+            ));
+        }
+
+        if self.objects.len() <= MAX_SHARD_OBJECTS {
+            return Box::new(future::result(
+                self.to_vec()
+                    .map(|self_buf| (self_buf, ShardCache::default(), 0)),
+            ));
+        }
+
+        let refs = self.refs.clone();
+        let prev_idx_hash = self.prev_idx_hash.clone();
+        let merged_idx_hash = self.merged_idx_hash.clone();
+        Box::new(
+            shard::store_objects(&self.objects, &self.shards, ipfs).and_then(
+                move |(object_root, shards)| {
+                    let stored = StoredIndex {
+                        refs,
+                        objects: None,
+                        object_root: Some(object_root),
+                        delta: None,
+                        prev_idx_hash,
+                        merged_idx_hash,
+                    };
+
+                    Ok((Self::encode_stored(&stored)?, shards, 0))
+                },
+            ),
+        )
+    }
+
+    /// Work out the changes `self` makes to its base from the refs and objects touched since.
+    /// Returns `None` if a full index should be published instead, i.e. when the base isn't the
+    /// previous index, the chain of deltas is due a checkpoint, the index was changed in ways the
+    /// base doesn't know about or the delta wouldn't be much smaller than the full index.
+    fn delta_from_base(&self) -> Option<IndexDelta> {
+        let base = self.base.0.as_ref()?;
+        match (self.prev_idx_hash.as_ref(), self.base_idx_hash.as_ref()) {
+            (Some(prev), Some(base_link)) if bare_hash(prev) == bare_hash(base_link) => {}
+            _ => return None,
+        }
+        if base.delta_depth >= MAX_DELTA_CHAIN {
+            debug!("{} deltas since the last full index", base.delta_depth);
+            return None;
+        }
+
+        // Swap the touched entries' base values for their current ones in the base fingerprint
+        let mut expected = base.fingerprint;
+        for (tag, touched, current) in &[
+            (REF_TAG, &base.refs_touched, &self.refs),
+            (OBJECT_TAG, &base.objects_touched, &self.objects),
+        ] {
+            for (key, old) in touched.iter() {
+                if let Some(old) = old {
+                    expected = expected.wrapping_sub(entry_fingerprint(*tag, key, old));
+                }
+                if let Some(new) = current.get(key) {
+                    expected = expected.wrapping_add(entry_fingerprint(*tag, key, new));
+                }
+            }
+        }
+        if expected != fingerprint(&self.refs, &self.objects) {
+            debug!("Index changed outside of apply() or merge(), falling back to a full index");
+            return None;
+        }
+
+        let (refs_set, refs_removed) = touched_changes(&base.refs_touched, &self.refs);
+        let (objects_added, objects_removed) =
+            touched_changes(&base.objects_touched, &self.objects);
+        let delta = IndexDelta {
+            depth: base.delta_depth + 1,
+            refs_set,
+            refs_removed,
+            objects_added,
+            objects_removed,
+        };
+        if delta.len() * 2 > self.refs.len() + self.objects.len() {
+            debug!("Delta too big, falling back to a full index");
+            return None;
+        }
+
+        Some(delta)
+    }
+
+    fn encode_stored(stored: &StoredIndex) -> Result<Vec<u8>, Error> {
+        let mut self_buf = gen_nip_header(None)?;
+        self_buf.extend_from_slice(&serde_cbor::to_vec(stored)?);
+
+        Ok(self_buf)
+    }
+
+    /// Work out the `prev_idx_hash` value for an index replacing `prev_remote`
+    fn resolve_prev_idx_hash<S: ContentStore>(
+        prev_remote: Option<&NIPRemote>,
+        ipfs: &mut S,
+    ) -> StoreFuture<Option<String>> {
+        match prev_remote {
+            Some(remote) => match remote {
+                NIPRemote::ExistingIPFS(_) => Box::new(future::ok(Some(remote.to_string()))),
+                NIPRemote::ExistingIPNS(hash) => Box::new(ipfs.name_resolve(hash).map(Some)),
+                NIPRemote::ExistingFile(_, hash) => {
+                    Box::new(future::ok(Some(format!("/ipfs/{}", hash))))
+                }
+                NIPRemote::NewIPFS | NIPRemote::NewIPNS | NIPRemote::NewFile(_) => {
+                    Box::new(future::ok(None))
+                }
+            },
+            None => Box::new(future::ok(None)),
+        }
+    }
+
+    /// Work out what changed between `self` and `other`, `other` being the newer index. An object
+    /// stored under a different link in `other` is reported as both removed and added.
+    pub fn diff(&self, other: &NIPIndex) -> IndexDiff {
+        let mut diff = IndexDiff::default();
+
+        for (ref_name, old) in &self.refs {
+            match other.refs.get(ref_name) {
+                Some(new) if new != old => {
+                    diff.refs_moved
+                        .insert(ref_name.clone(), (old.clone(), new.clone()));
+                }
+                Some(_) => {}
+                None => {
+                    diff.refs_removed.insert(ref_name.clone(), old.clone());
+                }
+            }
+        }
+        for (ref_name, new) in &other.refs {
+            if !self.refs.contains_key(ref_name) {
+                diff.refs_added.insert(ref_name.clone(), new.clone());
+            }
+        }
+
+        for (git_hash, old) in &self.objects {
+            if other.objects.get(git_hash) != Some(old) {
+                diff.objects_removed.insert(git_hash.clone(), old.clone());
+            }
+        }
+        for (git_hash, new) in &other.objects {
+            if self.objects.get(git_hash) != Some(new) {
+                diff.objects_added.insert(git_hash.clone(), new.clone());
+            }
+        }
+
+        diff
+    }
+
+    /// Combine `self` with `other`, a divergent version of the same repository, e.g. the index a
+    /// concurrent writer published. Objects are united and refs known to only one side are kept.
+    /// Where both sides have a ref, the tip that descends from the other one wins; tips that
+    /// diverged are reported as conflicts and keep `self`'s value. Commit ancestry is taken from
+    /// `repo` where it's present locally and read from the nip objects in `ipfs` otherwise, so
+    /// none of the history needs to be fetched first.
+    ///
+    /// The merged index is based on `self` and has `other`'s link as its `merged_idx_hash`, so
+    /// `other` must have been downloaded or published; `NIPIndexError::UnpublishedIndex` is
+    /// returned otherwise.
+    /// Publishing it over `self`'s remote with `ipfs_add()` makes `self`'s link its
+    /// `prev_idx_hash`; indices published on top of it no longer record `merged_idx_hash`.
+    pub fn merge<S: ContentStore>(
+        &self,
+        other: &NIPIndex,
+        repo: &Repository,
+        ipfs: &mut S,
+    ) -> Result<IndexMerge, Error> {
+        current_thread::block_on_all(self.merge_async(other, repo, ipfs))
+    }
+
+    /// A non-blocking version of `merge()`
+    pub fn merge_async<S: ContentStore>(
+        &self,
+        other: &NIPIndex,
+        repo: &Repository,
+        ipfs: &mut S,
+    ) -> StoreFuture<IndexMerge> {
+        let other_link = match other.base_idx_hash.clone() {
+            Some(link) => link,
+            None => return Box::new(future::err(NIPIndexError::UnpublishedIndex.into())),
+        };
+        let prepared = reopen_repo(repo).and_then(|repo| Ok((repo, self.object_store(ipfs)?)));
+        let (repo, mut ipfs) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let mut merged = self.clone();
+        merged.merged_idx_hash = Some(other_link);
+        for (git_hash, link) in &other.objects {
+            if !merged.objects.contains_key(git_hash) {
+                merged.set_object(git_hash.clone(), link.clone());
+            }
+        }
+
+        // Refs both sides moved; (name, ours, theirs) triples
+        let mut contested = Vec::new();
+        for (ref_name, theirs) in &other.refs {
+            match self.refs.get(ref_name) {
+                Some(ours) if ours == theirs => {}
+                Some(ours) => contested.push((ref_name.clone(), ours.clone(), theirs.clone())),
+                None => {
+                    debug!("Merging {} from the other index", ref_name);
+                    merged.set_ref(ref_name.clone(), theirs.clone());
+                }
+            }
+        }
+
+        let walk = AncestryWalk {
+            idx: merged,
+            repo,
+            parents: HashMap::new(),
+        };
+        let opts = TransferOptions::default();
+
+        Box::new(
+            future::loop_fn(
+                (walk, BTreeMap::new(), contested.into_iter()),
+                move |(walk, mut conflicts, mut contested)| -> StoreFuture<_> {
+                    let (ref_name, ours, theirs) = match contested.next() {
+                        Some(contested_ref) => contested_ref,
+                        None => return Box::new(future::ok(Loop::Break((walk, conflicts)))),
+                    };
+
+                    let mut behind_ipfs = ipfs.clone();
+                    let behind_opts = opts.clone();
+                    let (behind_ours, behind_theirs) = (ours.clone(), theirs.clone());
+                    let checked = Self::descends_from_async(
+                        walk,
+                        theirs.clone(),
+                        ours.clone(),
+                        &mut ipfs,
+                        &opts,
+                    )
+                    .and_then(move |(walk, theirs_ahead)| -> StoreFuture<_> {
+                        if theirs_ahead {
+                            return Box::new(future::ok((walk, true, false)));
+                        }
+                        Box::new(
+                            Self::descends_from_async(
+                                walk,
+                                behind_ours,
+                                behind_theirs,
+                                &mut behind_ipfs,
+                                &behind_opts,
+                            )
+                            .map(|(walk, ours_ahead)| (walk, false, ours_ahead)),
+                        )
+                    });
+
+                    Box::new(checked.map(move |(mut walk, theirs_ahead, ours_ahead)| {
+                        if theirs_ahead {
+                            debug!("Fast-forwarding {} to {}", ref_name, theirs);
+                            walk.idx.set_ref(ref_name, theirs);
+                        } else if !ours_ahead {
+                            warn!(
+                                "{} diverged: {} here, {} in the other index",
+                                ref_name, ours, theirs
+                            );
+                            conflicts.insert(ref_name, (ours, theirs));
+                        }
+                        Loop::Continue((walk, conflicts, contested))
+                    }))
+                },
+            )
+            .map(|(walk, conflicts)| IndexMerge {
+                index: walk.idx,
+                conflicts,
+            }),
+        )
+    }
+
+    /// Returns true if `ancestor` is reachable from `tip` through commit parents and tag targets.
+    /// The walk goes one generation at a time, downloading the nip objects of each with up to
+    /// `opts.max_in_flight` requests in flight, and stops at commits present in `walk.repo`,
+    /// whose whole history is known locally.
+    fn descends_from_async<S: ContentStore>(
+        walk: AncestryWalk,
+        tip: String,
+        ancestor: String,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<(AncestryWalk, bool)> {
+        let mut ipfs = ipfs.clone();
+        let opts = opts.clone();
+
+        Box::new(future::loop_fn(
+            (walk, vec![tip], HashSet::new()),
+            move |(mut walk, frontier, mut visited)| -> StoreFuture<_> {
+                if frontier.is_empty() {
+                    return Box::new(future::ok(Loop::Break((walk, false))));
+                }
+
+                let mut next_frontier = Vec::new();
+                let mut to_download = Vec::new();
+                for git_hash in frontier {
+                    if git_hash == ancestor {
+                        return Box::new(future::ok(Loop::Break((walk, true))));
+                    }
+                    if !visited.insert(git_hash.clone()) {
+                        continue;
+                    }
+                    if let Some(parents) = walk.parents.get(&git_hash) {
+                        next_frontier.extend(parents.iter().cloned());
+                        continue;
+                    }
+
+                    match walk.local_descent(&git_hash, &ancestor) {
+                        Ok(Some(true)) => return Box::new(future::ok(Loop::Break((walk, true)))),
+                        Ok(Some(false)) => continue,
+                        Ok(None) => {}
+                        Err(e) => return Box::new(future::err(e)),
+                    }
+
+                    match walk.idx.objects.get(&git_hash) {
+                        Some(link) if link != SUBMODULE_TIP_MARKER => {
+                            match Oid::from_str(&git_hash) {
+                                Ok(oid) => to_download.push((oid, link.clone())),
+                                Err(e) => return Box::new(future::err(e.into())),
+                            }
+                        }
+                        _ => debug!("{} not in the index, history ends there", git_hash),
+                    }
+                }
+
+                Box::new(
+                    Self::download_nip_objects(to_download, &mut ipfs, &opts).map(
+                        move |downloaded| {
+                            for (oid, _, nip_obj, _) in downloaded {
+                                let parents = match nip_obj.metadata {
+                                    NIPObjectMetadata::Commit {
+                                        parent_git_hashes, ..
+                                    } => parent_git_hashes.into_iter().collect(),
+                                    NIPObjectMetadata::Tag { target_git_hash } => {
+                                        vec![target_git_hash]
+                                    }
+                                    _ => Vec::new(),
+                                };
+                                next_frontier.extend(parents.iter().cloned());
+                                walk.parents.insert(oid.to_string(), parents);
+                            }
+
+                            Loop::Continue((walk, next_frontier, visited))
+                        },
+                    ),
+                )
+            },
+        ))
+    }
+
+    /// Fail with `ConcurrentWrite` if `prev_remote` is an IPNS name that no longer points at the
+    /// index this one is based on, `current` being what it resolves to now. Indices not
+    /// downloaded from a remote have nothing to compare against and always pass.
+    fn ensure_no_concurrent_write(
+        &self,
+        prev_remote: Option<&NIPRemote>,
+        current: Option<&String>,
+    ) -> Result<(), Error> {
+        if !prev_remote.map(NIPRemote::is_ipns).unwrap_or(false) {
+            return Ok(());
+        }
+
+        match (self.base_idx_hash.as_ref(), current) {
+            (Some(base), Some(current)) if bare_hash(base) != bare_hash(current) => {
+                error!(
+                    "{} moved from {} to {} in the meantime. Please fetch first.",
+                    prev_remote.map(NIPRemote::to_string).unwrap_or_default(),
+                    base,
+                    current
+                );
+                Err(NIPIndexError::ConcurrentWrite {
+                    expected: base.clone(),
+                    found: current.clone(),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Upload an encoded index and publish it the way `prev_remote` dictates; resolves to the
+    /// index's `/ipfs/` link and the remote to use from now on
+    fn upload_self_buf<S: ContentStore>(
+        self_buf: Vec<u8>,
+        ipfs: &mut S,
+        prev_remote: Option<&NIPRemote>,
+    ) -> StoreFuture<(String, NIPRemote)> {
+        // Publish on IPNS if applicable; prev_remote == None means no IPNS
+        let publish = prev_remote.map(|remote| remote.is_ipns()).unwrap_or(false);
+        // File remotes keep pointing at the same store directory
+        let file_path = prev_remote
+            .and_then(|remote| remote.file_path())
+            .map(Path::to_owned);
+
+        let add_req = ipfs.add(self_buf);
+        let mut ipfs = ipfs.clone();
+        Box::new(add_req.and_then(move |new_hash| {
+            let link = ipfs_link(&new_hash);
+
+            let published: StoreFuture<String> = if publish {
+                debug!("Previous remote was IPNS, republishing");
+                ipfs.name_publish(&new_hash)
+            } else {
+                Box::new(future::ok(new_hash))
+            };
+
+            published.and_then(move |new_hash| {
+                let remote = match file_path {
+                    Some(path) => NIPRemote::ExistingFile(path, bare_hash(&new_hash).to_owned()),
+                    None => new_hash.parse()?,
+                };
+                Ok((link, remote))
+            })
+        }))
+    }
+}
+
+impl ChangeSet {
+    /// Create an empty change set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if no objects are waiting for upload
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Upload the pending objects from `repo`, keeping at most `opts.max_in_flight` objects in
+    /// transfer at once. Every finished upload is moved from `pending` to `objects` right away,
+    /// so a failed upload can be resumed with another call.
+    ///
+    /// Blocking only; use `upload_async()` inside a tokio runtime.
+    pub fn upload<S: ContentStore>(
+        &mut self,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<(), Error> {
+        let mut ipfs = IndexStore::new(self.file_store.as_ref(), ipfs)?;
+        let mut journal = self.start_journal(opts)?;
+        let uploads =
+            NIPIndex::upload_git_objects(self.pending_oids()?, reopen_repo(repo)?, &mut ipfs, opts);
+
+        current_thread::block_on_all(uploads.for_each(|(git_hash, nip_object_hash)| {
+            if let Some(journal) = journal.as_mut() {
+                journal.record(&git_hash, &nip_object_hash)?;
+            }
+            self.record_upload(git_hash, nip_object_hash);
+            Ok(())
+        }))
+    }
+
+    /// A non-blocking version of `upload()`; resolves to the change set with everything
+    /// uploaded.
+    pub fn upload_async<S: ContentStore>(
+        self,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<Self> {
+        let prepared = reopen_repo(repo)
+            .and_then(|repo| Ok((repo, IndexStore::new(self.file_store.as_ref(), ipfs)?)));
+        match prepared {
+            Ok((repo, mut ipfs)) => self.upload_owned(repo, &mut ipfs, opts),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Write the change set to `path`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut self_buf = gen_nip_header(None)?;
+        self_buf.extend_from_slice(&serde_cbor::to_vec(self)?);
+
+        write_atomic(path.as_ref(), &self_buf)
+    }
+
+    /// Read a change set written by `save()`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let bytes = fs::read(path)?;
+
+        let protocol_version = parse_nip_header(&bytes)?;
+        if protocol_version != NIP_PROTOCOL_VERSION {
+            return Err(NIPError::InvalidVersion(protocol_version).into());
+        }
+
+        Ok(serde_cbor::from_slice(&bytes[NIP_HEADER_LEN..])?)
+    }
+
+    fn upload_owned<S: ContentStore>(
+        self,
+        repo: Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> StoreFuture<Self> {
+        let prepared = self
+            .pending_oids()
+            .and_then(|oids| Ok((oids, self.start_journal(opts)?)));
+        let (oids, journal) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        Box::new(
+            NIPIndex::upload_git_objects(oids, repo, ipfs, opts)
+                .fold(
+                    (self, journal),
+                    |(mut changes, mut journal), (git_hash, nip_object_hash)| {
+                        if let Some(journal) = journal.as_mut() {
+                            journal.record(&git_hash, &nip_object_hash)?;
+                        }
+                        changes.record_upload(git_hash, nip_object_hash);
+                        Ok::<_, Error>((changes, journal))
+                    },
+                )
+                .map(|(changes, _journal)| changes),
+        )
+    }
+
+    /// Start a `PushJournal` for this change set in `opts.journal` if one is configured
+    fn start_journal(&self, opts: &TransferOptions) -> Result<Option<PushJournal>, Error> {
+        match opts.journal {
+            Some(ref dir) => Ok(Some(PushJournal::start(dir, self)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn pending_oids(&self) -> Result<Vec<Oid>, Error> {
+        self.pending
+            .iter()
+            .map(|git_hash| Ok(Oid::from_str(git_hash)?))
+            .collect()
+    }
+
+    pub(crate) fn record_upload(&mut self, git_hash: String, nip_object_hash: String) {
+        self.pending.remove(&git_hash);
+        self.objects.insert(git_hash, nip_object_hash);
+    }
+}
+
+/// The result of `NIPIndex::merge()`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexMerge {
+    /// The merged index
+    pub index: NIPIndex,
+    /// Refs whose tips diverged; a {name -> (our sha1, their sha1)} map. The merged index keeps
+    /// our value for them.
+    pub conflicts: BTreeMap<String, (String, String)>,
+}
+
+/// An iterator over the history of an index, created by `NIPIndex::history()`. Every step
+/// downloads one index; iteration ends after the first index ever published or the first error.
+///
+/// Every step blocks, so inside a tokio runtime use the stream from `NIPIndex::history_async()`
+/// instead.
+pub struct IndexHistory {
+    entries: Option<Box<dyn Stream<Item = (String, NIPIndex), Error = Error> + Send>>,
+}
+
+impl Iterator for IndexHistory {
+    type Item = Result<(String, NIPIndex), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entries = self.entries.take()?;
+        match current_thread::block_on_all(entries.into_future()) {
+            Ok((entry, rest)) => {
+                self.entries = Some(rest);
+                entry.map(Ok)
+            }
+            Err((e, _)) => Some(Err(e)),
+        }
+    }
+}
+
+/// The changes between two indices, as reported by `NIPIndex::diff()`. The `Display`
+/// implementation lists every ref change followed by object totals; the alternate form (`{:#}`)
+/// lists every object change as well.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IndexDiff {
+    /// Refs only the newer index has; a {name -> sha1} map
+    pub refs_added: BTreeMap<String, String>,
+    /// Refs only the older index has; a {name -> sha1} map
+    pub refs_removed: BTreeMap<String, String>,
+    /// Refs pointing somewhere else in the newer index; a {name -> (old sha1, new sha1)} map
+    pub refs_moved: BTreeMap<String, (String, String)>,
+    /// Objects only the newer index has; a {sha1 -> IPFS hash} map
+    pub objects_added: BTreeMap<String, String>,
+    /// Objects only the older index has; a {sha1 -> IPFS hash} map
+    pub objects_removed: BTreeMap<String, String>,
+}
+
+impl IndexDiff {
+    /// Returns true if the two indices hold the same refs and objects
+    pub fn is_empty(&self) -> bool {
+        self.refs_added.is_empty()
+            && self.refs_removed.is_empty()
+            && self.refs_moved.is_empty()
+            && self.objects_added.is_empty()
+            && self.objects_removed.is_empty()
+    }
+}
+
+impl fmt::Display for IndexDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (ref_name, new) in &self.refs_added {
+            writeln!(f, "+ {} {}", ref_name, new)?;
+        }
+        for (ref_name, old) in &self.refs_removed {
+            writeln!(f, "- {} {}", ref_name, old)?;
+        }
+        for (ref_name, (old, new)) in &self.refs_moved {
+            writeln!(f, "~ {} {}..{}", ref_name, old, new)?;
+        }
+
+        if f.alternate() {
+            for (git_hash, link) in &self.objects_added {
+                writeln!(f, "+ {} {}", git_hash, link)?;
+            }
+            for (git_hash, link) in &self.objects_removed {
+                writeln!(f, "- {} {}", git_hash, link)?;
+            }
+        }
+
+        writeln!(
+            f,
+            "{} object(s) added, {} removed",
+            self.objects_added.len(),
+            self.objects_removed.len()
+        )
+    }
+}
+
+/// A ref change between two consecutive indices, as reported by `NIPIndex::reflog()`
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RefLogEntry {
+    /// The ref's sha1 before the change; `None` if the change created the ref
+    pub old: Option<String>,
+    /// The ref's sha1 after the change; `None` if the change deleted the ref
+    pub new: Option<String>,
+    /// The `/ipfs/` link of the index that made the change
+    pub idx_link: String,
+}
+
+/// Add an entry to `reflog` for every ref that differs between `new_refs` and `old_refs`, the
+/// latter being the refs of the index preceding the one at `idx_link`
+fn record_ref_changes(
+    reflog: &mut BTreeMap<String, Vec<RefLogEntry>>,
+    idx_link: String,
+    new_refs: &BTreeMap<String, String>,
+    old_refs: &BTreeMap<String, String>,
+) {
+    let ref_names: BTreeSet<&String> = new_refs.keys().chain(old_refs.keys()).collect();
+    for ref_name in ref_names {
+        let (old, new) = (old_refs.get(ref_name), new_refs.get(ref_name));
+        if old != new {
+            reflog
+                .entry(ref_name.clone())
+                .or_default()
+                .push(RefLogEntry {
+                    old: old.cloned(),
+                    new: new.cloned(),
+                    idx_link: idx_link.clone(),
+                });
+        }
+    }
+}
+
+/// Tell refs and objects apart in fingerprints
+const REF_TAG: u8 = 0;
+const OBJECT_TAG: u8 = 1;
+
+/// Returns a fingerprint of `refs` and `objects` that can be updated one entry at a time, being
+/// the sum of `entry_fingerprint()`s of all their entries
+fn fingerprint(refs: &BTreeMap<String, String>, objects: &BTreeMap<String, String>) -> u64 {
+    let refs = refs
+        .iter()
+        .map(|(ref_name, git_hash)| entry_fingerprint(REF_TAG, ref_name, git_hash));
+    let objects = objects
+        .iter()
+        .map(|(git_hash, link)| entry_fingerprint(OBJECT_TAG, git_hash, link));
+
+    refs.chain(objects).fold(0, u64::wrapping_add)
+}
+
+fn entry_fingerprint(tag: u8, key: &str, value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (tag, key, value).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Split the `touched` entries of `current` into the ones set to something other than their
+/// base value and the ones removed since
+fn touched_changes(
+    touched: &BTreeMap<String, Option<String>>,
+    current: &BTreeMap<String, String>,
+) -> (BTreeMap<String, String>, BTreeSet<String>) {
+    let mut set = BTreeMap::new();
+    let mut removed = BTreeSet::new();
+    for (key, old) in touched {
+        match (old, current.get(key)) {
+            (old, Some(new)) if old.as_ref() != Some(new) => {
+                set.insert(key.clone(), new.clone());
+            }
+            (Some(_), None) => {
+                removed.insert(key.clone());
+            }
+            _ => {}
+        }
+    }
+
+    (set, removed)
+}
+
+/// Turn a bare or `/ipfs/`-prefixed hash into an `/ipfs/` link
+fn ipfs_link(hash: &str) -> String {
+    format!("/ipfs/{}", bare_hash(hash))
+}
+
+/// A single ref update of a batch push
+#[derive(Clone)]
+struct PushSpec {
+    ref_src: String,
+    ref_dst: String,
+    force: bool,
+    /// The value `ref_dst` must have for the update to go through, `Some(None)` meaning it must
+    /// not exist
+    lease: Option<Option<Oid>>,
+}
+
+impl PushSpec {
+    fn new(ref_src: &str, ref_dst: &str, force: bool) -> Self {
+        Self {
+            ref_src: ref_src.to_owned(),
+            ref_dst: ref_dst.to_owned(),
+            force,
+            lease: None,
+        }
+    }
+
+    /// A forced update of `ref_dst` leased on it pointing at `expected`
+    fn with_lease(ref_src: &str, ref_dst: &str, expected: Option<&str>) -> Result<Self, Error> {
+        let lease = match expected {
+            Some(git_hash) => Some(Oid::from_str(git_hash)?),
+            None => None,
+        };
+
+        Ok(Self {
+            lease: Some(lease),
+            ..Self::new(ref_src, ref_dst, true)
+        })
+    }
+
+    /// Turn `(ref_src, ref_dst, force)` triples into specs
+    fn batch(refspecs: &[(&str, &str, bool)]) -> Vec<Self> {
+        refspecs
+            .iter()
+            .map(|&(ref_src, ref_dst, force)| Self::new(ref_src, ref_dst, force))
+            .collect()
+    }
+}
+
+/// The outcome of every ref of a push, in push order
+type PushReport = Vec<(String, PushOutcome)>;
+
+/// What `precheck_spec()` could tell about a spec: either its final outcome and the object to
+/// point the ref at, or the outcome it gets unless the remote history rejects it
+enum SpecCheck {
+    Done((PushOutcome, Option<Oid>)),
+    Pending(PushOutcome, Oid),
+}
+
+/// The state of a push being staged, carried between its specs
+#[derive(Default)]
+struct StagedPush {
+    report: PushReport,
+    /// `(ref_dst, src_oid)` pairs of the accepted updates
+    updates: Vec<(String, Oid)>,
+    deletions: Vec<String>,
+    leases: BTreeMap<String, Option<String>>,
+}
+
+/// The store an index keeps its objects in: the one it's used with, or the `FsStore` of the
+/// file remote it belongs to, so that a file remote always holds everything it refers to
+#[derive(Clone)]
+enum IndexStore<S> {
+    Given(S),
+    File(FsStore),
+}
+
+impl<S: ContentStore> IndexStore<S> {
+    fn new(file_store: Option<&PathBuf>, ipfs: &S) -> Result<Self, Error> {
+        match file_store {
+            Some(path) => Ok(IndexStore::File(FsStore::open(path)?)),
+            None => Ok(IndexStore::Given(ipfs.clone())),
+        }
+    }
+}
+
+impl<S: ContentStore> ContentStore for IndexStore<S> {
+    fn add(&mut self, data: Vec<u8>) -> StoreFuture<String> {
+        match self {
+            IndexStore::Given(store) => store.add(data),
+            IndexStore::File(store) => store.add(data),
+        }
+    }
+
+    fn cat(&mut self, link: &str) -> StoreFuture<Vec<u8>> {
+        match self {
+            IndexStore::Given(store) => store.cat(link),
+            IndexStore::File(store) => store.cat(link),
+        }
+    }
+
+    fn name_publish(&mut self, link: &str) -> StoreFuture<String> {
+        match self {
+            IndexStore::Given(store) => store.name_publish(link),
+            IndexStore::File(store) => store.name_publish(link),
+        }
+    }
+
+    fn name_resolve(&mut self, name: &str) -> StoreFuture<String> {
+        match self {
+            IndexStore::Given(store) => store.name_resolve(name),
+            IndexStore::File(store) => store.name_resolve(name),
+        }
+    }
+}
+
+/// Commit ancestry learned during a merge, shared by all of its descent checks so that no nip
+/// object is downloaded twice
+struct AncestryWalk {
+    /// The merged index, whose objects the walk follows
+    idx: NIPIndex,
+    repo: Repository,
+    /// Downloaded ancestry; a {sha1 -> parent sha1s} map, tags having their target as the only
+    /// parent
+    parents: HashMap<String, Vec<String>>,
+}
+
+impl AncestryWalk {
+    /// Answer whether `ancestor` is reachable from `git_hash` locally, which is possible whenever
+    /// `git_hash` is in `self.repo`: local history is complete, so `ancestor` is either part of
+    /// it or not reachable at all. Returns `None` for objects missing from the repo.
+    fn local_descent(&self, git_hash: &str, ancestor: &str) -> Result<Option<bool>, Error> {
+        let oid = match Oid::from_str(git_hash) {
+            Ok(oid) if self.repo.find_object(oid, None).is_ok() => oid,
+            _ => return Ok(None),
+        };
+
+        match Oid::from_str(ancestor) {
+            Ok(ancestor_oid) => Ok(Some(NIPIndex::is_fast_forward(
+                ancestor_oid,
+                oid,
+                &self.repo,
+            )?)),
+            Err(_) => Ok(Some(false)),
+        }
+    }
+}
+
+/// State carried between the frontiers of an asynchronous fetch enumeration
+struct FetchEnumeration {
+    idx: NIPIndex,
+    repo: Repository,
+    frontier: Vec<Oid>,
+    fetch_todo: HashMap<Oid, NIPObject>,
+    queued: HashSet<Oid>,
+    counting: PhaseTracker,
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{Signature, Time};
+    use ipfs_api::IpfsClient;
+    use tempfile::TempDir;
+    use tokio::runtime::Runtime;
+
+    use std::{
+        collections::hash_map::DefaultHasher,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+
+    use crate::{
+        store::{CachePolicy, InMemoryStore, ObjectCache},
+        test_utils::{repo_with_history, MockIpfsServer},
+        transfer::{CancellationToken, ProgressEvent, TransferError},
+    };
+
+    fn empty_repo() -> (TempDir, Repository) {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        (dir, repo)
+    }
+
+    /// Returns `repo_with_history(n_commits)` along with a new index its master was pushed to
+    /// and the store holding the pushed objects
+    fn pushed_index(n_commits: usize) -> (TempDir, Repository, NIPIndex, InMemoryStore) {
+        let (dir, mut repo) = repo_with_history(n_commits);
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut repo,
+            &mut ipfs,
+        )
+        .unwrap();
+
+        (dir, repo, idx, ipfs)
+    }
+
+    fn roundtrip(new_remote: NIPRemote) {
+        let (_src_dir, mut src_repo) = repo_with_history(3);
+        let (_dst_dir, mut dst_repo) = empty_repo();
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&new_remote, &mut ipfs).unwrap();
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut ipfs,
+        )
+        .unwrap();
+
+        // 3 commits, 3 trees, 3 blobs
+        assert_eq!(idx.objects.len(), 9);
+
+        let remote = idx.ipfs_add(&mut ipfs, Some(&new_remote)).unwrap();
+        assert_eq!(remote.is_ipns(), new_remote.is_ipns());
+
+        let fetched_idx = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        assert_eq!(fetched_idx, idx);
+
+        let tip = fetched_idx.refs["refs/heads/master"].clone();
+        fetched_idx
+            .fetch_to_ref_from_str(&tip, "refs/heads/master", &mut dst_repo, &mut ipfs)
+            .unwrap();
+
+        let src_tip = src_repo.refname_to_id("refs/heads/master").unwrap();
+        assert_eq!(
+            dst_repo.refname_to_id("refs/heads/master").unwrap(),
+            src_tip
+        );
+        for git_hash in fetched_idx.objects.keys() {
+            assert!(dst_repo.odb().unwrap().exists(git_hash.parse().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_push_fetch_roundtrip_ipfs() {
+        roundtrip(NIPRemote::NewIPFS);
+    }
+
+    #[test]
+    fn test_push_fetch_roundtrip_ipns() {
+        roundtrip(NIPRemote::NewIPNS);
+    }
+
+    #[test]
+    fn test_push_fetch_roundtrip_file() {
+        let (_src_dir, mut src_repo) = repo_with_history(2);
+        let (_dst_dir, mut dst_repo) = empty_repo();
+        let store_dir = TempDir::new().unwrap();
+        let mut store = FsStore::open(store_dir.path()).unwrap();
+
+        let new_remote: NIPRemote = format!("file://{}", store_dir.path().display())
+            .parse()
+            .unwrap();
+        let mut idx = NIPIndex::from_nip_remote(&new_remote, &mut store).unwrap();
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut store,
+        )
+        .unwrap();
+
+        let remote = idx.ipfs_add(&mut store, Some(&new_remote)).unwrap();
+        assert_eq!(remote.file_path(), Some(store_dir.path()));
+
+        // The file remote is self-contained, the passed store doesn't matter for the index
+        let fetched_idx = NIPIndex::from_nip_remote(
+            &remote.to_string().parse().unwrap(),
+            &mut InMemoryStore::new(),
+        )
+        .unwrap();
+        assert_eq!(fetched_idx, idx);
+
+        // Neither does it for writing one
+        let mut other_store = InMemoryStore::new();
+        let mut updated = fetched_idx.clone();
+        updated.refs.insert(
+            "refs/heads/copy".to_owned(),
+            updated.refs["refs/heads/master"].clone(),
+        );
+        let updated_remote = updated.ipfs_add(&mut other_store, Some(&remote)).unwrap();
+        assert_eq!(other_store.len(), 0);
+        assert_eq!(updated_remote.file_path(), Some(store_dir.path()));
+        assert_eq!(
+            NIPIndex::from_nip_remote(&updated_remote, &mut InMemoryStore::new()).unwrap(),
+            updated
+        );
+
+        let mut store = FsStore::open(store_dir.path()).unwrap();
+        let tip = fetched_idx.refs["refs/heads/master"].clone();
+        fetched_idx
+            .fetch_to_ref_from_str(&tip, "refs/heads/master", &mut dst_repo, &mut store)
+            .unwrap();
+        assert_eq!(
+            dst_repo.refname_to_id("refs/heads/master").unwrap(),
+            src_repo.refname_to_id("refs/heads/master").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_file_remote_holds_objects_pushed_with_other_store() {
+        let (_src_dir, mut src_repo) = repo_with_history(2);
+        let (_dst_dir, mut dst_repo) = empty_repo();
+        let store_dir = TempDir::new().unwrap();
+        let mut push_store = InMemoryStore::new();
+
+        let new_remote: NIPRemote = format!("file://{}", store_dir.path().display())
+            .parse()
+            .unwrap();
+        let mut idx = NIPIndex::from_nip_remote(&new_remote, &mut push_store).unwrap();
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut push_store,
+        )
+        .unwrap();
+        let remote = idx.ipfs_add(&mut push_store, Some(&new_remote)).unwrap();
+        assert_eq!(push_store.len(), 0);
+
+        // Another machine only has the directory
+        let mut fetch_store = InMemoryStore::new();
+        let fetched_idx =
+            NIPIndex::from_nip_remote(&remote.to_string().parse().unwrap(), &mut fetch_store)
+                .unwrap();
+        let tip = fetched_idx.refs["refs/heads/master"].clone();
+        fetched_idx
+            .fetch_to_ref_from_str(&tip, "refs/heads/master", &mut dst_repo, &mut fetch_store)
+            .unwrap();
+        assert_eq!(
+            dst_repo.refname_to_id("refs/heads/master").unwrap(),
+            src_repo.refname_to_id("refs/heads/master").unwrap()
+        );
+        assert_eq!(fetch_store.len(), 0);
+    }
+
+    #[test]
+    fn test_push_bounds_concurrent_uploads() {
+        let (_src_dir, src_repo) = repo_with_history(4);
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+        server.set_latency(Some(Duration::from_millis(20)));
+
+        let opts = TransferOptions {
+            max_in_flight: 3,
+            ..Default::default()
+        };
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        assert_eq!(
+            idx.push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap(),
+            PushOutcome::Created
+        );
+
+        assert!(server.max_concurrent_requests() > 1);
+        assert!(server.max_concurrent_requests() <= opts.max_in_flight);
+
+        // Completion order doesn't matter for the resulting index
+        let mut serial_idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        assert_eq!(
+            serial_idx
+                .push_ref_from_str_with_opts(
+                    "refs/heads/master",
+                    "refs/heads/master",
+                    false,
+                    &src_repo,
+                    &mut InMemoryStore::new(),
+                    &TransferOptions {
+                        max_in_flight: 1,
+                        ..Default::default()
+                    },
+                )
+                .unwrap(),
+            PushOutcome::Created
+        );
+        assert_eq!(serial_idx, idx);
+    }
+
+    #[test]
+    fn test_fetch_bounds_concurrent_downloads() {
+        let (_src_dir, src_repo) = repo_with_history(4);
+        let (_dst_dir, mut dst_repo) = empty_repo();
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        assert_eq!(
+            idx.push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &TransferOptions {
+                    max_in_flight: 1,
+                    ..Default::default()
+                },
+            )
+            .unwrap(),
+            PushOutcome::Created
+        );
+        assert_eq!(server.max_concurrent_requests(), 1);
+
+        server.set_latency(Some(Duration::from_millis(20)));
+        let opts = TransferOptions {
+            max_in_flight: 3,
+            ..Default::default()
+        };
+        let tip = idx.refs["refs/heads/master"].clone();
+        idx.fetch_to_ref_from_str_with_opts(
+            &tip,
+            "refs/heads/master",
+            &mut dst_repo,
+            &mut ipfs,
+            &opts,
+        )
+        .unwrap();
+
+        assert!(server.max_concurrent_requests() > 1);
+        assert!(server.max_concurrent_requests() <= opts.max_in_flight);
+        for git_hash in idx.objects.keys() {
+            assert!(dst_repo.odb().unwrap().exists(git_hash.parse().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_fetch_downloads_each_object_once() {
+        let (_src_dir, mut src_repo) = repo_with_history(3);
+        let (_dst_dir, mut dst_repo) = empty_repo();
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut ipfs,
+        )
+        .unwrap();
+
+        let tip = idx.refs["refs/heads/master"].clone();
+        idx.fetch_to_ref_from_str(&tip, "refs/heads/master", &mut dst_repo, &mut ipfs)
+            .unwrap();
+
+        // One request for every NIPObject and one for its raw data
+        assert_eq!(server.request_count("/cat"), 2 * idx.objects.len());
+    }
+
+    #[test]
+    fn test_push_objects_by_oid() {
+        let (_src_dir, src_repo) = repo_with_history(3);
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let tip = src_repo
+            .revparse_single("refs/heads/master")
+            .unwrap()
+            .peel(ObjectType::Commit)
+            .unwrap();
+        let (mut oids, mut submodules) = (HashSet::new(), HashSet::new());
+        idx.enumerate_for_push(&tip, &mut oids, &mut submodules, &src_repo)
+            .unwrap();
+        assert!(submodules.is_empty());
+
+        idx.push_git_objects(&oids, &src_repo, &mut ipfs).unwrap();
+        assert_eq!(idx.objects.len(), oids.len());
+        for oid in &oids {
+            assert!(idx.objects.contains_key(&oid.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_fetch_objects_by_oid() {
+        let (_src_dir, _src_repo, idx, mut ipfs) = pushed_index(3);
+        let (_dst_dir, mut dst_repo) = empty_repo();
+
+        let tip = Oid::from_str(&idx.refs["refs/heads/master"]).unwrap();
+        let mut oids = HashSet::new();
+        idx.enumerate_for_fetch(tip, &mut oids, &dst_repo, &mut ipfs)
+            .unwrap();
+        assert_eq!(oids.len(), idx.objects.len());
+
+        idx.fetch_nip_objects(&oids, &mut dst_repo, &mut ipfs)
+            .unwrap();
+        for oid in &oids {
+            assert!(dst_repo.odb().unwrap().read_header(*oid).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_fetch_reuses_object_cache() {
+        let (_src_dir, mut src_repo) = repo_with_history(3);
+        let (dst_dir, mut dst_repo) = empty_repo();
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut ipfs,
+        )
+        .unwrap();
+
+        let tip = idx.refs["refs/heads/master"].clone();
+        let opts = TransferOptions::default();
+        idx.fetch_to_ref_from_str_with_opts(
+            &tip,
+            "refs/heads/master",
+            &mut dst_repo,
+            &mut ipfs,
+            &opts,
+        )
+        .unwrap();
+        let cats = server.request_count("/cat");
+
+        // Lose every fetched object, but keep the cache
+        for entry in fs::read_dir(dst_dir.path().join(".git/objects")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap().len() == 2 {
+                fs::remove_dir_all(path).unwrap();
+            }
+        }
+        let mut dst_repo = Repository::open(dst_dir.path()).unwrap();
+        assert!(dst_repo.find_commit(tip.parse().unwrap()).is_err());
+
+        idx.fetch_to_ref_from_str_with_opts(
+            &tip,
+            "refs/heads/master",
+            &mut dst_repo,
+            &mut ipfs,
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(server.request_count("/cat"), cats);
+        assert!(dst_repo.find_commit(tip.parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_fetch_without_cache() {
+        let (_src_dir, mut src_repo) = repo_with_history(2);
+        let (dst_dir, mut dst_repo) = empty_repo();
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut ipfs,
+        )
+        .unwrap();
+
+        let tip = idx.refs["refs/heads/master"].clone();
+        let opts = TransferOptions {
+            cache: CachePolicy::Disabled,
+            ..Default::default()
+        };
+        idx.fetch_to_ref_from_str_with_opts(
+            &tip,
+            "refs/heads/master",
+            &mut dst_repo,
+            &mut ipfs,
+            &opts,
+        )
+        .unwrap();
+
+        assert_eq!(server.request_count("/cat"), 2 * idx.objects.len());
+        assert!(!dst_dir.path().join(".git/nip/cache").exists());
+    }
+
+    #[test]
+    fn test_downloads_with_opts_use_cache() {
+        let (_src_dir, mut src_repo) = repo_with_history(1);
+        let (_dst_dir, dst_repo) = empty_repo();
+        let cache_dir = TempDir::new().unwrap();
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut ipfs,
+        )
+        .unwrap();
+        let remote = idx.ipfs_add(&mut ipfs, None).unwrap();
+
+        let cache = ObjectCache::open(cache_dir.path(), 1024 * 1024).unwrap();
+        let opts = TransferOptions {
+            cache: CachePolicy::Custom(cache.clone()),
+            ..Default::default()
+        };
+        let tip = idx.refs["refs/heads/master"].clone();
+        let obj_link = idx.objects[&tip].clone();
+
+        let download_all = |ipfs: &mut IpfsClient| {
+            let downloaded =
+                NIPIndex::from_nip_remote_with_opts(&remote, &dst_repo, ipfs, &opts).unwrap();
+            assert_eq!(downloaded, idx);
+
+            let obj = NIPObject::ipfs_get_with_opts(&obj_link, &dst_repo, ipfs, &opts).unwrap();
+            assert_eq!(
+                obj.write_raw_data_with_opts(&dst_repo, ipfs, &opts)
+                    .unwrap(),
+                tip.parse().unwrap()
+            );
+        };
+
+        download_all(&mut ipfs);
+        let cats = server.request_count("/cat");
+        assert!(cache.contains(&obj_link));
+
+        download_all(&mut ipfs);
+        assert_eq!(server.request_count("/cat"), cats);
+    }
+
+    #[test]
+    fn test_push_aborts_on_upload_failure() {
+        let (_src_dir, mut src_repo) = repo_with_history(2);
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+        server.fail_next("/add", 1);
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        assert!(idx
+            .push_ref_from_str(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &mut src_repo,
+                &mut ipfs,
+            )
+            .is_err());
+        assert!(!idx.refs.contains_key("refs/heads/master"));
+    }
+
+    #[test]
+    fn test_push_only_uploads_new_objects() {
+        let (_src_dir, mut src_repo, mut idx, mut ipfs) = pushed_index(2);
+        let stored_before = ipfs.len();
+
+        // Pushing the same tip again has nothing to upload
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut ipfs,
+        )
+        .unwrap();
+        assert_eq!(ipfs.len(), stored_before);
+    }
+
+    #[test]
+    fn test_async_roundtrip_on_runtime() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let (_dst_dir, dst_repo) = empty_repo();
+        let mut ipfs = InMemoryStore::new();
+        let opts = TransferOptions::default();
+
+        // A multi-threaded runtime only accepts `Send + 'static` futures
+        let mut rt = Runtime::new().unwrap();
+
+        let idx = rt
+            .block_on(NIPIndex::from_nip_remote_async(
+                &NIPRemote::NewIPNS,
+                &mut ipfs,
+            ))
+            .unwrap();
+        let (idx, outcome) = rt
+            .block_on(idx.push_ref_from_str_async(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            ))
+            .unwrap();
+        assert_eq!(outcome, PushOutcome::Created);
+        assert_eq!(idx.objects.len(), 6);
+
+        let (idx, remote) = rt
+            .block_on(idx.ipfs_add_async(&mut ipfs, Some(&NIPRemote::NewIPNS)))
+            .unwrap();
+        assert!(remote.is_ipns());
+
+        let fetched_idx = rt
+            .block_on(NIPIndex::from_nip_remote_async(&remote, &mut ipfs))
+            .unwrap();
+        assert_eq!(fetched_idx, idx);
+
+        let tip = fetched_idx.refs["refs/heads/master"].clone();
+        rt.block_on(fetched_idx.fetch_to_ref_from_str_async(
+            &tip,
+            "refs/heads/master",
+            &dst_repo,
+            &mut ipfs,
+            &opts,
+        ))
+        .unwrap();
+        assert_eq!(
+            dst_repo.refname_to_id("refs/heads/master").unwrap(),
+            src_repo.refname_to_id("refs/heads/master").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_async_push_requires_fetch_first() {
+        let (_first_dir, first_repo) = repo_with_history(2);
+        let (_second_dir, second_repo) = repo_with_history(1);
+        let mut ipfs = InMemoryStore::new();
+        let opts = TransferOptions::default();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        assert_eq!(
+            idx.push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &first_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap(),
+            PushOutcome::Created
+        );
+
+        // The second repo lacks the remote tip
+        let mut rt = Runtime::new().unwrap();
+        let (rejected_idx, outcome) = rt
+            .block_on(idx.clone().push_ref_from_str_async(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &second_repo,
+                &mut ipfs,
+                &opts,
+            ))
+            .unwrap();
+        assert_eq!(outcome, PushOutcome::RejectedFetchFirst);
+        assert_eq!(rejected_idx, idx);
+
+        let (idx, outcome) = rt
+            .block_on(idx.push_ref_from_str_async(
+                "refs/heads/master",
+                "refs/heads/master",
+                true,
+                &second_repo,
+                &mut ipfs,
+                &opts,
+            ))
+            .unwrap();
+        assert_eq!(outcome, PushOutcome::Forced);
+        assert_eq!(
+            idx.refs["refs/heads/master"],
+            second_repo
+                .refname_to_id("refs/heads/master")
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_async_batch_and_leased_push() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let master = src_repo.refname_to_id("refs/heads/master").unwrap();
+        let first = src_repo.find_commit(master).unwrap().parent_id(0).unwrap();
+        src_repo
+            .reference("refs/heads/old", first, false, "test")
+            .unwrap();
+        let mut ipfs = InMemoryStore::new();
+        let opts = TransferOptions::default();
+        let mut rt = Runtime::new().unwrap();
+
+        let idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let (idx, report) = rt
+            .block_on(idx.push_refs_async(
+                &[
+                    ("refs/heads/master", "refs/heads/master", false),
+                    ("refs/heads/nope", "refs/heads/nope", false),
+                ],
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            ))
+            .unwrap();
+        assert_eq!(
+            report,
+            vec![
+                ("refs/heads/master".to_owned(), PushOutcome::Created),
+                (
+                    "refs/heads/nope".to_owned(),
+                    PushOutcome::RejectedMissingSrc
+                ),
+            ]
+        );
+
+        let (idx, outcome) = rt
+            .block_on(idx.push_ref_with_lease_async(
+                "refs/heads/old",
+                "refs/heads/master",
+                Some(&first.to_string()),
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            ))
+            .unwrap();
+        assert_eq!(outcome, PushOutcome::RejectedStale);
+
+        let (idx, outcome) = rt
+            .block_on(idx.push_ref_with_lease_async(
+                "refs/heads/old",
+                "refs/heads/master",
+                Some(&master.to_string()),
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            ))
+            .unwrap();
+        assert_eq!(outcome, PushOutcome::Forced);
+        assert_eq!(idx.refs["refs/heads/master"], first.to_string());
+    }
+
+    /// Returns options that record every progress event in the returned vector
+    fn recording_opts() -> (TransferOptions, Arc<Mutex<Vec<ProgressEvent>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+
+        let opts = TransferOptions {
+            progress: Some(Arc::new(move |event: &ProgressEvent| {
+                sink.lock().unwrap().push(event.clone())
+            })),
+            ..Default::default()
+        };
+
+        (opts, events)
+    }
+
+    /// Returns options that cancel the transfer once the first object of `phase` is done
+    fn cancel_after_first(phase: TransferPhase) -> TransferOptions {
+        let cancellation = CancellationToken::new();
+        let token = cancellation.clone();
+
+        TransferOptions {
+            max_in_flight: 1,
+            progress: Some(Arc::new(move |event: &ProgressEvent| {
+                if let ProgressEvent::Object { phase: p, .. } = event {
+                    if *p == phase {
+                        token.cancel();
+                    }
+                }
+            })),
+            cancellation,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_progress_events() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let (_dst_dir, mut dst_repo) = empty_repo();
+        let mut ipfs = InMemoryStore::new();
+
+        let (opts, events) = recording_opts();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        assert_eq!(
+            idx.push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap(),
+            PushOutcome::Created
+        );
+
+        let push_events: Vec<_> = events.lock().unwrap().drain(..).collect();
+        assert_eq!(
+            push_events.first(),
+            Some(&ProgressEvent::Started {
+                phase: TransferPhase::Counting,
+                total: None
+            })
+        );
+        assert!(push_events.contains(&ProgressEvent::Finished {
+            phase: TransferPhase::Counting,
+            objects: 6,
+            bytes: 0
+        }));
+        assert!(push_events.contains(&ProgressEvent::Started {
+            phase: TransferPhase::Uploading,
+            total: Some(6)
+        }));
+        let uploaded: Vec<usize> = push_events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::Object {
+                    phase: TransferPhase::Uploading,
+                    current,
+                    ..
+                } => Some(*current),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(uploaded, (1..=6).collect::<Vec<_>>());
+        match push_events.last() {
+            Some(ProgressEvent::Finished {
+                phase: TransferPhase::Uploading,
+                objects: 6,
+                bytes,
+            }) => assert!(*bytes > 0),
+            other => panic!("Expected the upload to finish last, got {:?}", other),
+        }
+
+        let tip = idx.refs["refs/heads/master"].clone();
+        idx.fetch_to_ref_from_str_with_opts(
+            &tip,
+            "refs/heads/master",
+            &mut dst_repo,
+            &mut ipfs,
+            &opts,
+        )
+        .unwrap();
+
+        let fetch_events: Vec<_> = events.lock().unwrap().drain(..).collect();
+        assert!(fetch_events.contains(&ProgressEvent::Started {
+            phase: TransferPhase::Downloading,
+            total: Some(6)
+        }));
+        let downloaded_bytes: u64 = fetch_events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::Object {
+                    phase: TransferPhase::Downloading,
+                    bytes,
+                    ..
+                } => Some(*bytes),
+                _ => None,
+            })
+            .sum();
+        assert_eq!(
+            fetch_events.last(),
+            Some(&ProgressEvent::Finished {
+                phase: TransferPhase::Downloading,
+                objects: 6,
+                bytes: downloaded_bytes
+            })
+        );
+    }
+
+    #[test]
+    fn test_push_cancelled_midway() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let idx_before = idx.clone();
+
+        let opts = cancel_after_first(TransferPhase::Uploading);
+        let err = idx
+            .push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<TransferError>().unwrap(),
+            TransferError::Cancelled
+        );
+
+        // Nothing past the first object was uploaded and the index is untouched
+        assert!(ipfs.len() < 12);
+        assert_eq!(idx, idx_before);
+    }
+
+    #[test]
+    fn test_fetch_cancelled_midway() {
+        let (_src_dir, _src_repo, idx, mut ipfs) = pushed_index(2);
+        let (_dst_dir, mut dst_repo) = empty_repo();
+
+        let tip = idx.refs["refs/heads/master"].clone();
+        for phase in &[TransferPhase::Counting, TransferPhase::Downloading] {
+            let err = idx
+                .fetch_to_ref_from_str_with_opts(
+                    &tip,
+                    "refs/heads/master",
+                    &mut dst_repo,
+                    &mut ipfs,
+                    &cancel_after_first(*phase),
+                )
+                .unwrap_err();
+            assert_eq!(
+                err.downcast::<TransferError>().unwrap(),
+                TransferError::Cancelled
+            );
+            assert!(dst_repo.find_reference("refs/heads/master").is_err());
+        }
+    }
+
+    #[test]
+    fn test_staged_push_resumes_after_interruption() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let staged_dir = TempDir::new().unwrap();
+        let staged_path = staged_dir.path().join("staged");
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let mut changes = idx
+            .stage_ref_push(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &TransferOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(changes.pending.len(), 6);
+        changes.save(&staged_path).unwrap();
+
+        assert!(changes
+            .upload(
+                &src_repo,
+                &mut ipfs,
+                &cancel_after_first(TransferPhase::Uploading)
+            )
+            .is_err());
+        assert_eq!(changes.pending.len(), 5);
+        assert_eq!(changes.objects.len(), 1);
+
+        // Half-done change sets are never applied
+        let idx_before = idx.clone();
+        match idx
+            .apply(changes.clone())
+            .unwrap_err()
+            .downcast::<NIPIndexError>()
+        {
+            Ok(NIPIndexError::IncompleteChangeSet(5)) => {}
+            other => panic!("Expected IncompleteChangeSet(5), got {:?}", other),
+        }
+        assert_eq!(idx, idx_before);
+
+        // Resuming from the interrupted change set and from the one saved before the upload
+        // give the same result
+        changes
+            .upload(&src_repo, &mut ipfs, &TransferOptions::default())
+            .unwrap();
+        let mut reloaded = ChangeSet::load(&staged_path).unwrap();
+        reloaded
+            .upload(&src_repo, &mut ipfs, &TransferOptions::default())
+            .unwrap();
+        assert_eq!(reloaded, changes);
+
+        idx.apply(changes).unwrap();
+        assert_eq!(idx.objects.len(), 6);
+        assert_eq!(
+            idx.refs["refs/heads/master"],
+            src_repo
+                .refname_to_id("refs/heads/master")
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_resume_push_skips_uploaded() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let journal_dir = TempDir::new().unwrap();
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let opts = TransferOptions {
+            journal: Some(journal_dir.path().to_owned()),
+            ..cancel_after_first(TransferPhase::Uploading)
+        };
+        assert!(idx
+            .push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .is_err());
+
+        // A fresh process only has the journal to go by
+        let (opts, events) = recording_opts();
+        let opts = TransferOptions {
+            journal: Some(journal_dir.path().to_owned()),
+            ..opts
+        };
+        let mut resumed = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        assert!(resumed.resume_push(&src_repo, &mut ipfs, &opts).unwrap());
+        assert!(events.lock().unwrap().contains(&ProgressEvent::Started {
+            phase: TransferPhase::Uploading,
+            total: Some(5)
+        }));
+
+        let (_expected_dir, _expected_repo, expected, _) = pushed_index(2);
+        assert_eq!(resumed, expected);
+
+        // The journal is gone once the push is through
+        assert!(!resumed.resume_push(&src_repo, &mut ipfs, &opts).unwrap());
+    }
+
+    #[test]
+    fn test_resume_push_reuploads_truncated_entry() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let journal_dir = TempDir::new().unwrap();
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let opts = TransferOptions {
+            journal: Some(journal_dir.path().to_owned()),
+            ..cancel_after_first(TransferPhase::Uploading)
+        };
+        assert!(idx
+            .push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .is_err());
+
+        // Cut the only entry short the way a crash mid-write would
+        let uploaded_path = journal_dir.path().join("uploaded");
+        let uploaded = fs::read_to_string(&uploaded_path).unwrap();
+        assert_eq!(uploaded.lines().count(), 1);
+        fs::write(&uploaded_path, &uploaded[..uploaded.len() - 20]).unwrap();
+
+        let (opts, events) = recording_opts();
+        let opts = TransferOptions {
+            journal: Some(journal_dir.path().to_owned()),
+            ..opts
+        };
+        let mut resumed = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        assert!(resumed.resume_push(&src_repo, &mut ipfs, &opts).unwrap());
+        // The truncated entry doesn't count, so every object is uploaded again
+        assert!(events.lock().unwrap().contains(&ProgressEvent::Started {
+            phase: TransferPhase::Uploading,
+            total: Some(6)
+        }));
+
+        let (_expected_dir, _expected_repo, expected, _) = pushed_index(2);
+        assert_eq!(resumed, expected);
+    }
+
+    #[test]
+    fn test_resume_push_refuses_moved_remote() {
+        let (_src_dir, mut src_repo) = repo_with_history(2);
+        let master = src_repo.refname_to_id("refs/heads/master").unwrap();
+        let first = src_repo.find_commit(master).unwrap().parent_id(0).unwrap();
+        src_repo
+            .reference("refs/heads/old", first, false, "test")
+            .unwrap();
+        let journal_dir = TempDir::new().unwrap();
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        idx.refs
+            .insert("refs/heads/other".to_owned(), first.to_string());
+        let remote = idx.ipfs_add(&mut ipfs, None).unwrap();
+
+        let opts = TransferOptions {
+            journal: Some(journal_dir.path().to_owned()),
+            ..cancel_after_first(TransferPhase::Uploading)
+        };
+        let mut crashed = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        assert!(crashed
+            .push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .is_err());
+
+        // Another writer publishes the same ref before the push is resumed
+        let mut other_writer = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        other_writer
+            .push_ref_from_str(
+                "refs/heads/old",
+                "refs/heads/master",
+                false,
+                &mut src_repo,
+                &mut ipfs,
+            )
+            .unwrap();
+        let moved_remote = other_writer.ipfs_add(&mut ipfs, Some(&remote)).unwrap();
+
+        let opts = TransferOptions {
+            journal: Some(journal_dir.path().to_owned()),
+            ..Default::default()
+        };
+        let mut resumed = NIPIndex::from_nip_remote(&moved_remote, &mut ipfs).unwrap();
+        let resumed_before = resumed.clone();
+        let stored_before = ipfs.len();
+        match resumed
+            .resume_push(&src_repo, &mut ipfs, &opts)
+            .unwrap_err()
+            .downcast::<NIPIndexError>()
+        {
+            Ok(NIPIndexError::StaleRef(ref ref_name)) if ref_name == "refs/heads/master" => {}
+            other => panic!("Expected StaleRef, got {:?}", other),
+        }
+        assert_eq!(resumed, resumed_before);
+        assert_eq!(ipfs.len(), stored_before);
+        assert_eq!(resumed.refs["refs/heads/master"], first.to_string());
+
+        // The journal is kept, and still resumes onto the index it was staged against
+        let mut original = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        assert!(original.resume_push(&src_repo, &mut ipfs, &opts).unwrap());
+        assert_eq!(original.refs["refs/heads/master"], master.to_string());
+    }
+
+    #[test]
+    fn test_push_refs_counts_shared_history_once() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let master = src_repo.refname_to_id("refs/heads/master").unwrap();
+        let first = src_repo.find_commit(master).unwrap().parent_id(0).unwrap();
+        src_repo
+            .reference("refs/heads/old", first, false, "test")
+            .unwrap();
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        idx.refs
+            .insert("refs/heads/stale".to_owned(), first.to_string());
+
+        let (opts, events) = recording_opts();
+        let idx_before = idx.clone();
+        assert!(idx
+            .push_refs(
+                &[
+                    ("refs/heads/master", "refs/heads/master", false),
+                    ("refs/heads/old", "refs/heads/master", false),
+                ],
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .is_err());
+        assert_eq!(idx, idx_before);
+
+        let report = idx
+            .push_refs(
+                &[
+                    ("refs/heads/master", "refs/heads/master", false),
+                    ("refs/heads/old", "refs/heads/old", false),
+                    ("refs/heads/nope", "refs/heads/nope", false),
+                    ("", "refs/heads/stale", false),
+                ],
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap();
+
+        assert_eq!(
+            report,
+            vec![
+                ("refs/heads/master".to_owned(), PushOutcome::Created),
+                ("refs/heads/old".to_owned(), PushOutcome::Created),
+                (
+                    "refs/heads/nope".to_owned(),
+                    PushOutcome::RejectedMissingSrc
+                ),
+                ("refs/heads/stale".to_owned(), PushOutcome::Deleted),
+            ]
+        );
+
+        let mut expected_refs = BTreeMap::new();
+        expected_refs.insert("refs/heads/master".to_owned(), master.to_string());
+        expected_refs.insert("refs/heads/old".to_owned(), first.to_string());
+        assert_eq!(idx.refs, expected_refs);
+        assert_eq!(idx.objects.len(), 6);
+
+        // One counting pass, one upload of the union
+        let events = events.lock().unwrap();
+        let counting_passes = events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    ProgressEvent::Started {
+                        phase: TransferPhase::Counting,
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(counting_passes, 1);
+        assert!(events.contains(&ProgressEvent::Finished {
+            phase: TransferPhase::Counting,
+            objects: 6,
+            bytes: 0
+        }));
+        assert!(events.contains(&ProgressEvent::Started {
+            phase: TransferPhase::Uploading,
+            total: Some(6)
+        }));
+    }
+
+    #[test]
+    fn test_fetch_refs_downloads_shared_history_once() {
+        let (_src_dir, src_repo) = repo_with_history(3);
+        let (_dst_dir, mut dst_repo) = empty_repo();
+        let master = src_repo.refname_to_id("refs/heads/master").unwrap();
+        let older = src_repo.find_commit(master).unwrap().parent_id(0).unwrap();
+        src_repo
+            .reference("refs/heads/older", older, false, "test")
+            .unwrap();
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        idx.push_refs(
+            &[
+                ("refs/heads/master", "refs/heads/master", false),
+                ("refs/heads/older", "refs/heads/older", false),
+            ],
+            &src_repo,
+            &mut ipfs,
+            &TransferOptions::default(),
+        )
+        .unwrap();
+
+        let master_hash = master.to_string();
+        let older_hash = older.to_string();
+        idx.fetch_refs(
+            &[
+                (&older_hash, "refs/heads/older"),
+                (&master_hash, "refs/heads/master"),
+            ],
+            &mut dst_repo,
+            &mut ipfs,
+            &TransferOptions::default(),
+        )
+        .unwrap();
+
+        // One request for every NIPObject and one for its raw data, despite the overlap
+        assert_eq!(server.request_count("/cat"), 2 * idx.objects.len());
+        assert_eq!(dst_repo.refname_to_id("refs/heads/master").unwrap(), master);
+        assert_eq!(dst_repo.refname_to_id("refs/heads/older").unwrap(), older);
+    }
+
+    #[test]
+    fn test_push_outcomes() {
+        let (_first_dir, mut first_repo) = repo_with_history(2);
+        let (_second_dir, mut second_repo) = repo_with_history(1);
+        let mut ipfs = InMemoryStore::new();
+        let opts = TransferOptions::default();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let mut push = |idx: &mut NIPIndex, ref_src, force, repo: &mut Repository| {
+            idx.push_ref_from_str_with_opts(
+                ref_src,
+                "refs/heads/master",
+                force,
+                repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            push(&mut idx, "refs/heads/master", false, &mut second_repo),
+            PushOutcome::Created
+        );
+        assert_eq!(
+            push(&mut idx, "refs/heads/master", false, &mut second_repo),
+            PushOutcome::UpToDate
+        );
+        assert_eq!(
+            push(&mut idx, "refs/heads/master", false, &mut first_repo),
+            PushOutcome::FastForwarded
+        );
+
+        // The second repo lacks the new remote tip
+        let idx_before = idx.clone();
+        let outcome = push(&mut idx, "refs/heads/master", false, &mut second_repo);
+        assert_eq!(outcome, PushOutcome::RejectedFetchFirst);
+        assert_eq!(idx, idx_before);
+        assert_eq!(
+            outcome.helper_status("refs/heads/master"),
+            "error refs/heads/master fetch first"
+        );
+
+        let outcome = push(&mut idx, "refs/heads/nope", false, &mut second_repo);
+        assert_eq!(outcome, PushOutcome::RejectedMissingSrc);
+        assert_eq!(idx, idx_before);
+
+        let outcome = push(&mut idx, "refs/heads/master", true, &mut second_repo);
+        assert_eq!(outcome, PushOutcome::Forced);
+        assert_eq!(
+            outcome.helper_status("refs/heads/master"),
+            "ok refs/heads/master"
+        );
+
+        assert_eq!(
+            push(&mut idx, "", false, &mut second_repo),
+            PushOutcome::Deleted
+        );
+        assert!(idx.refs.is_empty());
+
+        let outcome = push(&mut idx, "", false, &mut second_repo);
+        assert_eq!(outcome, PushOutcome::RejectedMissingDst);
+        assert_eq!(
+            outcome.helper_status("refs/heads/master"),
+            "error refs/heads/master remote ref does not exist"
+        );
+    }
+
+    #[test]
+    fn test_push_ref_from_str_fails_on_rejection() {
+        let (_first_dir, _first_repo, mut idx, mut ipfs) = pushed_index(2);
+        let (_second_dir, mut second_repo) = repo_with_history(1);
+        let idx_before = idx.clone();
+
+        // The second repo lacks the remote tip
+        let err = idx
+            .push_ref_from_str(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &mut second_repo,
+                &mut ipfs,
+            )
+            .unwrap_err();
+        match err.downcast::<NIPIndexError>() {
+            Ok(NIPIndexError::FetchFirst) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(idx, idx_before);
+
+        assert!(idx
+            .push_ref_from_str(
+                "refs/heads/nope",
+                "refs/heads/master",
+                false,
+                &mut second_repo,
+                &mut ipfs,
+            )
+            .is_err());
+        assert_eq!(idx, idx_before);
+    }
+
+    #[test]
+    fn test_push_rejects_rewritten_history() {
+        let (_src_dir, mut src_repo) = repo_with_history(2);
+        let mut ipfs = InMemoryStore::new();
+        let opts = TransferOptions::default();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let mut push = |idx: &mut NIPIndex, force, repo: &mut Repository| {
+            idx.push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                force,
+                repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap()
+        };
+        assert_eq!(push(&mut idx, false, &mut src_repo), PushOutcome::Created);
+
+        // Commits `msg` on top of the tip's first parent if `amend` is set, on the tip otherwise
+        let commit = |repo: &Repository, msg: &str, amend: bool| {
+            let tip = repo.head().unwrap().peel_to_commit().unwrap();
+            let parent = if amend {
+                tip.parent(0).unwrap()
+            } else {
+                tip.clone()
+            };
+            let sig = Signature::new("nip", "nip@example.com", &Time::new(1, 0)).unwrap();
+            let oid = repo
+                .commit(None, &sig, &sig, msg, &tip.tree().unwrap(), &[&parent])
+                .unwrap();
+            repo.reference("refs/heads/master", oid, true, "test")
+                .unwrap();
+        };
+
+        // Amend the tip; the old one stays in the odb
+        commit(&src_repo, "Amended", true);
+
+        let idx_before = idx.clone();
+        assert_eq!(
+            push(&mut idx, false, &mut src_repo),
+            PushOutcome::RejectedNonFastForward
+        );
+        assert_eq!(
+            PushOutcome::RejectedNonFastForward.helper_status("refs/heads/master"),
+            "error refs/heads/master non-fast-forward"
+        );
+        assert_eq!(idx, idx_before);
+        assert_eq!(push(&mut idx, true, &mut src_repo), PushOutcome::Forced);
+
+        // Forcing a push that happens to be a fast-forward is no forced update
+        commit(&src_repo, "On top", false);
+        assert_eq!(
+            push(&mut idx, true, &mut src_repo),
+            PushOutcome::FastForwarded
+        );
+    }
+
+    #[test]
+    fn test_push_with_lease() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let master = src_repo.refname_to_id("refs/heads/master").unwrap();
+        let first = src_repo.find_commit(master).unwrap().parent_id(0).unwrap();
+        src_repo
+            .reference("refs/heads/old", first, false, "test")
+            .unwrap();
+        let (master_hash, first_hash) = (master.to_string(), first.to_string());
+        let mut ipfs = InMemoryStore::new();
+        let opts = TransferOptions::default();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let mut push = |idx: &mut NIPIndex, ref_src, expected| {
+            idx.push_ref_with_lease(
+                ref_src,
+                "refs/heads/master",
+                expected,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            push(&mut idx, "refs/heads/master", None),
+            PushOutcome::Created
+        );
+
+        // Someone else's view of the ref is stale
+        let idx_before = idx.clone();
+        assert_eq!(
+            push(&mut idx, "refs/heads/old", None),
+            PushOutcome::RejectedStale
+        );
+        assert_eq!(
+            push(&mut idx, "refs/heads/old", Some(&first_hash)),
+            PushOutcome::RejectedStale
+        );
+        assert_eq!(idx, idx_before);
+
+        // An up-to-date lease rewinds the ref like a forced push
+        assert_eq!(
+            push(&mut idx, "refs/heads/old", Some(&master_hash)),
+            PushOutcome::Forced
+        );
+        assert_eq!(idx.refs["refs/heads/master"], first_hash);
+
+        // Change sets carry the lease until they're applied
+        let mut changes = ChangeSet::new();
+        changes
+            .refs
+            .insert("refs/heads/master".to_owned(), Some(master_hash.clone()));
+        changes
+            .leases
+            .insert("refs/heads/master".to_owned(), Some(master_hash.clone()));
+        let idx_before = idx.clone();
+        match idx.apply(changes).unwrap_err().downcast::<NIPIndexError>() {
+            Ok(NIPIndexError::StaleRef(ref ref_name)) if ref_name == "refs/heads/master" => {}
+            other => panic!("Expected StaleRef, got {:?}", other),
+        }
+        assert_eq!(idx, idx_before);
+    }
+
+    #[test]
+    fn test_apply_rejects_refs_moved_since_staging() {
+        let (_src_dir, mut src_repo) = repo_with_history(2);
+        let master = src_repo.refname_to_id("refs/heads/master").unwrap();
+        let first = src_repo.find_commit(master).unwrap().parent_id(0).unwrap();
+        src_repo
+            .reference("refs/heads/old", first, false, "test")
+            .unwrap();
+        let mut ipfs = InMemoryStore::new();
+        let opts = TransferOptions::default();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let mut changes = idx
+            .stage_ref_push(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap();
+        assert_eq!(changes.leases["refs/heads/master"], None);
+        changes.upload(&src_repo, &mut ipfs, &opts).unwrap();
+
+        // The ref showed up after the change set was staged
+        let staged_against = idx.clone();
+        idx.push_ref_from_str(
+            "refs/heads/old",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut ipfs,
+        )
+        .unwrap();
+        let idx_before = idx.clone();
+        match idx
+            .apply(changes.clone())
+            .unwrap_err()
+            .downcast::<NIPIndexError>()
+        {
+            Ok(NIPIndexError::StaleRef(ref ref_name)) if ref_name == "refs/heads/master" => {}
+            other => panic!("Expected StaleRef, got {:?}", other),
+        }
+        assert_eq!(idx, idx_before);
+
+        let mut idx = staged_against;
+        idx.apply(changes).unwrap();
+        assert_eq!(idx.refs["refs/heads/master"], master.to_string());
+    }
+
+    #[test]
+    fn test_ipfs_add_detects_concurrent_writer() {
+        let (_src_dir, src_repo) = repo_with_history(2);
+        let master = src_repo.refname_to_id("refs/heads/master").unwrap();
+        let first = src_repo.find_commit(master).unwrap().parent_id(0).unwrap();
+        src_repo
+            .reference("refs/heads/old", first, false, "test")
+            .unwrap();
+        let mut ipfs = InMemoryStore::new();
+        let opts = TransferOptions::default();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPNS, &mut ipfs).unwrap();
+        assert_eq!(
+            idx.push_ref_from_str_with_opts(
+                "refs/heads/master",
+                "refs/heads/master",
+                false,
+                &src_repo,
+                &mut ipfs,
+                &opts,
+            )
+            .unwrap(),
+            PushOutcome::Created
+        );
+        let remote = idx.ipfs_add(&mut ipfs, Some(&NIPRemote::NewIPNS)).unwrap();
+
+        let mut first_writer = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        let mut second_writer = first_writer.clone();
+        for writer in &mut [&mut first_writer, &mut second_writer] {
+            assert_eq!(
+                writer
+                    .push_ref_from_str_with_opts(
+                        "refs/heads/old",
+                        "refs/heads/old",
+                        false,
+                        &src_repo,
+                        &mut ipfs,
+                        &opts,
+                    )
+                    .unwrap(),
+                PushOutcome::Created
+            );
+        }
+
+        first_writer.ipfs_add(&mut ipfs, Some(&remote)).unwrap();
+        // Publishing again on top of one's own index is fine
+        first_writer.ipfs_add(&mut ipfs, Some(&remote)).unwrap();
+
+        let second_before = second_writer.clone();
+        match second_writer
+            .ipfs_add(&mut ipfs, Some(&remote))
+            .unwrap_err()
+            .downcast::<NIPIndexError>()
+        {
+            Ok(NIPIndexError::ConcurrentWrite { expected, found }) => {
+                assert_eq!(Some(expected), second_before.base_idx_hash);
+                assert_eq!(Some(found), first_writer.base_idx_hash);
+            }
+            other => panic!("Expected ConcurrentWrite, got {:?}", other),
+        }
+        assert_eq!(second_writer, second_before);
+        assert_eq!(
+            NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap(),
+            first_writer
+        );
+    }
+
+    #[test]
+    fn test_merge_divergent_indices() {
+        let (_src_dir, src_repo) = repo_with_history(3);
+        let c2 = src_repo.refname_to_id("refs/heads/master").unwrap();
+        let c1 = src_repo.find_commit(c2).unwrap().parent_id(0).unwrap();
+        let c0 = src_repo.find_commit(c1).unwrap().parent_id(0).unwrap();
+        let d1 = {
+            let c0_commit = src_repo.find_commit(c0).unwrap();
+            let sig = Signature::new("nip", "nip@example.com", &Time::new(1, 0)).unwrap();
+            src_repo
+                .commit(
+                    None,
+                    &sig,
+                    &sig,
+                    "Diverged",
+                    &c0_commit.tree().unwrap(),
+                    &[&c0_commit],
+                )
+                .unwrap()
+        };
+        for (name, oid) in &[("c0", c0), ("c1", c1), ("c2", c2), ("d1", d1)] {
+            src_repo
+                .reference(&format!("refs/heads/{}", name), *oid, false, "test")
+                .unwrap();
+        }
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+        let opts = TransferOptions::default();
+
+        let mut store = ipfs.clone();
+        let mut push_and_add = |idx: &mut NIPIndex, refspecs: &[(&str, &str, bool)]| {
+            idx.push_refs(refspecs, &src_repo, &mut store, &opts)
+                .unwrap();
+            let remote = idx.ipfs_add(&mut store, None).unwrap();
+            NIPIndex::from_nip_remote(&remote, &mut store).unwrap()
+        };
+
+        let mut base = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let base = push_and_add(
+            &mut base,
+            &[
+                ("refs/heads/c0", "refs/heads/master", false),
+                ("refs/heads/c0", "refs/heads/topic", false),
+            ],
+        );
+        let ours = push_and_add(
+            &mut base.clone(),
+            &[
+                ("refs/heads/c1", "refs/heads/master", false),
+                ("refs/heads/c1", "refs/heads/topic", false),
+                ("refs/heads/c0", "refs/heads/mine", false),
+            ],
+        );
+        let theirs = push_and_add(
+            &mut base.clone(),
+            &[
+                ("refs/heads/c2", "refs/heads/master", false),
+                ("refs/heads/d1", "refs/heads/topic", false),
+                ("refs/heads/c0", "refs/heads/theirs", false),
+            ],
+        );
+
+        // None of the history is present locally
+        let (_dst_dir, dst_repo) = empty_repo();
+        let cats_before = server.request_count("/cat");
+        let IndexMerge { index, conflicts } = ours.merge(&theirs, &dst_repo, &mut ipfs).unwrap();
+        // c2, c1, d1 and c0, the latter being shared by both walks of topic
+        assert_eq!(server.request_count("/cat") - cats_before, 4);
+
+        let expected_refs: BTreeMap<_, _> = vec![
+            ("refs/heads/master", c2),
+            ("refs/heads/mine", c0),
+            ("refs/heads/theirs", c0),
+            ("refs/heads/topic", c1),
+        ]
+        .into_iter()
+        .map(|(name, oid)| (name.to_owned(), oid.to_string()))
+        .collect();
+        assert_eq!(index.refs, expected_refs);
+
+        let mut expected_conflicts = BTreeMap::new();
+        expected_conflicts.insert(
+            "refs/heads/topic".to_owned(),
+            (c1.to_string(), d1.to_string()),
+        );
+        assert_eq!(conflicts, expected_conflicts);
+
+        for git_hash in ours.objects.keys().chain(theirs.objects.keys()) {
+            assert!(index.objects.contains_key(git_hash));
+        }
+        assert_eq!(index.merged_idx_hash, theirs.base_idx_hash);
+
+        // The published merge matches the merged index, later indices aren't merges
+        let ours_remote: NIPRemote = ours.base_idx_hash.clone().unwrap().parse().unwrap();
+        let mut published = index.clone();
+        let merge_remote = published.ipfs_add(&mut ipfs, Some(&ours_remote)).unwrap();
+        assert_eq!(published.prev_idx_hash, ours.base_idx_hash);
+        assert_eq!(published.merged_idx_hash, theirs.base_idx_hash);
+        assert_eq!(
+            NIPIndex::from_nip_remote(&merge_remote, &mut ipfs).unwrap(),
+            published
+        );
+        published.ipfs_add(&mut ipfs, Some(&merge_remote)).unwrap();
+        assert_eq!(published.merged_idx_hash, None);
+
+        // Ancestors never win over their descendants, whichever side they're on
+        let merged_the_other_way = theirs.merge(&ours, &dst_repo, &mut ipfs).unwrap();
+        assert_eq!(
+            merged_the_other_way.index.refs["refs/heads/master"],
+            c2.to_string()
+        );
+
+        // Local history needs no downloads at all; an empty store would fail any of them
+        let local_merge = ours
+            .merge(&theirs, &src_repo, &mut InMemoryStore::new())
+            .unwrap();
+        assert_eq!(local_merge.index, index);
+        assert_eq!(local_merge.conflicts, conflicts);
+
+        // An index that was never published has no link to merge
+        let mut unpublished = theirs.clone();
+        unpublished.base_idx_hash = None;
+        let err = ours
+            .merge(&unpublished, &src_repo, &mut InMemoryStore::new())
+            .unwrap_err();
+        match err.downcast::<NIPIndexError>() {
+            Ok(NIPIndexError::UnpublishedIndex) => {}
+            other => panic!("Expected UnpublishedIndex, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_big_index_is_sharded() {
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        for i in 0..=MAX_SHARD_OBJECTS {
+            let i = i as u64;
+            let git_hash = format!("{:016x}{:024x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15), i);
+            idx.objects.insert(git_hash, format!("/ipfs/Qm{}", i));
+        }
+        idx.refs
+            .insert("refs/heads/master".to_owned(), "0".repeat(40));
+
+        let remote = idx.ipfs_add(&mut ipfs, None).unwrap();
+        let hash = match remote {
+            NIPRemote::ExistingIPFS(ref hash) => hash.clone(),
+            other => panic!("Unexpected remote {:?}", other),
+        };
+        let bytes = current_thread::block_on_all(ipfs.cat(&hash)).unwrap();
+        let stored = NIPIndex::parse_stored(&bytes).unwrap();
+        assert!(stored.objects.is_none());
+        assert!(stored.object_root.is_some());
+        assert_eq!(NIPIndex::decode(&bytes, &mut ipfs).unwrap(), idx);
+
+        let downloaded = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        assert_eq!(downloaded.objects, idx.objects);
+        assert_eq!(downloaded.refs, idx.refs);
+
+        // Only the touched shard, the trie root and the index itself are new. Without a previous
+        // index to build a delta on, the index is stored in full.
+        let mut updated = downloaded;
+        updated
+            .objects
+            .insert("f".repeat(40), "/ipfs/QmNew".to_owned());
+        let len_before = ipfs.len();
+        let updated_remote = updated.ipfs_add(&mut ipfs, None).unwrap();
+        assert_eq!(ipfs.len(), len_before + 3);
+        assert_eq!(
+            NIPIndex::from_nip_remote(&updated_remote, &mut ipfs).unwrap(),
+            updated
+        );
+    }
+
+    /// A change set moving `refs/heads/master` to a fake commit `i` and dropping
+    /// `refs/heads/old`
+    fn fake_commit_changes(i: usize) -> ChangeSet {
+        let mut changes = ChangeSet::new();
+        changes
+            .objects
+            .insert(format!("{:040x}", i), format!("/ipfs/Qm{}", i));
+        changes
+            .refs
+            .insert("refs/heads/master".to_owned(), Some(format!("{:040x}", i)));
+        changes.refs.insert("refs/heads/old".to_owned(), None);
+        changes
+    }
+
+    #[test]
+    fn test_ipfs_add_publishes_deltas() {
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        for i in 0..100 {
+            idx.objects
+                .insert(format!("{:040x}", i), format!("/ipfs/Qm{}", i));
+        }
+        idx.refs
+            .insert("refs/heads/master".to_owned(), format!("{:040x}", 0));
+        idx.refs
+            .insert("refs/heads/old".to_owned(), format!("{:040x}", 1));
+        let mut remote = idx.ipfs_add(&mut ipfs, None).unwrap();
+
+        let mut stored_deltas = 0;
+        for i in 100..100 + MAX_DELTA_CHAIN + 1 {
+            let mut idx = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+            idx.apply(fake_commit_changes(i)).unwrap();
+
+            let prev_remote = remote;
+            remote = idx.ipfs_add(&mut ipfs, Some(&prev_remote)).unwrap();
+            let hash = match remote {
+                NIPRemote::ExistingIPFS(ref hash) => hash.clone(),
+                ref other => panic!("Unexpected remote {:?}", other),
+            };
+            let bytes = current_thread::block_on_all(ipfs.cat(&hash)).unwrap();
+            let stored = NIPIndex::parse_stored(&bytes).unwrap();
+            if stored.delta.is_some() {
+                assert_eq!(stored.prev_idx_hash, Some(prev_remote.to_string()));
+                stored_deltas += 1;
+            } else {
+                // The chain got long enough for a checkpoint
+                assert_eq!(stored_deltas, MAX_DELTA_CHAIN);
+                assert_eq!(stored.objects.as_ref(), Some(&idx.objects));
+                assert_eq!(stored.refs, idx.refs);
+            }
+            assert_eq!(NIPIndex::decode(&bytes, &mut ipfs).unwrap(), idx);
+
+            let downloaded = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+            assert_eq!(downloaded, idx);
+            assert_eq!(downloaded.objects.len(), i + 1);
+            assert!(!downloaded.refs.contains_key("refs/heads/old"));
+        }
+        assert_eq!(stored_deltas, MAX_DELTA_CHAIN);
+
+        // Changes made behind apply()'s back can't go in a delta
+        let mut idx = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        idx.apply(fake_commit_changes(1000)).unwrap();
+        idx.objects.remove(&format!("{:040x}", 0));
+        let prev_remote = remote;
+        let remote = idx.ipfs_add(&mut ipfs, Some(&prev_remote)).unwrap();
+        let bytes = current_thread::block_on_all(ipfs.cat(bare_hash(&remote.to_string()))).unwrap();
+        assert!(NIPIndex::parse_stored(&bytes).unwrap().delta.is_none());
+        assert_eq!(NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap(), idx);
+    }
+
+    #[test]
+    fn test_delta_publish_downloads_nothing() {
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        for i in 0..=MAX_SHARD_OBJECTS {
+            idx.objects
+                .insert(format!("{:040x}", i), format!("/ipfs/Qm{}", i));
+        }
+        let remote = idx.ipfs_add(&mut ipfs, None).unwrap();
+
+        let mut idx = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        idx.apply(fake_commit_changes(MAX_SHARD_OBJECTS + 1))
+            .unwrap();
+
+        let cats_before = server.request_count("/cat");
+        let new_remote = idx.ipfs_add(&mut ipfs, Some(&remote)).unwrap();
+        assert_eq!(server.request_count("/cat"), cats_before);
+
+        let bytes = current_thread::block_on_all(ContentStore::cat(
+            &mut ipfs,
+            bare_hash(&new_remote.to_string()),
+        ))
+        .unwrap();
+        assert!(NIPIndex::parse_stored(&bytes).unwrap().delta.is_some());
+        assert_eq!(
+            NIPIndex::from_nip_remote(&new_remote, &mut ipfs).unwrap(),
+            idx
+        );
+    }
+
+    #[test]
+    fn test_short_index_is_rejected() {
+        let mut ipfs = InMemoryStore::new();
+
+        let link = current_thread::block_on_all(ipfs.add(b"nip".to_vec())).unwrap();
+        let remote = NIPRemote::ExistingIPFS(bare_hash(&link).to_owned());
+        let e = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap_err();
+        assert!(e.downcast_ref::<NIPError>().is_some());
+    }
+
+    #[test]
+    fn test_eq_and_hash_ignore_base_idx_hash() {
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        idx.refs
+            .insert("refs/heads/master".to_owned(), "0".repeat(40));
+        let remote = idx.ipfs_add(&mut ipfs, None).unwrap();
+
+        let downloaded = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        assert!(downloaded.base_idx_hash.is_some());
+        idx.base_idx_hash = None;
+
+        let hash_of = |idx: &NIPIndex| {
+            let mut hasher = DefaultHasher::new();
+            idx.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(downloaded, idx);
+        assert_eq!(hash_of(&downloaded), hash_of(&idx));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut ipfs = InMemoryStore::new();
+
+        assert_eq!(NIPIndex::history(&NIPRemote::NewIPNS, &mut ipfs).count(), 0);
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPNS, &mut ipfs).unwrap();
+        let mut remote = NIPRemote::NewIPNS;
+        let mut published = Vec::new();
+        for i in 0..3 {
+            idx.refs
+                .insert("refs/heads/master".to_owned(), format!("{:040x}", i));
+            remote = idx.ipfs_add(&mut ipfs, Some(&remote)).unwrap();
+            published.push((idx.base_idx_hash.clone().unwrap(), idx.refs.clone()));
+        }
+
+        let history = NIPIndex::history(&remote, &mut ipfs)
+            .map(|entry| {
+                let (link, idx) = entry.unwrap();
+                (link, idx.refs)
+            })
+            .collect::<Vec<_>>();
+        published.reverse();
+        assert_eq!(history, published);
+    }
+
+    #[test]
+    fn test_reflog() {
+        let mut ipfs = InMemoryStore::new();
+        let hash = |i| format!("{:040x}", i);
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        let mut remote = NIPRemote::NewIPFS;
+        let mut links = Vec::new();
+        let steps: &[&dyn Fn(&mut NIPIndex)] = &[
+            &|idx| {
+                idx.refs.insert("refs/heads/master".to_owned(), hash(1));
+                idx.refs.insert("refs/heads/topic".to_owned(), hash(2));
+            },
+            &|idx| {
+                idx.refs.insert("refs/heads/master".to_owned(), hash(3));
+            },
+            // A force-push of master and a deletion of topic
+            &|idx| {
+                idx.refs.insert("refs/heads/master".to_owned(), hash(4));
+                idx.refs.remove("refs/heads/topic");
+            },
+            // Nothing changes
+            &|_| {},
+        ];
+        for step in steps {
+            step(&mut idx);
+            remote = idx.ipfs_add(&mut ipfs, Some(&remote)).unwrap();
+            links.push(idx.base_idx_hash.clone().unwrap());
+        }
+
+        let entry = |old: Option<usize>, new: Option<usize>, link: &String| RefLogEntry {
+            old: old.map(hash),
+            new: new.map(hash),
+            idx_link: link.clone(),
+        };
+        let reflog = NIPIndex::reflog(&remote, &mut ipfs).unwrap();
+        assert_eq!(reflog.len(), 2);
+        assert_eq!(
+            reflog["refs/heads/master"],
+            vec![
+                entry(Some(3), Some(4), &links[2]),
+                entry(Some(1), Some(3), &links[1]),
+                entry(None, Some(1), &links[0]),
+            ]
+        );
+        assert_eq!(
+            reflog["refs/heads/topic"],
+            vec![
+                entry(Some(2), None, &links[2]),
+                entry(None, Some(2), &links[0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut ipfs = InMemoryStore::new();
+        let hash = |i| format!("{:040x}", i);
+
+        let mut old = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        old.refs.insert("refs/heads/master".to_owned(), hash(1));
+        old.refs.insert("refs/heads/gone".to_owned(), hash(1));
+        old.refs.insert("refs/tags/v1".to_owned(), hash(1));
+        old.objects.insert(hash(1), "/ipfs/QmOne".to_owned());
+        old.objects.insert(hash(2), "/ipfs/QmTwo".to_owned());
+
+        assert!(old.diff(&old).is_empty());
+        assert_eq!(
+            format!("{}", old.diff(&old)),
+            "0 object(s) added, 0 removed\n"
+        );
+
+        let mut new = old.clone();
+        new.refs.insert("refs/heads/master".to_owned(), hash(3));
+        new.refs.remove("refs/heads/gone");
+        new.refs.insert("refs/heads/topic".to_owned(), hash(3));
+        new.objects.remove(&hash(2));
+        new.objects.insert(hash(3), "/ipfs/QmThree".to_owned());
+
+        let diff = old.diff(&new);
+        assert!(!diff.is_empty());
+        assert_eq!(diff.refs_moved["refs/heads/master"], (hash(1), hash(3)));
+        assert_eq!(
+            diff.refs_removed.keys().collect::<Vec<_>>(),
+            vec!["refs/heads/gone"]
+        );
+        assert_eq!(
+            diff.refs_added.keys().collect::<Vec<_>>(),
+            vec!["refs/heads/topic"]
+        );
+        assert_eq!(
+            diff.objects_added.keys().collect::<Vec<_>>(),
+            vec![&hash(3)]
+        );
+        assert_eq!(
+            diff.objects_removed.keys().collect::<Vec<_>>(),
+            vec![&hash(2)]
+        );
+
+        assert_eq!(
+            format!("{}", diff),
+            format!(
+                "+ refs/heads/topic {new}\n- refs/heads/gone {old}\n\
+                 ~ refs/heads/master {old}..{new}\n1 object(s) added, 1 removed\n",
+                old = hash(1),
+                new = hash(3)
+            )
+        );
+        assert_eq!(
+            format!("{:#}", diff).lines().count(),
+            format!("{}", diff).lines().count() + 2
+        );
 
-        Ok(new_hash.parse()?)
+        let json = serde_json::to_string(&diff).unwrap();
+        assert_eq!(serde_json::from_str::<IndexDiff>(&json).unwrap(), diff);
     }
 }
@@ -0,0 +1,133 @@
+//! An on-disk record of push progress that survives process restarts
+use failure::Error;
+use git2::Repository;
+
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{constants::IPFS_HASH_LEN, index::ChangeSet, store::checked_hash};
+
+const STAGED_FILE: &str = "staged";
+const UPLOADED_FILE: &str = "uploaded";
+
+/// A push journal directory. It holds the change set a push started uploading (`staged`) and a
+/// `<sha1> <link>` line for every object uploaded since (`uploaded`). Pushes keep one when
+/// `TransferOptions::journal` is set; `NIPIndex::resume_push()` uses it to skip the objects a
+/// crashed push already uploaded. The change set's leases record what the pushed refs pointed at
+/// when the push was staged, which keeps a resume from overwriting refs moved since.
+#[derive(Debug)]
+pub struct PushJournal {
+    dir: PathBuf,
+    uploaded: File,
+}
+
+impl PushJournal {
+    /// Returns the default journal directory for `repo`, i.e. `.git/nip/journal`
+    pub fn dir_for_repo(repo: &Repository) -> PathBuf {
+        repo.path().join("nip").join("journal")
+    }
+
+    /// Start journaling the upload of `changes` in `dir`, replacing whatever the directory held
+    pub fn start<P: AsRef<Path>>(dir: P, changes: &ChangeSet) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        // Everything recorded so far is part of `changes`, so the old lines can go once it's saved
+        changes.save(dir.join(STAGED_FILE))?;
+        let uploaded = File::create(dir.join(UPLOADED_FILE))?;
+        debug!(
+            "Journaling push of {} object(s) in {}",
+            changes.pending.len(),
+            dir.display()
+        );
+
+        Ok(Self { dir, uploaded })
+    }
+
+    /// Record that `git_hash` was uploaded to `nip_object_hash`
+    pub fn record(&mut self, git_hash: &str, nip_object_hash: &str) -> Result<(), Error> {
+        trace!(
+            "Journaling {} -> {} in {}",
+            git_hash,
+            nip_object_hash,
+            self.dir.display()
+        );
+        self.uploaded
+            .write_all(format!("{} {}\n", git_hash, nip_object_hash).as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Rebuild the change set journaled in `dir` with all recorded uploads accounted for; returns
+    /// `None` if there's no journal.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Option<ChangeSet>, Error> {
+        let dir = dir.as_ref();
+
+        let mut changes = match ChangeSet::load(dir.join(STAGED_FILE)) {
+            Ok(changes) => changes,
+            Err(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(io_err) if io_err.kind() == ErrorKind::NotFound => return Ok(None),
+                _ => return Err(e),
+            },
+        };
+
+        let uploaded = match fs::read_to_string(dir.join(UPLOADED_FILE)) {
+            Ok(uploaded) => uploaded,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        // Only newline-terminated lines were written in full; a crash may cut the last one short
+        let complete = match uploaded.rfind('\n') {
+            Some(end) => &uploaded[..=end],
+            None => "",
+        };
+        let partial = &uploaded[complete.len()..];
+        if !partial.is_empty() {
+            warn!("Ignoring truncated push journal entry {:?}", partial);
+        }
+
+        for line in complete.lines() {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(git_hash), Some(nip_object_hash), None)
+                    if changes.pending.contains(git_hash) && is_valid_link(nip_object_hash) =>
+                {
+                    changes.record_upload(git_hash.to_owned(), nip_object_hash.to_owned());
+                }
+                _ => warn!("Ignoring push journal entry {:?}", line),
+            }
+        }
+        debug!(
+            "Loaded push journal from {}: {} object(s) uploaded, {} pending",
+            dir.display(),
+            changes.objects.len(),
+            changes.pending.len()
+        );
+
+        Ok(Some(changes))
+    }
+
+    /// Remove the journal from `dir`
+    pub fn clear<P: AsRef<Path>>(dir: P) -> Result<(), Error> {
+        for file_name in &[UPLOADED_FILE, STAGED_FILE] {
+            match fs::remove_file(dir.as_ref().join(file_name)) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns true if `link` points at a whole IPFS hash
+fn is_valid_link(link: &str) -> bool {
+    match checked_hash(link) {
+        Ok(hash) => hash.len() == IPFS_HASH_LEN,
+        Err(_) => false,
+    }
+}
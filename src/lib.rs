@@ -1,6 +1,14 @@
 //! `nip_core` is a library that lets you interact with [nip](https://github.com/drozdziak1/nip)
 //! repositories programmatically.
 //!
+//! All storage-facing APIs are generic over the `ContentStore` trait; a go-ipfs daemon reached
+//! through `ipfs_api::IpfsClient` is the default backend.
+//!
+//! Blocking calls drive their own event loop and must not be used from within a tokio runtime.
+//! Those that push, fetch, publish or download indices are thin wrappers around `*_async`
+//! counterparts returning `Send + 'static` futures, which are the ones to use there; the few
+//! lower-level calls without one say so in their docs.
+//!
 //! ```rust,no_run
 //! extern crate failure;
 //! extern crate git2;
@@ -46,21 +54,39 @@ extern crate futures;
 extern crate git2;
 extern crate hyper;
 extern crate ipfs_api;
+extern crate multibase;
+extern crate multihash;
 extern crate serde;
 extern crate serde_cbor;
+extern crate tempfile;
 extern crate tokio;
 
+#[cfg(any(test, feature = "test-utils"))]
+extern crate serde_json;
+#[cfg(any(test, feature = "test-utils"))]
+extern crate serde_urlencoded;
+
 pub mod constants;
 pub mod error;
 pub mod index;
+pub mod journal;
 pub mod object;
 pub mod remote;
+mod shard;
+pub mod store;
+pub mod transfer;
 pub mod util;
 
 #[cfg(feature = "migrations")]
 pub mod migrations;
 
-pub use crate::{constants::*, error::*, index::*, object::*, remote::*, util::*};
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+
+pub use crate::{
+    constants::*, error::*, index::*, journal::*, object::*, remote::*, store::*, transfer::*,
+    util::*,
+};
 
 #[cfg(feature = "migrations")]
 pub use crate::migrations::*;
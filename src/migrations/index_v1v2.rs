@@ -0,0 +1,34 @@
+//! v1/v2 nip index implementation
+use std::collections::BTreeMap;
+
+use crate::index::NIPIndex as NIPIndexV3;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+/// The entrypoint data structure for nip repos before object sharding and deltas
+pub struct NIPIndexV1V2 {
+    /// All refs this repository knows; a {name -> sha1} mapping
+    pub refs: BTreeMap<String, String>,
+    /// All objects this repository contains; a {sha1 -> IPFS hash} map
+    pub objects: BTreeMap<String, String>,
+    /// The IPFS hash of the previous index
+    pub prev_idx_hash: Option<String>,
+    /// The IPFS hash of the second parent of a merged index; never present in V1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merged_idx_hash: Option<String>,
+}
+
+impl NIPIndexV1V2 {
+    /// Convert to a V3 index
+    pub fn into_v3(self) -> NIPIndexV3 {
+        NIPIndexV3 {
+            refs: self.refs,
+            objects: self.objects,
+            prev_idx_hash: self.prev_idx_hash,
+            merged_idx_hash: self.merged_idx_hash,
+            base_idx_hash: None,
+            shards: Default::default(),
+            base: Default::default(),
+            file_store: None,
+        }
+    }
+}
@@ -1,23 +1,22 @@
 //! A collection of migration helpers for keeping older nip repos relevant; controlled by the
 //! `migrations` feature.`
 
+mod index_v1v2;
 mod object_v1;
 
 use failure::{Error, Fail};
-use ipfs_api::IpfsClient;
 
 use crate::{
     constants::{NIP_PROTOCOL_VERSION, SUBMODULE_TIP_MARKER},
     index::NIPIndex,
     object::NIPObject,
-    util::ipfs_cat,
+    store::ContentStore,
+    util::{gen_nip_header, ipfs_cat},
 };
 
+use index_v1v2::NIPIndexV1V2;
 use object_v1::NIPObjectV1;
 
-// Once we go beyond NIP_PROTOCOL_VERSION 2 import the real V1V2 here.
-type NIPIndexV1V2 = NIPIndex;
-
 /// An error which happened during a migration
 #[derive(Clone, Debug, Eq, Fail, PartialEq)]
 pub enum MigrationError {
@@ -31,11 +30,15 @@ pub enum MigrationError {
 
 /// Take headerless `data` bytes containing an older index from nip version `version` and return a
 /// recursively updated present-day equivalent.
-pub fn migrate_index(data: &[u8], version: u16, ipfs: &mut IpfsClient) -> Result<NIPIndex, Error> {
+pub fn migrate_index<S: ContentStore>(
+    data: &[u8],
+    version: u16,
+    ipfs: &mut S,
+) -> Result<NIPIndex, Error> {
     match version {
         0 => Err(MigrationError::ZeroVersion.into()),
         1 => {
-            debug!("Migrating index: version 1 -> 2 ");
+            debug!("Migrating index: version 1 -> {}", NIP_PROTOCOL_VERSION);
             // Index structure stayed the same between v1 and v2
             let mut idx: NIPIndexV1V2 = serde_cbor::from_slice(data)?;
             for (git_hash, ipfs_hash) in idx.objects.iter_mut() {
@@ -52,11 +55,25 @@ pub fn migrate_index(data: &[u8], version: u16, ipfs: &mut IpfsClient) -> Result
 
                 *ipfs_hash = new_hash;
             }
-            Ok(idx)
+            Ok(idx.into_v3())
+        }
+        2 => {
+            debug!("Migrating index: version 2 -> {}", NIP_PROTOCOL_VERSION);
+            // V3 added sharding and deltas, neither of which a V2 index uses. Objects stayed the
+            // same and are still read as they are, so their links are kept.
+            let idx: NIPIndexV1V2 = serde_cbor::from_slice(data)?;
+            Ok(idx.into_v3())
         }
         NIP_PROTOCOL_VERSION => {
-            debug!("Trivial migration of current version {}, deserializing", NIP_PROTOCOL_VERSION);
-            Ok(serde_cbor::from_slice(data)?)},
+            debug!(
+                "Trivial migration of current version {}, decoding",
+                NIP_PROTOCOL_VERSION
+            );
+            // Sharded and delta indices need `ipfs` to be read
+            let mut bytes = gen_nip_header(None)?;
+            bytes.extend_from_slice(data);
+            NIPIndex::decode(&bytes[..], ipfs)
+        }
         other if other > NIP_PROTOCOL_VERSION => Err(MigrationError::TooNew(other).into()),
         _ => unreachable!(),
     }
@@ -64,14 +81,12 @@ pub fn migrate_index(data: &[u8], version: u16, ipfs: &mut IpfsClient) -> Result
 
 /// Take an older headerless object under `data` from nip version `version` and return a
 /// present-day equivalent.
-pub fn migrate_object(
-    data: &[u8],
-    git_hash: &str,
-    version: u16,
-) -> Result<NIPObject, Error> {
+pub fn migrate_object(data: &[u8], git_hash: &str, version: u16) -> Result<NIPObject, Error> {
     match version {
         0 => Err(MigrationError::ZeroVersion.into()),
         1 => Ok(serde_cbor::from_slice::<NIPObjectV1>(data)?.to_v2(git_hash)),
+        // Object structure stayed the same between v2 and v3
+        2 => Ok(serde_cbor::from_slice(data)?),
         NIP_PROTOCOL_VERSION => Ok(serde_cbor::from_slice(data)?),
         other if other > NIP_PROTOCOL_VERSION => Err(MigrationError::TooNew(other).into()),
         _ => unreachable!(),
@@ -80,13 +95,22 @@ pub fn migrate_object(
 
 #[cfg(test)]
 mod tests {
-    use ipfs_api::IpfsClient;
+    use tokio::runtime::current_thread;
+
+    use std::collections::BTreeMap;
 
     use super::*;
 
+    use crate::{
+        constants::NIP_HEADER_LEN, object::NIPObjectMetadata, remote::NIPRemote,
+        store::InMemoryStore, util::parse_nip_header,
+    };
+
+    use object_v1::NIPObjectV1Metadata;
+
     #[test]
     fn zero_version_test() {
-        let mut ipfs = IpfsClient::default();
+        let mut ipfs = InMemoryStore::new();
         let idx = NIPIndex::from_nip_remote(&"new-ipfs".parse().unwrap(), &mut ipfs).unwrap();
 
         let payload = serde_cbor::to_vec(&idx).unwrap();
@@ -103,7 +127,7 @@ mod tests {
 
     #[test]
     fn too_new_test() {
-        let mut ipfs = IpfsClient::default();
+        let mut ipfs = InMemoryStore::new();
         let idx = NIPIndex::from_nip_remote(&"new-ipfs".parse().unwrap(), &mut ipfs).unwrap();
 
         let payload = serde_cbor::to_vec(&idx).unwrap();
@@ -117,4 +141,110 @@ mod tests {
             panic!("Did not get an error at all");
         }
     }
+
+    #[test]
+    fn v1_index_test() {
+        let mut ipfs = InMemoryStore::new();
+
+        let v1_obj = NIPObjectV1 {
+            raw_data_ipfs_hash: "/ipfs/ValueIrrelevant".to_owned(),
+            metadata: NIPObjectV1Metadata::Blob,
+        };
+        let mut v1_obj_buf = gen_nip_header(Some(1)).unwrap();
+        v1_obj_buf.extend_from_slice(&serde_cbor::to_vec(&v1_obj).unwrap());
+        let v1_obj_hash = current_thread::block_on_all(ipfs.add(v1_obj_buf)).unwrap();
+
+        let mut objects = BTreeMap::new();
+        objects.insert("SomeBlob".to_owned(), v1_obj_hash.clone());
+        objects.insert("SomeSubmodule".to_owned(), SUBMODULE_TIP_MARKER.to_owned());
+        let v1_idx = NIPIndexV1V2 {
+            refs: BTreeMap::new(),
+            objects,
+            prev_idx_hash: None,
+            merged_idx_hash: None,
+        };
+
+        let payload = serde_cbor::to_vec(&v1_idx).unwrap();
+        let idx = migrate_index(payload.as_slice(), 1, &mut ipfs).unwrap();
+
+        assert_eq!(idx.objects["SomeSubmodule"], SUBMODULE_TIP_MARKER);
+        assert_ne!(idx.objects["SomeBlob"], v1_obj_hash);
+
+        let v2_obj = NIPObject::ipfs_get(&idx.objects["SomeBlob"], &mut ipfs).unwrap();
+        assert_eq!(v2_obj.git_hash, "SomeBlob");
+        assert_eq!(v2_obj.raw_data_ipfs_hash, v1_obj.raw_data_ipfs_hash);
+        match v2_obj.metadata {
+            NIPObjectMetadata::Blob => {}
+            other => panic!("Expected blob metadata, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn v2_index_test() {
+        let mut ipfs = InMemoryStore::new();
+
+        let v2_obj = NIPObject {
+            git_hash: "SomeBlob".to_owned(),
+            raw_data_ipfs_hash: "/ipfs/ValueIrrelevant".to_owned(),
+            metadata: NIPObjectMetadata::Blob,
+        };
+        let mut v2_obj_buf = gen_nip_header(Some(2)).unwrap();
+        v2_obj_buf.extend_from_slice(&serde_cbor::to_vec(&v2_obj).unwrap());
+        let v2_obj_hash = current_thread::block_on_all(ipfs.add(v2_obj_buf)).unwrap();
+
+        let mut objects = BTreeMap::new();
+        objects.insert("SomeBlob".to_owned(), v2_obj_hash.clone());
+        objects.insert("SomeSubmodule".to_owned(), SUBMODULE_TIP_MARKER.to_owned());
+        let mut refs = BTreeMap::new();
+        refs.insert("refs/heads/master".to_owned(), "SomeCommit".to_owned());
+        let v2_idx = NIPIndexV1V2 {
+            refs: refs.clone(),
+            objects,
+            prev_idx_hash: Some("/ipfs/SomePrevIdx".to_owned()),
+            merged_idx_hash: Some("/ipfs/SomeMergedIdx".to_owned()),
+        };
+
+        let payload = serde_cbor::to_vec(&v2_idx).unwrap();
+        let idx = migrate_index(payload.as_slice(), 2, &mut ipfs).unwrap();
+
+        assert_eq!(idx.refs, refs);
+        assert_eq!(idx.prev_idx_hash, v2_idx.prev_idx_hash);
+        assert_eq!(idx.merged_idx_hash, v2_idx.merged_idx_hash);
+        assert_eq!(idx.objects["SomeSubmodule"], SUBMODULE_TIP_MARKER);
+        assert_eq!(idx.objects["SomeBlob"], v2_obj_hash);
+
+        // V2 objects are read as they are
+        let obj = NIPObject::ipfs_get(&idx.objects["SomeBlob"], &mut ipfs).unwrap();
+        assert_eq!(obj.git_hash, v2_obj.git_hash);
+        assert_eq!(obj.raw_data_ipfs_hash, v2_obj.raw_data_ipfs_hash);
+    }
+
+    #[test]
+    fn current_version_delta_test() {
+        let mut ipfs = InMemoryStore::new();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+        for i in 0..10 {
+            idx.objects
+                .insert(format!("{:040x}", i), format!("/ipfs/Qm{}", i));
+        }
+        let first = idx.ipfs_add(&mut ipfs, None).unwrap();
+
+        // A small change is published as a delta, which can only be read with a store at hand
+        let mut idx = NIPIndex::from_nip_remote(&first, &mut ipfs).unwrap();
+        idx.refs
+            .insert("refs/heads/master".to_owned(), format!("{:040x}", 0));
+        let second = idx.ipfs_add(&mut ipfs, Some(&first)).unwrap();
+
+        let hash = match second {
+            NIPRemote::ExistingIPFS(ref hash) => hash.clone(),
+            other => panic!("Unexpected remote {:?}", other),
+        };
+        let bytes = ipfs_cat(&hash, &mut ipfs).unwrap();
+        assert_eq!(parse_nip_header(&bytes[..]).unwrap(), NIP_PROTOCOL_VERSION);
+
+        let migrated =
+            migrate_index(&bytes[NIP_HEADER_LEN..], NIP_PROTOCOL_VERSION, &mut ipfs).unwrap();
+        assert_eq!(migrated, idx);
+    }
 }
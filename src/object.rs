@@ -1,14 +1,16 @@
 //! nip object implementation
 use failure::Error;
-use futures::Stream;
-use git2::{Blob, Commit, ObjectType, Odb, OdbObject, Oid, Tag, Tree};
-use ipfs_api::IpfsClient;
+use futures::{future, Future};
+use git2::{Blob, Commit, Object, ObjectType, Odb, OdbObject, Oid, Repository, Tag, Tree};
 use tokio::runtime::current_thread;
 
-use std::{collections::BTreeSet, io::Cursor};
+use std::collections::BTreeSet;
 
 use crate::{
-    constants::{NIP_HEADER_LEN, NIP_PROTOCOL_VERSION},
+    constants::{NIP_HEADER_LEN, NIP_MIN_OBJECT_VERSION, NIP_PROTOCOL_VERSION},
+    error::NIPError,
+    store::{ContentStore, StoreFuture},
+    transfer::TransferOptions,
     util::{gen_nip_header, parse_nip_header},
 };
 
@@ -41,131 +43,219 @@ pub enum NIPObjectMetadata {
 
 impl NIPObject {
     /// Instantiate a `NIPObject` from a blob object.
-    pub fn from_git_blob(blob: &Blob, odb: &Odb, ipfs: &mut IpfsClient) -> Result<Self, Error> {
+    pub fn from_git_blob<S: ContentStore>(
+        blob: &Blob,
+        odb: &Odb,
+        ipfs: &mut S,
+    ) -> Result<Self, Error> {
         let odb_obj = odb.read(blob.id())?;
         let raw_data_ipfs_hash = Self::upload_odb_obj(&odb_obj, ipfs)?;
 
         Ok(Self {
             git_hash: blob.id().to_string(),
             raw_data_ipfs_hash,
-            metadata: NIPObjectMetadata::Blob,
+            metadata: NIPObjectMetadata::from_git_object(blob.as_object())?,
         })
     }
 
     /// Instantiate a `NIPObject` from a commit object.
-    pub fn from_git_commit(
+    pub fn from_git_commit<S: ContentStore>(
         commit: &Commit,
         odb: &Odb,
-        ipfs: &mut IpfsClient,
+        ipfs: &mut S,
     ) -> Result<Self, Error> {
         let odb_obj = odb.read(commit.id())?;
         let raw_data_ipfs_hash = Self::upload_odb_obj(&odb_obj, ipfs)?;
-        let parent_git_hashes: BTreeSet<String> = commit
-            .parent_ids()
-            .map(|parent_id| format!("{}", parent_id))
-            .collect();
-
-        let tree_git_hash = format!("{}", commit.tree()?.id());
 
         Ok(Self {
             git_hash: commit.id().to_string(),
             raw_data_ipfs_hash,
-            metadata: NIPObjectMetadata::Commit {
-                parent_git_hashes,
-                tree_git_hash,
-            },
+            metadata: NIPObjectMetadata::from_git_object(commit.as_object())?,
         })
     }
 
     /// Instantiate a `NIPObject` from an annotated/signed tag object.
-    pub fn from_git_tag(tag: &Tag, odb: &Odb, ipfs: &mut IpfsClient) -> Result<Self, Error> {
+    pub fn from_git_tag<S: ContentStore>(
+        tag: &Tag,
+        odb: &Odb,
+        ipfs: &mut S,
+    ) -> Result<Self, Error> {
         let odb_obj = odb.read(tag.id())?;
         let raw_data_ipfs_hash = Self::upload_odb_obj(&odb_obj, ipfs)?;
 
         Ok(Self {
             git_hash: tag.id().to_string(),
             raw_data_ipfs_hash,
-            metadata: NIPObjectMetadata::Tag {
-                target_git_hash: format!("{}", tag.target_id()),
-            },
+            metadata: NIPObjectMetadata::from_git_object(tag.as_object())?,
         })
     }
 
     /// Instantiate a `NIPObject` from a tree object.
-    pub fn from_git_tree(tree: &Tree, odb: &Odb, ipfs: &mut IpfsClient) -> Result<Self, Error> {
+    pub fn from_git_tree<S: ContentStore>(
+        tree: &Tree,
+        odb: &Odb,
+        ipfs: &mut S,
+    ) -> Result<Self, Error> {
         let odb_obj = odb.read(tree.id())?;
         let raw_data_ipfs_hash = Self::upload_odb_obj(&odb_obj, ipfs)?;
 
-        let entry_git_hashes: BTreeSet<String> =
-            tree.iter().map(|entry| format!("{}", entry.id())).collect();
-
         Ok(Self {
             git_hash: tree.id().to_string(),
             raw_data_ipfs_hash,
-            metadata: NIPObjectMetadata::Tree { entry_git_hashes },
+            metadata: NIPObjectMetadata::from_git_object(tree.as_object())?,
         })
     }
 
     /// Deserialize raw NIPObject bytes
     pub fn from_slice(bytes: &[u8]) -> Result<Self, Error> {
-        let obj_nip_proto_version = parse_nip_header(&bytes)?;
+        let obj_nip_proto_version = parse_nip_header(bytes)?;
 
-        if obj_nip_proto_version != NIP_PROTOCOL_VERSION {
+        if !(NIP_MIN_OBJECT_VERSION..=NIP_PROTOCOL_VERSION).contains(&obj_nip_proto_version) {
             bail!(
-                "Unsupported protocol version {} (We're at {})",
+                "Unsupported protocol version {} (We're at {}, reading objects from {} on)",
                 obj_nip_proto_version,
-                NIP_PROTOCOL_VERSION
+                NIP_PROTOCOL_VERSION,
+                NIP_MIN_OBJECT_VERSION
             );
         }
 
         Ok(serde_cbor::from_slice(&bytes[NIP_HEADER_LEN..])?)
     }
 
-    /// Download from IPFS and instantiate a `NIPObject`.
-    pub fn ipfs_get(hash: &str, ipfs: &mut IpfsClient) -> Result<Self, Error> {
-        let object_bytes_req = ipfs.cat(hash).concat2();
+    /// Download from `ipfs` and instantiate a `NIPObject`.
+    pub fn ipfs_get<S: ContentStore>(hash: &str, ipfs: &mut S) -> Result<Self, Error> {
+        current_thread::block_on_all(Self::ipfs_get_async(hash, ipfs))
+    }
 
-        let object_bytes: Vec<u8> = current_thread::block_on_all(object_bytes_req)?
-            .into_iter()
-            .collect();
+    /// `ipfs_get()` with custom transfer options. The download is cached according to
+    /// `opts.cache`, with `repo` standing in for the local repository.
+    pub fn ipfs_get_with_opts<S: ContentStore>(
+        hash: &str,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<Self, Error> {
+        let mut ipfs = opts.cache.wrap(ipfs.clone(), repo)?;
+        Self::ipfs_get(hash, &mut ipfs)
+    }
 
-        Ok(Self::from_slice(&object_bytes[..])?)
+    /// A non-blocking version of `ipfs_get()`
+    pub fn ipfs_get_async<S: ContentStore>(hash: &str, ipfs: &mut S) -> StoreFuture<Self> {
+        Box::new(
+            ipfs.cat(hash)
+                .and_then(|object_bytes| Self::from_slice(&object_bytes[..])),
+        )
     }
 
-    /// Put `self` on IPFS and return the link.
-    pub fn ipfs_add(&self, ipfs: &mut IpfsClient) -> Result<String, Error> {
+    /// Serialize `self` into header-prefixed bytes understood by `from_slice()`
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
         let mut self_buf = gen_nip_header(None)?;
 
         self_buf.extend_from_slice(&serde_cbor::to_vec(self)?);
 
-        let req = ipfs.add(Cursor::new(self_buf));
-        let ipfs_hash = format!("/ipfs/{}", current_thread::block_on_all(req)?.hash);
+        Ok(self_buf)
+    }
+
+    /// Put `self` on `ipfs` and return the link.
+    pub fn ipfs_add<S: ContentStore>(&self, ipfs: &mut S) -> Result<String, Error> {
+        current_thread::block_on_all(self.ipfs_add_async(ipfs))
+    }
 
-        Ok(ipfs_hash)
+    /// A non-blocking version of `ipfs_add()`
+    pub fn ipfs_add_async<S: ContentStore>(&self, ipfs: &mut S) -> StoreFuture<String> {
+        match self.to_vec() {
+            Ok(self_buf) => ipfs.add(self_buf),
+            Err(e) => Box::new(future::err(e)),
+        }
     }
 
-    /// Upload `odb_obj` to IPFS and return the link.
-    fn upload_odb_obj(odb_obj: &OdbObject, ipfs: &mut IpfsClient) -> Result<String, Error> {
+    /// Upload `odb_obj` to `ipfs` and return the link.
+    fn upload_odb_obj<S: ContentStore>(odb_obj: &OdbObject, ipfs: &mut S) -> Result<String, Error> {
         let obj_buf = odb_obj.data().to_vec();
 
-        let raw_data_req = ipfs.add(Cursor::new(obj_buf));
-        let ipfs_hash = current_thread::block_on_all(raw_data_req)?.hash;
-        Ok(format!("/ipfs/{}", ipfs_hash))
+        let raw_data_req = ipfs.add(obj_buf);
+        current_thread::block_on_all(raw_data_req)
     }
 
-    /// Download `self.raw_data_ipfs_hash` from IPFS and use it to instantiate `self` in `odb`.
-    pub fn write_raw_data(&self, odb: &mut Odb, ipfs: &mut IpfsClient) -> Result<Oid, Error> {
-        let req = ipfs.cat(&self.raw_data_ipfs_hash).concat2();
+    /// Download `self.raw_data_ipfs_hash` from `ipfs` and use it to instantiate `self` in `odb`.
+    /// Blocking only; `NIPIndex::fetch_refs_async()` covers it inside a tokio runtime.
+    pub fn write_raw_data<S: ContentStore>(
+        &self,
+        odb: &mut Odb,
+        ipfs: &mut S,
+    ) -> Result<Oid, Error> {
+        let req = ipfs.cat(&self.raw_data_ipfs_hash);
 
         let bytes = current_thread::block_on_all(req)?;
 
-        let obj_type = match self.metadata {
+        Ok(odb.write(self.metadata.object_type(), &bytes)?)
+    }
+
+    /// `write_raw_data()` into `repo` with custom transfer options. The download is cached
+    /// according to `opts.cache`.
+    pub fn write_raw_data_with_opts<S: ContentStore>(
+        &self,
+        repo: &Repository,
+        ipfs: &mut S,
+        opts: &TransferOptions,
+    ) -> Result<Oid, Error> {
+        let mut ipfs = opts.cache.wrap(ipfs.clone(), repo)?;
+        self.write_raw_data(&mut repo.odb()?, &mut ipfs)
+    }
+}
+
+impl NIPObjectMetadata {
+    /// Describe `obj`'s relationship with other git objects.
+    pub fn from_git_object(obj: &Object) -> Result<Self, Error> {
+        match obj.kind() {
+            Some(ObjectType::Commit) => {
+                let commit = obj
+                    .as_commit()
+                    .ok_or_else(|| format_err!("Could not view {:?} as a commit", obj))?;
+
+                Ok(NIPObjectMetadata::Commit {
+                    parent_git_hashes: commit
+                        .parent_ids()
+                        .map(|parent_id| format!("{}", parent_id))
+                        .collect(),
+                    tree_git_hash: format!("{}", commit.tree_id()),
+                })
+            }
+            Some(ObjectType::Tag) => {
+                let tag = obj
+                    .as_tag()
+                    .ok_or_else(|| format_err!("Could not view {:?} as a tag", obj))?;
+
+                Ok(NIPObjectMetadata::Tag {
+                    target_git_hash: format!("{}", tag.target_id()),
+                })
+            }
+            Some(ObjectType::Tree) => {
+                let tree = obj
+                    .as_tree()
+                    .ok_or_else(|| format_err!("Could not view {:?} as a tree", obj))?;
+
+                Ok(NIPObjectMetadata::Tree {
+                    entry_git_hashes: tree.iter().map(|entry| format!("{}", entry.id())).collect(),
+                })
+            }
+            Some(ObjectType::Blob) => Ok(NIPObjectMetadata::Blob),
+            other => Err(NIPError::InternalError(format!(
+                "Don't know how to describe a {:?} ({})",
+                other,
+                obj.id()
+            ))
+            .into()),
+        }
+    }
+
+    /// Returns the git object type this metadata describes
+    pub fn object_type(&self) -> ObjectType {
+        match self {
             NIPObjectMetadata::Blob => ObjectType::Blob,
             NIPObjectMetadata::Commit { .. } => ObjectType::Commit,
             NIPObjectMetadata::Tag { .. } => ObjectType::Tag,
             NIPObjectMetadata::Tree { .. } => ObjectType::Tree,
-        };
-
-        Ok(odb.write(obj_type, &bytes)?)
+        }
     }
 }
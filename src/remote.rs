@@ -1,7 +1,11 @@
 //! nip remote implementation
 use failure::Error;
 
-use std::{str::FromStr, string::ToString};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    string::ToString,
+};
 
 use crate::constants::IPFS_HASH_LEN;
 
@@ -16,6 +20,10 @@ pub enum NIPRemote {
     NewIPFS,
     /// Same as `NewIPFS` except for IPNS
     NewIPNS,
+    /// An index stored in a local `FsStore` directory; `file:///path#<hash>`
+    ExistingFile(PathBuf, String),
+    /// Same as `NewIPFS` except for a local `FsStore` directory; `file:///path`
+    NewFile(PathBuf),
 }
 
 #[derive(Debug, Fail, PartialEq)]
@@ -34,7 +42,18 @@ impl NIPRemote {
     pub fn is_ipns(&self) -> bool {
         match self {
             NIPRemote::NewIPNS | NIPRemote::ExistingIPNS(_) => true,
-            NIPRemote::NewIPFS | NIPRemote::ExistingIPFS(_) => false,
+            NIPRemote::NewIPFS
+            | NIPRemote::ExistingIPFS(_)
+            | NIPRemote::NewFile(_)
+            | NIPRemote::ExistingFile(..) => false,
+        }
+    }
+
+    /// Return the `FsStore` directory if `self` refers to a `*File` variant
+    pub fn file_path(&self) -> Option<&Path> {
+        match self {
+            NIPRemote::NewFile(ref path) | NIPRemote::ExistingFile(ref path, _) => Some(path),
+            _ => None,
         }
     }
 
@@ -55,8 +74,10 @@ impl NIPRemote {
     /// ```
     pub fn get_hash(&self) -> Option<String> {
         match self {
-            NIPRemote::NewIPFS | NIPRemote::NewIPNS => None,
-            NIPRemote::ExistingIPFS(_) | NIPRemote::ExistingIPNS(_) => Some(self.to_string()),
+            NIPRemote::NewIPFS | NIPRemote::NewIPNS | NIPRemote::NewFile(_) => None,
+            NIPRemote::ExistingIPFS(_)
+            | NIPRemote::ExistingIPNS(_)
+            | NIPRemote::ExistingFile(..) => Some(self.to_string()),
         }
     }
 }
@@ -90,6 +111,26 @@ impl FromStr for NIPRemote {
                 }
                 Ok(NIPRemote::ExistingIPNS(hash.to_owned()))
             }
+            file if file.starts_with("file://") => {
+                let mut parts = file["file://".len()..].splitn(2, '#');
+                let path = parts
+                    .next()
+                    .filter(|path| !path.is_empty())
+                    .ok_or_else(|| NIPRemoteParseError::InvalidLinkFormat(file.to_owned()))?;
+                match parts.next() {
+                    Some(hash) => {
+                        if hash.len() != IPFS_HASH_LEN {
+                            return Err(NIPRemoteParseError::InvalidHashLength(
+                                hash.len(),
+                                IPFS_HASH_LEN,
+                            )
+                            .into());
+                        }
+                        Ok(NIPRemote::ExistingFile(path.into(), hash.to_owned()))
+                    }
+                    None => Ok(NIPRemote::NewFile(path.into())),
+                }
+            }
             other => Err(NIPRemoteParseError::InvalidLinkFormat(other.to_owned()).into()),
         }
     }
@@ -102,6 +143,10 @@ impl ToString for NIPRemote {
             NIPRemote::ExistingIPNS(ref hash) => format!("/ipns/{}", hash),
             NIPRemote::NewIPFS => "new-ipfs".to_owned(),
             NIPRemote::NewIPNS => "new-ipns".to_owned(),
+            NIPRemote::ExistingFile(ref path, ref hash) => {
+                format!("file://{}#{}", path.display(), hash)
+            }
+            NIPRemote::NewFile(ref path) => format!("file://{}", path.display()),
         }
     }
 }
@@ -120,6 +165,24 @@ mod tests {
         assert_eq!("new-ipns".parse::<NIPRemote>().unwrap(), NIPRemote::NewIPNS);
     }
 
+    #[test]
+    fn test_parses_file() {
+        let existing = "file:///mnt/usb/repo#QmdT2sVhj8UicZsGY7x687FgdJPrzR9idGyavi5282CPH3";
+        assert_eq!(
+            existing.parse::<NIPRemote>().unwrap(),
+            NIPRemote::ExistingFile(
+                "/mnt/usb/repo".into(),
+                "QmdT2sVhj8UicZsGY7x687FgdJPrzR9idGyavi5282CPH3".to_owned()
+            )
+        );
+        assert_eq!(existing.parse::<NIPRemote>().unwrap().to_string(), existing);
+
+        assert_eq!(
+            "file:///mnt/usb/repo".parse::<NIPRemote>().unwrap(),
+            NIPRemote::NewFile("/mnt/usb/repo".into())
+        );
+    }
+
     #[test]
     fn test_invalid_link_err() {
         match "gibberish".parse::<NIPRemote>() {
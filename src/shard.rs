@@ -0,0 +1,323 @@
+//! Storage of big index object maps as a trie of shards keyed by git hash prefixes
+use super::serde_cbor;
+
+use failure::Error;
+use futures::{
+    future::{self, Loop},
+    stream, Future, Stream,
+};
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    constants::{
+        DEFAULT_MAX_IN_FLIGHT, MAX_SHARD_OBJECTS, NIP_HEADER_LEN, NIP_MIN_OBJECT_VERSION,
+        NIP_PROTOCOL_VERSION,
+    },
+    error::NIPError,
+    store::{cid_v0, ContentStore, StoreFuture},
+    util::{gen_nip_header, parse_nip_header},
+};
+
+/// Git hashes are 40 hex digits long; the trie never gets deeper than that
+const GIT_HASH_LEN: usize = 40;
+
+/// A node of the object trie
+#[derive(Debug, Deserialize, Serialize)]
+enum ObjectShard {
+    /// A slice of the object map; a {sha1 -> IPFS hash} map
+    Leaf(BTreeMap<String, String>),
+    /// Child shards by the next character of the git hashes they hold; a {character -> IPFS
+    /// hash} map
+    Branch(BTreeMap<String, String>),
+}
+
+/// The links of the shards making up an index's object trie; a {content digest -> IPFS hash}
+/// map letting `store_objects()` skip the shards that didn't change
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ShardCache(Arc<BTreeMap<String, String>>);
+
+/// A shard waiting for upload; branches name their children by trie path
+enum PlannedShard {
+    Leaf(BTreeMap<String, String>),
+    Branch(Vec<(String, String)>),
+}
+
+/// Store `objects` as a trie of shards holding at most `MAX_SHARD_OBJECTS` objects each and
+/// resolve to the root shard's link along with the cache of the new trie. Shards found in `cache`
+/// aren't uploaded again.
+pub(crate) fn store_objects<S: ContentStore>(
+    objects: &BTreeMap<String, String>,
+    cache: &ShardCache,
+    ipfs: &mut S,
+) -> StoreFuture<(String, ShardCache)> {
+    // Children have to be stored before their parents learn their links, so go deepest first
+    let mut levels = Vec::new();
+    let entries = objects
+        .iter()
+        .map(|(git_hash, link)| (git_hash.clone(), link.clone()))
+        .collect();
+    plan_shard(entries, String::new(), &mut levels);
+    debug!(
+        "Storing {} object(s) in a {}-level shard trie",
+        objects.len(),
+        levels.len()
+    );
+
+    let cache = cache.0.clone();
+    let ipfs = ipfs.clone();
+
+    Box::new(
+        future::loop_fn(
+            (levels, BTreeMap::new(), BTreeMap::new()),
+            move |(mut levels, mut paths, mut trie_links)| -> StoreFuture<_> {
+                let level = match levels.pop() {
+                    Some(level) => level,
+                    None => return Box::new(future::ok(Loop::Break((paths, trie_links)))),
+                };
+
+                let encoded = level
+                    .into_iter()
+                    .map(|(path, planned)| {
+                        let bytes = encode_shard(&to_shard(planned, &paths)?)?;
+                        Ok((path, cid_v0(&bytes)?, bytes))
+                    })
+                    .collect::<Result<Vec<_>, Error>>();
+                let encoded = match encoded {
+                    Ok(encoded) => encoded,
+                    Err(e) => return Box::new(future::err(e)),
+                };
+
+                let cache = cache.clone();
+                let mut ipfs = ipfs.clone();
+                let stored = stream::iter_ok(encoded)
+                    .map(move |(path, digest, bytes)| -> StoreFuture<_> {
+                        match cache.get(&digest) {
+                            Some(link) => {
+                                trace!("Shard {:?} unchanged", path);
+                                Box::new(future::ok((path, digest, link.clone())))
+                            }
+                            None => {
+                                trace!("Uploading shard {:?}", path);
+                                Box::new(ipfs.add(bytes).map(|link| (path, digest, link)))
+                            }
+                        }
+                    })
+                    .buffer_unordered(DEFAULT_MAX_IN_FLIGHT)
+                    .collect();
+
+                Box::new(stored.map(move |stored| {
+                    for (path, digest, link) in stored {
+                        trie_links.insert(digest, link.clone());
+                        paths.insert(path, link);
+                    }
+                    Loop::Continue((levels, paths, trie_links))
+                }))
+            },
+        )
+        .and_then(|(mut paths, trie_links)| {
+            let root = paths
+                .remove("")
+                .ok_or_else(|| NIPError::InternalError("Shard trie has no root".to_owned()))?;
+
+            Ok((root, ShardCache(Arc::new(trie_links))))
+        }),
+    )
+}
+
+/// Download the object trie rooted at `root` and resolve to the objects in it along with the
+/// trie's cache
+pub(crate) fn load_objects<S: ContentStore>(
+    root: &str,
+    ipfs: &mut S,
+) -> StoreFuture<(BTreeMap<String, String>, ShardCache)> {
+    let ipfs = ipfs.clone();
+
+    Box::new(
+        future::loop_fn(
+            (vec![root.to_owned()], BTreeMap::new(), BTreeMap::new()),
+            move |(frontier, mut objects, mut trie_links)| -> StoreFuture<_> {
+                if frontier.is_empty() {
+                    return Box::new(future::ok(Loop::Break((objects, trie_links))));
+                }
+
+                let mut ipfs = ipfs.clone();
+                let downloaded = stream::iter_ok(frontier)
+                    .map(move |link| ipfs.cat(&link).map(|bytes| (link, bytes)))
+                    .buffer_unordered(DEFAULT_MAX_IN_FLIGHT)
+                    .collect();
+
+                Box::new(downloaded.and_then(move |downloaded| {
+                    let mut next_frontier = Vec::new();
+                    for (link, bytes) in downloaded {
+                        match decode_shard(&bytes)? {
+                            ObjectShard::Leaf(leaf) => objects.extend(leaf),
+                            ObjectShard::Branch(children) => {
+                                next_frontier.extend(children.values().cloned())
+                            }
+                        }
+                        trie_links.insert(cid_v0(&bytes)?, link);
+                    }
+
+                    Ok(Loop::Continue((next_frontier, objects, trie_links)))
+                }))
+            },
+        )
+        .map(|(objects, trie_links)| {
+            debug!(
+                "Loaded {} object(s) from {} shard(s)",
+                objects.len(),
+                trie_links.len()
+            );
+            (objects, ShardCache(Arc::new(trie_links)))
+        }),
+    )
+}
+
+/// Split `entries` into shards below trie `path`, adding them to `levels` by depth
+fn plan_shard(
+    entries: Vec<(String, String)>,
+    path: String,
+    levels: &mut Vec<Vec<(String, PlannedShard)>>,
+) {
+    let depth = path.len();
+    if levels.len() <= depth {
+        levels.push(Vec::new());
+    }
+
+    if entries.len() <= MAX_SHARD_OBJECTS || depth >= GIT_HASH_LEN {
+        levels[depth].push((path, PlannedShard::Leaf(entries.into_iter().collect())));
+        return;
+    }
+
+    let mut buckets: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    for (git_hash, link) in entries {
+        let key = git_hash.get(depth..=depth).unwrap_or("").to_owned();
+        buckets.entry(key).or_default().push((git_hash, link));
+    }
+
+    let mut children = Vec::with_capacity(buckets.len());
+    for (key, entries) in buckets {
+        if key.is_empty() {
+            // Hashes too short to tell apart any further stay together one level down
+            let child_path = format!("{}.", path);
+            if levels.len() <= depth + 1 {
+                levels.push(Vec::new());
+            }
+            levels[depth + 1].push((
+                child_path.clone(),
+                PlannedShard::Leaf(entries.into_iter().collect()),
+            ));
+            children.push((key, child_path));
+        } else {
+            let child_path = format!("{}{}", path, key);
+            plan_shard(entries, child_path.clone(), levels);
+            children.push((key, child_path));
+        }
+    }
+
+    levels[depth].push((path, PlannedShard::Branch(children)));
+}
+
+/// Turn `planned` into a shard, looking its children's links up in `paths`
+fn to_shard(planned: PlannedShard, paths: &BTreeMap<String, String>) -> Result<ObjectShard, Error> {
+    match planned {
+        PlannedShard::Leaf(leaf) => Ok(ObjectShard::Leaf(leaf)),
+        PlannedShard::Branch(children) => {
+            let mut branch = BTreeMap::new();
+            for (key, child_path) in children {
+                let link = paths.get(&child_path).ok_or_else(|| {
+                    NIPError::InternalError(format!("Shard {:?} wasn't stored", child_path))
+                })?;
+                branch.insert(key, link.clone());
+            }
+
+            Ok(ObjectShard::Branch(branch))
+        }
+    }
+}
+
+fn encode_shard(shard: &ObjectShard) -> Result<Vec<u8>, Error> {
+    let mut buf = gen_nip_header(None)?;
+    buf.extend_from_slice(&serde_cbor::to_vec(shard)?);
+
+    Ok(buf)
+}
+
+fn decode_shard(bytes: &[u8]) -> Result<ObjectShard, Error> {
+    let protocol_version = parse_nip_header(bytes)?;
+    if !(NIP_MIN_OBJECT_VERSION..=NIP_PROTOCOL_VERSION).contains(&protocol_version) {
+        return Err(NIPError::InvalidVersion(protocol_version).into());
+    }
+
+    Ok(serde_cbor::from_slice(&bytes[NIP_HEADER_LEN..])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::current_thread;
+
+    use super::*;
+
+    use crate::store::InMemoryStore;
+
+    /// Returns `n` objects spread evenly over the trie
+    fn fake_objects(n: usize) -> BTreeMap<String, String> {
+        (0..n as u64)
+            .map(|i| {
+                let git_hash = format!("{:016x}{:024x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15), i);
+                (git_hash, format!("/ipfs/Qm{}", i))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_small_maps_fit_one_shard() {
+        let mut ipfs = InMemoryStore::new();
+        let objects = fake_objects(10);
+
+        let (root, cache) = current_thread::block_on_all(store_objects(
+            &objects,
+            &ShardCache::default(),
+            &mut ipfs,
+        ))
+        .unwrap();
+        assert_eq!(ipfs.len(), 1);
+        assert_eq!(cache.0.len(), 1);
+
+        let (loaded, _) = current_thread::block_on_all(load_objects(&root, &mut ipfs)).unwrap();
+        assert_eq!(loaded, objects);
+    }
+
+    #[test]
+    fn test_only_changed_shards_are_uploaded() {
+        let mut ipfs = InMemoryStore::new();
+        let mut objects = fake_objects(MAX_SHARD_OBJECTS + 1);
+
+        let (root, cache) = current_thread::block_on_all(store_objects(
+            &objects,
+            &ShardCache::default(),
+            &mut ipfs,
+        ))
+        .unwrap();
+        // A root branch above one leaf per hex digit
+        assert_eq!(ipfs.len(), 17);
+
+        let (loaded, loaded_cache) =
+            current_thread::block_on_all(load_objects(&root, &mut ipfs)).unwrap();
+        assert_eq!(loaded, objects);
+        assert_eq!(loaded_cache.0, cache.0);
+
+        // One changed leaf and the root above it
+        objects.insert("f".repeat(40), "/ipfs/QmNew".to_owned());
+        let len_before = ipfs.len();
+        let (new_root, new_cache) =
+            current_thread::block_on_all(store_objects(&objects, &loaded_cache, &mut ipfs))
+                .unwrap();
+        assert_eq!(ipfs.len(), len_before + 2);
+        assert_eq!(new_cache.0.len(), 17);
+
+        let (loaded, _) = current_thread::block_on_all(load_objects(&new_root, &mut ipfs)).unwrap();
+        assert_eq!(loaded, objects);
+    }
+}
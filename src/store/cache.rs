@@ -0,0 +1,653 @@
+//! A persistent on-disk cache for immutable content store data
+use failure::Error;
+use futures::{future, Future};
+use git2::Repository;
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use super::{checked_hash, cid_v0, ContentStore, StoreError, StoreFuture};
+use crate::{
+    constants::{DEFAULT_CACHE_MAX_BYTES, IPFS_HASH_LEN},
+    util::write_atomic,
+};
+
+/// How stale a cached file's modification time may get before a cache hit refreshes it
+const TOUCH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How old a temporary file must be before it's considered a leftover of an interrupted write
+/// rather than one in progress in another process
+const STALE_TMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The size of the chunks go-ipfs splits added files into by default
+const UNIXFS_CHUNK_SIZE: usize = 256 * 1024;
+
+/// A size-limited directory of immutable blobs keyed by their IPFS hash. When the limit is
+/// exceeded, the least recently used entries are evicted first; recency survives restarts through
+/// file modification times.
+///
+/// Only bare or `/ipfs/`-prefixed CIDv0 hashes are accepted as keys, and blobs are only stored
+/// if their content hashes to the key. Files this instance didn't write itself are checked the
+/// same way before they're first served and deleted if they don't match. Clones share the same
+/// cache.
+#[derive(Clone, Debug)]
+pub struct ObjectCache {
+    state: Arc<Mutex<CacheState>>,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// All cached blobs; a {bare hash -> entry} map
+    entries: HashMap<String, CacheEntry>,
+    total_bytes: u64,
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    size: u64,
+    last_used: SystemTime,
+    /// The modification time of the file on disk
+    touched: SystemTime,
+    /// Whether the file's content is known to hash to its name
+    verified: bool,
+}
+
+impl ObjectCache {
+    /// Open the cache in `dir`, creating it if necessary. The cache will hold at most
+    /// `max_bytes` of data.
+    pub fn open<P: AsRef<Path>>(dir: P, max_bytes: u64) -> Result<Self, Error> {
+        Ok(Self {
+            state: Arc::new(Mutex::new(CacheState::open(dir.as_ref(), max_bytes)?)),
+        })
+    }
+
+    /// Open the default cache for `repo`, i.e. `.git/nip/cache` limited to
+    /// `DEFAULT_CACHE_MAX_BYTES`.
+    pub fn for_repo(repo: &Repository) -> Result<Self, Error> {
+        Self::for_repo_with_max_bytes(repo, DEFAULT_CACHE_MAX_BYTES)
+    }
+
+    /// Open the default cache directory of `repo` with a size limit of `max_bytes`
+    pub fn for_repo_with_max_bytes(repo: &Repository, max_bytes: u64) -> Result<Self, Error> {
+        Self::open(repo.path().join("nip").join("cache"), max_bytes)
+    }
+
+    /// Returns the cached bytes behind `link`, if any
+    pub fn get(&self, link: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.lock().get(link)
+    }
+
+    /// Store `data` under `link`, evicting least recently used entries if needed. Blobs larger
+    /// than the whole cache are skipped. Fails with `StoreError::HashMismatch` if `data` isn't
+    /// what `link` refers to.
+    pub fn insert(&self, link: &str, data: &[u8]) -> Result<(), Error> {
+        self.lock().insert(link, data)
+    }
+
+    /// Returns true if `link` is cached
+    pub fn contains(&self, link: &str) -> bool {
+        cache_key(link)
+            .map(|hash| self.lock().entries.contains_key(hash))
+            .unwrap_or(false)
+    }
+
+    /// Returns the combined size of all cached blobs
+    pub fn total_bytes(&self) -> u64 {
+        self.lock().total_bytes
+    }
+
+    /// The state is only ever changed after the matching file operation succeeded, so it stays
+    /// usable after a panic elsewhere.
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl CacheState {
+    fn open(dir: &Path, max_bytes: u64) -> Result<Self, Error> {
+        let dir = dir.to_owned();
+        fs::create_dir_all(&dir)?;
+
+        let mut entries = HashMap::new();
+        let mut total_bytes = 0;
+        for dir_entry in fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let file_name = dir_entry.file_name().to_string_lossy().into_owned();
+
+            // Temporary files of `write_atomic()`; other processes may still be writing them
+            if file_name.ends_with(".tmp") {
+                let age = dir_entry
+                    .metadata()?
+                    .modified()?
+                    .elapsed()
+                    .unwrap_or_default();
+                if age > STALE_TMP_AGE {
+                    debug!("Removing stale temporary file {:?}", file_name);
+                    match fs::remove_file(dir_entry.path()) {
+                        Ok(()) => {}
+                        Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                continue;
+            }
+
+            // Not something `insert()` would have written
+            if cache_key(&file_name).is_err() {
+                warn!("Ignoring stray file {:?} in object cache", file_name);
+                continue;
+            }
+
+            let metadata = dir_entry.metadata()?;
+            total_bytes += metadata.len();
+            entries.insert(
+                file_name,
+                CacheEntry {
+                    size: metadata.len(),
+                    last_used: metadata.modified()?,
+                    touched: metadata.modified()?,
+                    verified: false,
+                },
+            );
+        }
+
+        let mut cache = Self {
+            dir,
+            max_bytes,
+            entries,
+            total_bytes,
+        };
+        cache.evict()?;
+        debug!(
+            "Opened object cache at {} ({} entries, {} bytes)",
+            cache.dir.display(),
+            cache.entries.len(),
+            cache.total_bytes
+        );
+
+        Ok(cache)
+    }
+
+    fn get(&mut self, link: &str) -> Result<Option<Vec<u8>>, Error> {
+        let hash = cache_key(link)?;
+        let path = self.dir.join(hash);
+
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                if self.remove_entry(hash).is_some() {
+                    warn!("Object cache entry {} vanished from disk", hash);
+                }
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Files found on open or written by other processes may have been damaged or planted
+        let verified = self.entries.get(hash).map_or(false, |entry| entry.verified);
+        if !verified && !is_content_of(hash, &bytes)? {
+            warn!("Removing corrupted object cache entry {}", hash);
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.remove_entry(hash);
+            return Ok(None);
+        }
+
+        let now = SystemTime::now();
+        if !self.entries.contains_key(hash) {
+            debug!("Tracking object cache entry {} written elsewhere", hash);
+            let size = bytes.len() as u64;
+            self.total_bytes += size;
+            self.entries.insert(
+                hash.to_owned(),
+                CacheEntry {
+                    size,
+                    last_used: now,
+                    touched: now,
+                    verified: true,
+                },
+            );
+            self.evict()?;
+        }
+        if let Some(entry) = self.entries.get_mut(hash) {
+            entry.last_used = now;
+            entry.verified = true;
+
+            // Rewriting is the only portable way to bump the modification time, so it's only
+            // done once in a while
+            let stale = now
+                .duration_since(entry.touched)
+                .map(|age| age > TOUCH_INTERVAL)
+                .unwrap_or(false);
+            if stale {
+                write_atomic(&path, &bytes)?;
+                entry.touched = now;
+            }
+        }
+
+        Ok(Some(bytes))
+    }
+
+    fn insert(&mut self, link: &str, data: &[u8]) -> Result<(), Error> {
+        let hash = cache_key(link)?.to_owned();
+        let size = data.len() as u64;
+
+        if !is_content_of(&hash, data)? {
+            return Err(StoreError::HashMismatch(link.to_owned()).into());
+        }
+
+        if size > self.max_bytes {
+            debug!("Not caching {}: {} bytes exceed the cache size", hash, size);
+            return Ok(());
+        }
+
+        if self.entries.contains_key(&hash) {
+            return Ok(());
+        }
+
+        write_atomic(&self.dir.join(&hash), data)?;
+        self.total_bytes += size;
+        let now = SystemTime::now();
+        self.entries.insert(
+            hash,
+            CacheEntry {
+                size,
+                last_used: now,
+                touched: now,
+                verified: true,
+            },
+        );
+
+        self.evict()
+    }
+
+    /// Drop least recently used entries until the cache fits in `max_bytes`
+    fn evict(&mut self) -> Result<(), Error> {
+        if self.total_bytes <= self.max_bytes {
+            return Ok(());
+        }
+
+        let mut by_age: Vec<(String, SystemTime)> = self
+            .entries
+            .iter()
+            .map(|(hash, entry)| (hash.clone(), entry.last_used))
+            .collect();
+        by_age.sort_by_key(|(_, last_used)| *last_used);
+
+        for (hash, _) in by_age {
+            if self.total_bytes <= self.max_bytes {
+                break;
+            }
+            trace!("Evicting {} from object cache", hash);
+
+            match fs::remove_file(self.dir.join(&hash)) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.remove_entry(&hash);
+        }
+
+        Ok(())
+    }
+
+    fn remove_entry(&mut self, hash: &str) -> Option<CacheEntry> {
+        let entry = self.entries.remove(hash)?;
+        self.total_bytes -= entry.size;
+
+        Some(entry)
+    }
+}
+
+/// Returns the bare hash of `link` if it's a CIDv0 hash, the only kind of link whose content the
+/// cache can verify. IPNS names are mutable and never make a key, even if they look like one.
+fn cache_key(link: &str) -> Result<&str, Error> {
+    if link.starts_with("/ipns/") {
+        return Err(StoreError::InvalidLink(link.to_owned()).into());
+    }
+
+    let hash = checked_hash(link)?;
+    if hash.len() != IPFS_HASH_LEN || !hash.starts_with("Qm") {
+        return Err(StoreError::InvalidLink(link.to_owned()).into());
+    }
+
+    Ok(hash)
+}
+
+/// Returns true if `hash` is the CIDv0 of `data`, either as a raw SHA2-256 digest (`cid_v0()`, as
+/// used by `FsStore` and `InMemoryStore`) or as the single-chunk UnixFS file go-ipfs makes of it.
+/// Data spanning several UnixFS chunks can't be verified without the whole DAG and is reported as
+/// not matching.
+fn is_content_of(hash: &str, data: &[u8]) -> Result<bool, Error> {
+    if cid_v0(data)? == hash {
+        return Ok(true);
+    }
+    if data.len() > UNIXFS_CHUNK_SIZE {
+        return Ok(false);
+    }
+
+    // A protobuf PBNode { Data: unixfs.Data { Type: File, Data: data, filesize } } with no links
+    let mut unixfs = vec![0x08, 0x02];
+    if !data.is_empty() {
+        unixfs.push(0x12);
+        push_varint(&mut unixfs, data.len());
+        unixfs.extend_from_slice(data);
+    }
+    unixfs.push(0x18);
+    push_varint(&mut unixfs, data.len());
+
+    let mut node = vec![0x0a];
+    push_varint(&mut node, unixfs.len());
+    node.extend_from_slice(&unixfs);
+
+    Ok(cid_v0(&node)? == hash)
+}
+
+/// Append `value` to `buf` as a protobuf varint
+fn push_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// A `ContentStore` wrapper that serves `cat()` requests from an `ObjectCache` whenever possible
+/// and caches everything it downloads. Names are mutable and always go to the wrapped store.
+/// The `*_with_opts()` download calls wrap their store according to `TransferOptions::cache` by
+/// themselves; wrapping by hand extends caching to everything else, e.g. merges.
+///
+/// ```rust,no_run
+/// # extern crate failure;
+/// # extern crate git2;
+/// # extern crate ipfs_api;
+/// # extern crate nip_core;
+/// # use failure::Error;
+/// # use git2::Repository;
+/// # use ipfs_api::IpfsClient;
+/// # use nip_core::{CachedStore, NIPIndex, ObjectCache};
+/// # fn main() -> Result<(), Error> {
+/// let repo = Repository::open_from_env()?;
+/// let mut ipfs = CachedStore::new(IpfsClient::default(), ObjectCache::for_repo(&repo)?);
+///
+/// // The index is now cached in .git/nip/cache along with the objects fetched through it
+/// let idx = NIPIndex::from_nip_remote(&"/ipfs/QmdT2sVhj8UicZsGY7x687FgdJPrzR9idGyavi5282CPH3".parse()?, &mut ipfs)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CachedStore<S> {
+    inner: S,
+    /// `None` if caching is disabled
+    cache: Option<ObjectCache>,
+}
+
+impl<S: ContentStore> CachedStore<S> {
+    /// Wrap `inner` with `cache`
+    pub fn new(inner: S, cache: ObjectCache) -> Self {
+        Self {
+            inner,
+            cache: Some(cache),
+        }
+    }
+
+    /// Wrap `inner` with the default cache of `repo`, see `ObjectCache::for_repo()`
+    pub fn for_repo(inner: S, repo: &Repository) -> Result<Self, Error> {
+        Ok(Self::new(inner, ObjectCache::for_repo(repo)?))
+    }
+
+    /// Unwrap the underlying store
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: ContentStore> ContentStore for CachedStore<S> {
+    fn add(&mut self, data: Vec<u8>) -> StoreFuture<String> {
+        self.inner.add(data)
+    }
+
+    fn cat(&mut self, link: &str) -> StoreFuture<Vec<u8>> {
+        let cache = match self.cache {
+            // IPNS paths aren't immutable and other links can't be verified
+            Some(ref cache) if cache_key(link).is_ok() => cache.clone(),
+            _ => return self.inner.cat(link),
+        };
+
+        match cache.get(link) {
+            Ok(Some(bytes)) => {
+                trace!("Object cache hit for {}", link);
+                return Box::new(future::ok(bytes));
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Could not read {} from object cache: {}", link, e),
+        }
+
+        let link = link.to_owned();
+        Box::new(self.inner.cat(&link).map(move |bytes| {
+            match cache.insert(&link, &bytes) {
+                Ok(()) => {}
+                Err(ref e) if e.downcast_ref::<StoreError>().is_some() => {
+                    debug!("Not caching {}: {}", link, e)
+                }
+                Err(e) => warn!("Could not write {} to object cache: {}", link, e),
+            }
+            bytes
+        }))
+    }
+
+    fn name_publish(&mut self, link: &str) -> StoreFuture<String> {
+        self.inner.name_publish(link)
+    }
+
+    fn name_resolve(&mut self, name: &str) -> StoreFuture<String> {
+        self.inner.name_resolve(name)
+    }
+}
+
+/// Which `ObjectCache` downloads go through, see `TransferOptions::cache`
+#[derive(Clone, Debug)]
+pub enum CachePolicy {
+    /// The default cache of the local repository, see `ObjectCache::for_repo()`
+    Repo,
+    /// A specific cache, e.g. one with a different size limit or location
+    Custom(ObjectCache),
+    /// Always download from the store
+    Disabled,
+}
+
+impl CachePolicy {
+    /// Wrap `inner` according to `self`, using `repo` for `CachePolicy::Repo`
+    pub fn wrap<S: ContentStore>(
+        &self,
+        inner: S,
+        repo: &Repository,
+    ) -> Result<CachedStore<S>, Error> {
+        let cache = match self {
+            CachePolicy::Repo => Some(ObjectCache::for_repo(repo)?),
+            CachePolicy::Custom(cache) => Some(cache.clone()),
+            CachePolicy::Disabled => None,
+        };
+
+        Ok(CachedStore { inner, cache })
+    }
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy::Repo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::runtime::current_thread;
+
+    use super::*;
+
+    use crate::test_utils::MockIpfsServer;
+
+    #[test]
+    fn test_cat_hits_cache() {
+        let dir = TempDir::new().unwrap();
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+        let link =
+            current_thread::block_on_all(ContentStore::add(&mut ipfs, b"nip".to_vec())).unwrap();
+
+        let mut store = CachedStore::new(ipfs, ObjectCache::open(dir.path(), 1024).unwrap());
+        for _ in 0..2 {
+            assert_eq!(
+                current_thread::block_on_all(store.cat(&link)).unwrap(),
+                b"nip".to_vec()
+            );
+        }
+        assert_eq!(server.request_count("/cat"), 1);
+
+        // The cache outlives the process
+        let mut store = CachedStore::new(
+            store.into_inner(),
+            ObjectCache::open(dir.path(), 1024).unwrap(),
+        );
+        current_thread::block_on_all(store.cat(&link)).unwrap();
+        assert_eq!(server.request_count("/cat"), 1);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let dir = TempDir::new().unwrap();
+        let cache = ObjectCache::open(dir.path(), 8).unwrap();
+        let link = |data: &[u8]| format!("/ipfs/{}", cid_v0(data).unwrap());
+
+        cache.insert(&link(b"1234"), b"1234").unwrap();
+        cache.insert(&link(b"5678"), b"5678").unwrap();
+        // Touch the older entry so that the newer one becomes the eviction candidate
+        assert!(cache.get(&link(b"1234")).unwrap().is_some());
+
+        cache.insert(&link(b"9abc"), b"9abc").unwrap();
+
+        assert!(cache.contains(&link(b"1234")));
+        assert!(!cache.contains(&link(b"5678")));
+        assert!(cache.contains(&link(b"9abc")));
+        assert_eq!(cache.total_bytes(), 8);
+
+        // Oversized blobs are never cached
+        cache.insert(&link(&[0; 9]), &[0; 9]).unwrap();
+        assert!(!cache.contains(&link(&[0; 9])));
+    }
+
+    #[test]
+    fn test_open_keeps_writes_in_progress() {
+        let dir = TempDir::new().unwrap();
+        let link = format!("/ipfs/{}", cid_v0(b"nip").unwrap());
+        let cache = ObjectCache::open(dir.path(), 1024).unwrap();
+        cache.insert(&link, b"nip").unwrap();
+
+        // Another process sharing the cache is in the middle of a write
+        let tmp_path = dir.path().join(".QmSomething.a1b2c3.tmp");
+        fs::write(&tmp_path, b"partial").unwrap();
+
+        let cache = ObjectCache::open(dir.path(), 1024).unwrap();
+        assert!(tmp_path.exists());
+        assert!(cache.contains(&link));
+        assert_eq!(cache.total_bytes(), 3);
+    }
+
+    #[test]
+    fn test_rejects_unverified_data() {
+        let dir = TempDir::new().unwrap();
+        let cache = ObjectCache::open(dir.path(), 1024).unwrap();
+        let link = format!("/ipfs/{}", cid_v0(b"nip").unwrap());
+        // A peer ID that is a valid CIDv0 as far as its looks go
+        let name = format!("/ipns/{}", cid_v0(b"nip").unwrap());
+
+        for bad_link in &["/ipfs/../../hooks/post-checkout", &name, "QmShort"] {
+            assert_eq!(
+                cache
+                    .insert(bad_link, b"nip")
+                    .unwrap_err()
+                    .downcast::<StoreError>()
+                    .unwrap(),
+                StoreError::InvalidLink((*bad_link).to_owned())
+            );
+            assert!(cache.get(bad_link).is_err());
+        }
+        assert_eq!(
+            cache
+                .insert(&link, b"not nip")
+                .unwrap_err()
+                .downcast::<StoreError>()
+                .unwrap(),
+            StoreError::HashMismatch(link.clone())
+        );
+        assert_eq!(cache.total_bytes(), 0);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // Links go-ipfs hands out for small files verify as well
+        cache
+            .insert("QmZULkCELmmk5XNfCgTnCyFgAVxBRBXyDHGGMVoLFLiXEN", b"hello\n")
+            .unwrap();
+        cache
+            .insert("/ipfs/QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH", b"")
+            .unwrap();
+        assert_eq!(cache.total_bytes(), 6);
+    }
+
+    #[test]
+    fn test_corrupted_files_are_not_served() {
+        let dir = TempDir::new().unwrap();
+        let link = format!("/ipfs/{}", cid_v0(b"nip").unwrap());
+        let other_link = format!("/ipfs/{}", cid_v0(b"pin").unwrap());
+        ObjectCache::open(dir.path(), 1024)
+            .unwrap()
+            .insert(&link, b"nip")
+            .unwrap();
+        fs::write(dir.path().join(checked_hash(&link).unwrap()), b"bad").unwrap();
+
+        let cache = ObjectCache::open(dir.path(), 1024).unwrap();
+        // Another process sharing the cache writes a file after it was opened
+        fs::write(dir.path().join(checked_hash(&other_link).unwrap()), b"xxx").unwrap();
+
+        for bad_link in &[&link, &other_link] {
+            assert_eq!(cache.get(bad_link).unwrap(), None);
+            assert!(!cache.contains(bad_link));
+        }
+        assert_eq!(cache.total_bytes(), 0);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // Intact files of other processes are served and tracked
+        fs::write(dir.path().join(checked_hash(&other_link).unwrap()), b"pin").unwrap();
+        assert_eq!(cache.get(&other_link).unwrap(), Some(b"pin".to_vec()));
+        assert!(cache.contains(&other_link));
+        assert_eq!(cache.total_bytes(), 3);
+
+        // A corrupted entry gets downloaded and cached again
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+        let link =
+            current_thread::block_on_all(ContentStore::add(&mut ipfs, b"nip".to_vec())).unwrap();
+        fs::write(dir.path().join(checked_hash(&link).unwrap()), b"bad").unwrap();
+        let mut store = CachedStore::new(ipfs, ObjectCache::open(dir.path(), 1024).unwrap());
+        assert_eq!(
+            current_thread::block_on_all(store.cat(&link)).unwrap(),
+            b"nip".to_vec()
+        );
+        assert_eq!(server.request_count("/cat"), 1);
+        assert_eq!(
+            fs::read(dir.path().join(checked_hash(&link).unwrap())).unwrap(),
+            b"nip".to_vec()
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! A `ContentStore` backend keeping everything in a local directory
+use failure::Error;
+use futures::future;
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use super::{bare_hash, checked_hash, cid_v0, ContentStore, StoreError, StoreFuture};
+use crate::util::write_atomic;
+
+/// A `ContentStore` writing every blob into a directory under its CIDv0 hash, so that a nip repo
+/// can be produced and consumed without IPFS (e.g. on a USB stick or an NFS share).
+///
+/// Layout:
+/// * `<root>/blobs/<hash>` - stored data
+/// * `<root>/names/<name>` - the `/ipfs/` link a published name points at
+/// * `<root>/name` - the name this store publishes under
+#[derive(Clone, Debug)]
+pub struct FsStore {
+    root: PathBuf,
+    own_name: String,
+}
+
+impl FsStore {
+    /// Open the store at `root`, initializing it first if necessary
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, Error> {
+        let root = root.as_ref().to_owned();
+        fs::create_dir_all(root.join("blobs"))?;
+        fs::create_dir_all(root.join("names"))?;
+
+        let name_path = root.join("name");
+        let own_name = match fs::read_to_string(&name_path) {
+            Ok(name) => name.trim().to_owned(),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let name = cid_v0(fs::canonicalize(&root)?.to_string_lossy().as_bytes())?;
+                debug!("Initializing new FsStore at {}", root.display());
+                write_atomic(&name_path, name.as_bytes())?;
+                name
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { root, own_name })
+    }
+
+    /// Returns the directory this store lives in
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn blob_path(&self, link: &str) -> Result<PathBuf, Error> {
+        Ok(self.root.join("blobs").join(checked_hash(link)?))
+    }
+
+    fn name_path(&self, name: &str) -> Result<PathBuf, Error> {
+        Ok(self.root.join("names").join(checked_hash(name)?))
+    }
+
+    fn add_sync(&mut self, data: &[u8]) -> Result<String, Error> {
+        let hash = cid_v0(data)?;
+        let path = self.blob_path(&hash)?;
+
+        // Content-addressed, so an existing file already holds exactly these bytes
+        if !path.exists() {
+            trace!(
+                "FsStore: writing {} bytes to {}",
+                data.len(),
+                path.display()
+            );
+            write_atomic(&path, data)?;
+        }
+
+        Ok(format!("/ipfs/{}", hash))
+    }
+
+    fn cat_sync(&mut self, link: &str) -> Result<Vec<u8>, Error> {
+        match fs::read(self.blob_path(link)?) {
+            Ok(bytes) => Ok(bytes),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                Err(StoreError::NotFound(link.to_owned()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn name_publish_sync(&mut self, link: &str) -> Result<String, Error> {
+        if !self.blob_path(link)?.exists() {
+            return Err(StoreError::NotFound(link.to_owned()).into());
+        }
+
+        let name_path = self.name_path(&self.own_name)?;
+        write_atomic(&name_path, format!("/ipfs/{}", bare_hash(link)).as_bytes())?;
+
+        Ok(format!("/ipns/{}", self.own_name))
+    }
+
+    fn name_resolve_sync(&mut self, name: &str) -> Result<String, Error> {
+        match fs::read_to_string(self.name_path(name)?) {
+            Ok(link) => Ok(link.trim().to_owned()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                Err(StoreError::NameNotFound(name.to_owned()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl ContentStore for FsStore {
+    fn add(&mut self, data: Vec<u8>) -> StoreFuture<String> {
+        Box::new(future::result(self.add_sync(&data)))
+    }
+
+    fn cat(&mut self, link: &str) -> StoreFuture<Vec<u8>> {
+        Box::new(future::result(self.cat_sync(link)))
+    }
+
+    fn name_publish(&mut self, link: &str) -> StoreFuture<String> {
+        Box::new(future::result(self.name_publish_sync(link)))
+    }
+
+    fn name_resolve(&mut self, name: &str) -> StoreFuture<String> {
+        Box::new(future::result(self.name_resolve_sync(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::runtime::current_thread;
+
+    use super::*;
+
+    #[test]
+    fn test_persists_across_reopen() {
+        let dir = TempDir::new().unwrap();
+
+        let (link, name) = {
+            let mut store = FsStore::open(dir.path()).unwrap();
+            let link = current_thread::block_on_all(store.add(b"nip".to_vec())).unwrap();
+            let name = current_thread::block_on_all(store.name_publish(&link)).unwrap();
+            (link, name)
+        };
+
+        let mut store = FsStore::open(dir.path()).unwrap();
+        assert_eq!(
+            current_thread::block_on_all(store.cat(&link)).unwrap(),
+            b"nip".to_vec()
+        );
+        assert_eq!(
+            current_thread::block_on_all(store.name_resolve(&name)).unwrap(),
+            link
+        );
+
+        // The store keeps its name when reopened
+        assert_eq!(
+            current_thread::block_on_all(store.name_publish(&link)).unwrap(),
+            name
+        );
+    }
+
+    #[test]
+    fn test_cat_missing_err() {
+        let dir = TempDir::new().unwrap();
+        let mut store = FsStore::open(dir.path()).unwrap();
+
+        match current_thread::block_on_all(store.cat("/ipfs/QmNothingHere")) {
+            Err(e) => assert_eq!(
+                e.downcast::<StoreError>().unwrap(),
+                StoreError::NotFound("/ipfs/QmNothingHere".to_owned())
+            ),
+            Ok(_) => panic!("Got an Ok, NotFound expected"),
+        }
+    }
+
+    #[test]
+    fn test_rejects_path_traversal() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("secret"), b"secret").unwrap();
+        let mut store = FsStore::open(dir.path().join("store")).unwrap();
+
+        for link in &["/ipfs/../../secret", "/ipns/../../secret"] {
+            match current_thread::block_on_all(store.cat(link)) {
+                Err(e) => assert_eq!(
+                    e.downcast::<StoreError>().unwrap(),
+                    StoreError::InvalidLink((*link).to_owned())
+                ),
+                Ok(_) => panic!("Got an Ok, InvalidLink expected"),
+            }
+        }
+        assert!(current_thread::block_on_all(store.name_resolve("/ipns/../../secret")).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+//! `ContentStore` implementation for the go-ipfs HTTP API client
+use failure::Error;
+use futures::{Future, Stream};
+use ipfs_api::IpfsClient;
+
+use std::io::Cursor;
+
+use super::{ContentStore, StoreFuture};
+
+impl ContentStore for IpfsClient {
+    fn add(&mut self, data: Vec<u8>) -> StoreFuture<String> {
+        Box::new(
+            IpfsClient::add(self, Cursor::new(data))
+                .map(|res| format!("/ipfs/{}", res.hash))
+                .map_err(Error::from),
+        )
+    }
+
+    fn cat(&mut self, link: &str) -> StoreFuture<Vec<u8>> {
+        Box::new(
+            IpfsClient::cat(self, link)
+                .concat2()
+                .map(|bytes| bytes.to_vec())
+                .map_err(Error::from),
+        )
+    }
+
+    fn name_publish(&mut self, link: &str) -> StoreFuture<String> {
+        Box::new(
+            IpfsClient::name_publish(self, link, true, None, None, None)
+                .map(|res| format!("/ipns/{}", res.name))
+                .map_err(Error::from),
+        )
+    }
+
+    fn name_resolve(&mut self, name: &str) -> StoreFuture<String> {
+        Box::new(
+            IpfsClient::name_resolve(self, Some(name), true, false)
+                .map(|res| {
+                    // go-ipfs answers with a full /ipfs/ path
+                    if res.path.starts_with("/ipfs/") {
+                        res.path
+                    } else {
+                        format!("/ipfs/{}", res.path)
+                    }
+                })
+                .map_err(Error::from),
+        )
+    }
+}
@@ -0,0 +1,193 @@
+//! A `ContentStore` backend living entirely in process memory
+use futures::future;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use super::{bare_hash, cid_v0, ContentStore, StoreError, StoreFuture};
+
+/// A `ContentStore` keeping all data and IPNS names in memory. Links are real CIDv0 hashes of the
+/// stored bytes, which makes it a drop-in stand-in for IPFS in tests and embedded setups. Clones
+/// share the same contents.
+#[derive(Clone, Debug)]
+pub struct InMemoryStore {
+    state: Arc<Mutex<MemoryState>>,
+    /// The name this store publishes under
+    own_name: String,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// Stored data; a {bare hash -> bytes} map
+    blobs: HashMap<String, Vec<u8>>,
+    /// Published names; a {bare name hash -> /ipfs/ link} map
+    names: HashMap<String, String>,
+}
+
+impl InMemoryStore {
+    /// Create an empty store publishing under a name derived from `key`; stores using the same
+    /// key publish under the same name.
+    pub fn with_key(key: &str) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MemoryState::default())),
+            own_name: cid_v0(key.as_bytes()).expect("SHA2-256 multihash is always supported"),
+        }
+    }
+
+    /// Create an empty store
+    pub fn new() -> Self {
+        Self::with_key("nip in-memory store")
+    }
+
+    /// Returns the number of distinct blobs in the store
+    pub fn len(&self) -> usize {
+        self.state().blobs.len()
+    }
+
+    /// Returns true if nothing was added to the store yet
+    pub fn is_empty(&self) -> bool {
+        self.state().blobs.is_empty()
+    }
+
+    /// Returns true if `link` is present in the store
+    pub fn contains(&self, link: &str) -> bool {
+        self.state().blobs.contains_key(bare_hash(link))
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        // No operation can panic halfway through a state update, so a poisoned lock is still
+        // consistent
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContentStore for InMemoryStore {
+    fn add(&mut self, data: Vec<u8>) -> StoreFuture<String> {
+        let hash = match cid_v0(&data) {
+            Ok(hash) => hash,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        trace!("InMemoryStore: adding {} bytes as {}", data.len(), hash);
+
+        let link = format!("/ipfs/{}", hash);
+        self.state().blobs.insert(hash, data);
+
+        Box::new(future::ok(link))
+    }
+
+    fn cat(&mut self, link: &str) -> StoreFuture<Vec<u8>> {
+        Box::new(future::result(
+            self.state()
+                .blobs
+                .get(bare_hash(link))
+                .cloned()
+                .ok_or_else(|| StoreError::NotFound(link.to_owned()).into()),
+        ))
+    }
+
+    fn name_publish(&mut self, link: &str) -> StoreFuture<String> {
+        let mut state = self.state();
+        if !state.blobs.contains_key(bare_hash(link)) {
+            return Box::new(future::err(StoreError::NotFound(link.to_owned()).into()));
+        }
+        state
+            .names
+            .insert(self.own_name.clone(), format!("/ipfs/{}", bare_hash(link)));
+
+        Box::new(future::ok(format!("/ipns/{}", self.own_name)))
+    }
+
+    fn name_resolve(&mut self, name: &str) -> StoreFuture<String> {
+        Box::new(future::result(
+            self.state()
+                .names
+                .get(bare_hash(name))
+                .cloned()
+                .ok_or_else(|| StoreError::NameNotFound(name.to_owned()).into()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::current_thread;
+
+    use super::*;
+
+    #[test]
+    fn test_add_cat_roundtrip() {
+        let mut store = InMemoryStore::new();
+
+        let link = current_thread::block_on_all(store.add(b"nip".to_vec())).unwrap();
+        assert!(link.starts_with("/ipfs/Qm"));
+        assert_eq!(
+            current_thread::block_on_all(store.cat(&link)).unwrap(),
+            b"nip".to_vec()
+        );
+        assert_eq!(
+            current_thread::block_on_all(store.cat(bare_hash(&link))).unwrap(),
+            b"nip".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_cat_missing_err() {
+        let mut store = InMemoryStore::new();
+
+        match current_thread::block_on_all(store.cat("/ipfs/QmNothingHere")) {
+            Err(e) => assert_eq!(
+                e.downcast::<StoreError>().unwrap(),
+                StoreError::NotFound("/ipfs/QmNothingHere".to_owned())
+            ),
+            Ok(_) => panic!("Got an Ok, NotFound expected"),
+        }
+    }
+
+    #[test]
+    fn test_clones_share_contents() {
+        let mut store = InMemoryStore::new();
+        let mut clone = store.clone();
+
+        let link = current_thread::block_on_all(clone.add(b"nip".to_vec())).unwrap();
+        assert!(store.contains(&link));
+        assert_eq!(
+            current_thread::block_on_all(store.cat(&link)).unwrap(),
+            b"nip".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_name_publish_resolve() {
+        let mut store = InMemoryStore::new();
+
+        let first = current_thread::block_on_all(store.add(b"first".to_vec())).unwrap();
+        let second = current_thread::block_on_all(store.add(b"second".to_vec())).unwrap();
+
+        let name = current_thread::block_on_all(store.name_publish(&first)).unwrap();
+        assert!(name.starts_with("/ipns/"));
+        assert_eq!(
+            current_thread::block_on_all(store.name_resolve(&name)).unwrap(),
+            first
+        );
+
+        // Republishing keeps the name and moves the pointer
+        assert_eq!(
+            current_thread::block_on_all(store.name_publish(&second)).unwrap(),
+            name
+        );
+        assert_eq!(
+            current_thread::block_on_all(store.name_resolve(&name)).unwrap(),
+            second
+        );
+    }
+}
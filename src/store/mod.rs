@@ -0,0 +1,125 @@
+//! Content store abstraction. Every nip data structure is written to and read from a
+//! `ContentStore`; talking to a go-ipfs daemon through `IpfsClient` is just one of the backends.
+use failure::Error;
+use futures::Future;
+use multibase::Base;
+use multihash::Hash;
+
+mod cache;
+mod fs;
+mod ipfs;
+mod memory;
+
+pub use self::{
+    cache::{CachePolicy, CachedStore, ObjectCache},
+    fs::FsStore,
+    memory::InMemoryStore,
+};
+
+/// A boxed future returned by `ContentStore` operations and the asynchronous nip APIs built on
+/// top of them
+pub type StoreFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// A content-addressed storage backend for nip indices, nip objects and raw git object data.
+///
+/// Links handed out by a store are IPFS-style `/ipfs/<hash>` paths and names are IPNS-style
+/// `/ipns/<hash>` paths. Both the prefixed and the bare hash form must be accepted as input.
+///
+/// Stores are handles: transfers clone them into the futures they return, so all clones of a
+/// store must share the same underlying storage.
+pub trait ContentStore: Clone + Send + 'static {
+    /// Store `data` and return its `/ipfs/` link
+    fn add(&mut self, data: Vec<u8>) -> StoreFuture<String>;
+    /// Retrieve the bytes behind `link`
+    fn cat(&mut self, link: &str) -> StoreFuture<Vec<u8>>;
+    /// Point this store's name at `link` and return the name as an `/ipns/` link
+    fn name_publish(&mut self, link: &str) -> StoreFuture<String>;
+    /// Return the `/ipfs/` link that `name` currently points at
+    fn name_resolve(&mut self, name: &str) -> StoreFuture<String>;
+}
+
+#[derive(Clone, Debug, Eq, Fail, PartialEq)]
+/// Errors returned by the built-in `ContentStore` backends
+pub enum StoreError {
+    /// Nothing is stored under the specified link
+    #[fail(display = "Link {} not found in store", _0)]
+    NotFound(String),
+    /// The specified name was never published
+    #[fail(display = "Name {} not published in store", _0)]
+    NameNotFound(String),
+    /// The specified link isn't a (possibly `/ipfs/`- or `/ipns/`-prefixed) base58 hash
+    #[fail(display = "Invalid link {:?}", _0)]
+    InvalidLink(String),
+    /// The data doesn't hash to the link it was stored under
+    #[fail(display = "Data doesn't match link {}", _0)]
+    HashMismatch(String),
+}
+
+/// The characters of base58btc, which every CIDv0 hash and IPNS name is written in
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Compute the CIDv0 (base58 SHA2-256 multihash, "Qm...") of `data`
+pub fn cid_v0(data: &[u8]) -> Result<String, Error> {
+    let mh = multihash::encode(Hash::SHA2256, data)?;
+
+    // CIDv0 is plain base58 without the multibase prefix character
+    Ok(multibase::encode(Base::Base58btc, mh)[1..].to_owned())
+}
+
+/// Strip an optional `/ipfs/` or `/ipns/` prefix from `link`, leaving the bare hash
+pub fn bare_hash(link: &str) -> &str {
+    if link.starts_with("/ipfs/") || link.starts_with("/ipns/") {
+        &link[6..]
+    } else {
+        link
+    }
+}
+
+/// Like `bare_hash()`, but fails with `StoreError::InvalidLink` unless what's left is a base58
+/// hash. Backends building file paths out of links must go through this, since links come from
+/// untrusted indices and a hash like `../../hooks/x` would escape the store directory.
+pub fn checked_hash(link: &str) -> Result<&str, Error> {
+    let hash = bare_hash(link);
+    if hash.is_empty() || !hash.chars().all(|c| BASE58_ALPHABET.contains(c)) {
+        return Err(StoreError::InvalidLink(link.to_owned()).into());
+    }
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::constants::IPFS_HASH_LEN;
+
+    #[test]
+    fn test_cid_v0_matches_known_digest() {
+        // base58 SHA2-256 multihash of an empty input
+        let cid = cid_v0(b"").unwrap();
+
+        assert_eq!(cid, "QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n");
+        assert_eq!(cid.len(), IPFS_HASH_LEN);
+    }
+
+    #[test]
+    fn test_bare_hash() {
+        assert_eq!(bare_hash("/ipfs/QmFoo"), "QmFoo");
+        assert_eq!(bare_hash("/ipns/QmFoo"), "QmFoo");
+        assert_eq!(bare_hash("QmFoo"), "QmFoo");
+    }
+
+    #[test]
+    fn test_checked_hash() {
+        assert_eq!(checked_hash("/ipfs/QmFoo").unwrap(), "QmFoo");
+        for link in &["", "/ipfs/", "/ipfs/../../hooks/x", "/ipfs/Qm/Foo", "QmF00"] {
+            assert_eq!(
+                checked_hash(link)
+                    .unwrap_err()
+                    .downcast::<StoreError>()
+                    .unwrap(),
+                StoreError::InvalidLink((*link).to_owned())
+            );
+        }
+    }
+}
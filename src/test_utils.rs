@@ -0,0 +1,461 @@
+//! Test support code; controlled by the `test-utils` feature.
+//!
+//! `MockIpfsServer` is a local stand-in for a go-ipfs daemon implementing the subset of its HTTP
+//! API that `nip_core` relies on (`add`, `cat`, `name/publish` and `name/resolve`), backed by an
+//! `InMemoryStore`. Failures and latency can be injected per endpoint.
+use failure::Error;
+use futures::{future, sync::oneshot, Future, Stream};
+#[cfg(test)]
+use git2::{Repository, Signature, Time};
+use hyper::{
+    header::CONTENT_TYPE, service::service_fn, Body, Request, Response, Server, StatusCode,
+};
+use ipfs_api::IpfsClient;
+#[cfg(test)]
+use tempfile::TempDir;
+use tokio::{runtime::Runtime, timer::Delay};
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::store::{bare_hash, ContentStore, InMemoryStore};
+
+/// A mock go-ipfs HTTP API server running on a background thread. It shuts down when dropped.
+///
+/// ```rust
+/// # extern crate nip_core;
+/// # use nip_core::{test_utils::MockIpfsServer, NIPIndex, NIPRemote};
+/// let server = MockIpfsServer::start().unwrap();
+/// let mut ipfs = server.client().unwrap();
+///
+/// let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+/// let remote = idx.ipfs_add(&mut ipfs, None).unwrap();
+///
+/// assert_eq!(NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap(), idx);
+/// assert_eq!(server.request_count("/add"), 1);
+/// ```
+pub struct MockIpfsServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    store: InMemoryStore,
+    /// Artificial delay applied to every request
+    latency: Option<Duration>,
+    /// Injected failures left; a {endpoint -> count} map
+    failures: HashMap<String, usize>,
+    /// Requests received so far; a {endpoint -> count} map
+    requests: HashMap<String, usize>,
+    /// Requests currently being served
+    in_flight: usize,
+    /// The highest `in_flight` value seen so far
+    max_in_flight: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AddResponse {
+    name: String,
+    hash: String,
+    size: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct NamePublishResponse {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct NameResolveResponse {
+    path: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ApiError {
+    message: String,
+    code: u8,
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = io::Error> + Send>;
+
+impl MockIpfsServer {
+    /// Start a server listening on a random localhost port
+    pub fn start() -> Result<Self, Error> {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let (addr_tx, addr_rx) = mpsc::channel();
+
+        let server_state = state.clone();
+        let thread = thread::spawn(move || {
+            let mut rt = Runtime::new().expect("Could not start mock IPFS runtime");
+
+            let server = match Server::try_bind(&([127, 0, 0, 1], 0).into()) {
+                Ok(builder) => builder.serve(move || {
+                    let state = server_state.clone();
+                    service_fn(move |req| handle(&state, req))
+                }),
+                Err(e) => {
+                    addr_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+            addr_tx.send(Ok(server.local_addr())).ok();
+
+            let graceful = server
+                .with_graceful_shutdown(shutdown_rx.then(|_| Ok::<(), ()>(())))
+                .map_err(|e| error!("Mock IPFS server error: {}", e));
+            rt.block_on(graceful).ok();
+            rt.shutdown_now().wait().ok();
+        });
+
+        let addr = addr_rx.recv()??;
+        debug!("Mock IPFS server listening on {}", addr);
+
+        Ok(Self {
+            addr,
+            state,
+            shutdown_tx: Some(shutdown_tx),
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the address the server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns an `IpfsClient` talking to this server
+    pub fn client(&self) -> Result<IpfsClient, Error> {
+        Ok(IpfsClient::new(
+            &self.addr.ip().to_string(),
+            self.addr.port(),
+        )?)
+    }
+
+    /// Delay every subsequent response by `latency`
+    pub fn set_latency(&self, latency: Option<Duration>) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Make the next `count` requests to `endpoint` (e.g. `"/add"`, `"/name/resolve"`) fail with
+    /// an API error
+    pub fn fail_next(&self, endpoint: &str, count: usize) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .insert(endpoint.to_owned(), count);
+    }
+
+    /// Returns how many requests `endpoint` received so far
+    pub fn request_count(&self, endpoint: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .get(endpoint)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Returns the highest number of requests the server was serving at the same time
+    pub fn max_concurrent_requests(&self) -> usize {
+        self.state.lock().unwrap().max_in_flight
+    }
+
+    /// Returns a handle to the store backing the server
+    pub fn store(&self) -> InMemoryStore {
+        self.state.lock().unwrap().store.clone()
+    }
+}
+
+impl Drop for MockIpfsServer {
+    fn drop(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            shutdown_tx.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+fn handle(state: &Arc<Mutex<MockState>>, req: Request<Body>) -> ResponseFuture {
+    let endpoint = req.uri().path().trim_start_matches("/api/v0").to_owned();
+    let query = req.uri().query().unwrap_or("").to_owned();
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned());
+
+    trace!("Mock IPFS server: {} {}?{}", req.method(), endpoint, query);
+
+    let latency = {
+        let mut state = state.lock().unwrap();
+        *state.requests.entry(endpoint.clone()).or_insert(0) += 1;
+        state.in_flight += 1;
+        state.max_in_flight = state.max_in_flight.max(state.in_flight);
+        state.latency
+    };
+    let delay: Box<dyn Future<Item = (), Error = io::Error> + Send> = match latency {
+        Some(latency) => Box::new(
+            Delay::new(Instant::now() + latency)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        ),
+        None => Box::new(future::ok(())),
+    };
+
+    // Counted as in flight until dropped, so that failed requests stop counting as well
+    let in_flight = InFlightGuard(state.clone());
+
+    let state = state.clone();
+    Box::new(
+        req.into_body()
+            .concat2()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .join(delay)
+            .map(move |(body, ())| {
+                drop(in_flight);
+                let mut state = state.lock().unwrap();
+
+                if let Some(remaining) = state.failures.get_mut(&endpoint) {
+                    if *remaining > 0 {
+                        *remaining -= 1;
+                        debug!("Mock IPFS server: injecting failure for {}", endpoint);
+                        return api_error(&format!("Injected failure for {}", endpoint));
+                    }
+                }
+
+                dispatch(
+                    &mut state.store,
+                    &endpoint,
+                    &query,
+                    content_type.as_deref(),
+                    &body,
+                )
+                .unwrap_or_else(|e| api_error(&e.to_string()))
+            }),
+    )
+}
+
+/// Takes a request off `MockState::in_flight` when dropped
+struct InFlightGuard(Arc<Mutex<MockState>>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.lock() {
+            state.in_flight -= 1;
+        }
+    }
+}
+
+fn dispatch(
+    store: &mut InMemoryStore,
+    endpoint: &str,
+    query: &str,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<Response<Body>, Error> {
+    let args: Vec<(String, String)> = serde_urlencoded::from_str(query)?;
+    let arg = args
+        .into_iter()
+        .find(|(key, _)| key == "arg")
+        .map(|(_, value)| value);
+
+    // In-memory store futures are always ready, so waiting on them never blocks
+    match (endpoint, arg) {
+        ("/add", _) => {
+            let data = multipart_payload(content_type, body)?;
+            let size = data.len();
+            let link = store.add(data).wait()?;
+            let hash = bare_hash(&link).to_owned();
+
+            json_response(&AddResponse {
+                name: hash.clone(),
+                hash,
+                size: size.to_string(),
+            })
+        }
+        ("/cat", Some(link)) => Ok(Response::new(Body::from(store.cat(&link).wait()?))),
+        ("/name/publish", Some(link)) => {
+            let name = store.name_publish(&link).wait()?;
+
+            json_response(&NamePublishResponse {
+                name: bare_hash(&name).to_owned(),
+                value: link,
+            })
+        }
+        ("/name/resolve", Some(name)) => json_response(&NameResolveResponse {
+            path: store.name_resolve(&name).wait()?,
+        }),
+        (endpoint, arg) => bail!("Unsupported request {} (arg {:?})", endpoint, arg),
+    }
+}
+
+/// Extract the first part of a multipart/form-data `body`
+fn multipart_payload(content_type: Option<&str>, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let boundary = content_type
+        .and_then(|ct| ct.split("boundary=").nth(1))
+        .map(|boundary| format!("--{}", boundary.trim_matches('"')))
+        .ok_or_else(|| format_err!("Missing multipart boundary in {:?}", content_type))?;
+
+    let part_start = find(body, boundary.as_bytes())
+        .ok_or_else(|| format_err!("Multipart body has no opening boundary"))?
+        + boundary.len();
+    let part = &body[part_start..];
+
+    let data_start = find(part, b"\r\n\r\n")
+        .ok_or_else(|| format_err!("Multipart part has no header terminator"))?
+        + 4;
+    let data = &part[data_start..];
+
+    let closing = format!("\r\n{}", boundary);
+    let data_end = find(data, closing.as_bytes())
+        .ok_or_else(|| format_err!("Multipart body has no closing boundary"))?;
+
+    Ok(data[..data_end].to_vec())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn json_response<T: serde::Serialize>(payload: &T) -> Result<Response<Body>, Error> {
+    Ok(Response::new(Body::from(serde_json::to_vec(payload)?)))
+}
+
+fn api_error(message: &str) -> Response<Body> {
+    let payload = ApiError {
+        message: message.to_owned(),
+        code: 0,
+    };
+    let mut res = Response::new(Body::from(
+        serde_json::to_vec(&payload).unwrap_or_else(|_| message.as_bytes().to_vec()),
+    ));
+    *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+
+    res
+}
+
+#[cfg(test)]
+/// Create a repo with a few commits on `refs/heads/master`, every commit adding one file
+pub(crate) fn repo_with_history(n_commits: usize) -> (TempDir, Repository) {
+    let dir = TempDir::new().unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+
+    {
+        let sig = Signature::new("nip", "nip@example.com", &Time::new(0, 0)).unwrap();
+        let mut parent = None;
+        let mut builder = repo.treebuilder(None).unwrap();
+
+        for i in 0..n_commits {
+            let blob = repo.blob(format!("content {}", i).as_bytes()).unwrap();
+            builder
+                .insert(format!("file_{}", i), blob, 0o100_644)
+                .unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+
+            let parents: Vec<_> = parent.iter().collect();
+            let commit_oid = repo
+                .commit(
+                    Some("refs/heads/master"),
+                    &sig,
+                    &sig,
+                    &format!("Commit {}", i),
+                    &tree,
+                    &parents,
+                )
+                .unwrap();
+            parent = Some(repo.find_commit(commit_oid).unwrap());
+        }
+    }
+
+    (dir, repo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{index::NIPIndex, remote::NIPRemote};
+
+    #[test]
+    fn test_push_fetch_through_http() {
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+        let (_src_dir, mut src_repo) = repo_with_history(1);
+        let dst_dir = TempDir::new().unwrap();
+        let mut dst_repo = Repository::init(dst_dir.path()).unwrap();
+
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPNS, &mut ipfs).unwrap();
+        idx.push_ref_from_str(
+            "refs/heads/master",
+            "refs/heads/master",
+            false,
+            &mut src_repo,
+            &mut ipfs,
+        )
+        .unwrap();
+        let remote = idx.ipfs_add(&mut ipfs, Some(&NIPRemote::NewIPNS)).unwrap();
+        assert!(remote.is_ipns());
+
+        let fetched_idx = NIPIndex::from_nip_remote(&remote, &mut ipfs).unwrap();
+        assert_eq!(fetched_idx, idx);
+
+        let tip = fetched_idx.refs["refs/heads/master"].clone();
+        fetched_idx
+            .fetch_to_ref_from_str(&tip, "refs/heads/master", &mut dst_repo, &mut ipfs)
+            .unwrap();
+        assert_eq!(
+            dst_repo.refname_to_id("refs/heads/master").unwrap(),
+            src_repo.refname_to_id("refs/heads/master").unwrap()
+        );
+
+        assert_eq!(server.request_count("/name/publish"), 1);
+        assert_eq!(server.request_count("/name/resolve"), 1);
+    }
+
+    #[test]
+    fn test_injected_failure() {
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+
+        server.fail_next("/add", 1);
+        assert!(idx.ipfs_add(&mut ipfs, None).is_err());
+
+        // Only the next request was meant to fail
+        assert!(idx.ipfs_add(&mut ipfs, None).is_ok());
+        assert_eq!(server.request_count("/add"), 2);
+    }
+
+    #[test]
+    fn test_latency() {
+        let server = MockIpfsServer::start().unwrap();
+        let mut ipfs = server.client().unwrap();
+        let mut idx = NIPIndex::from_nip_remote(&NIPRemote::NewIPFS, &mut ipfs).unwrap();
+
+        server.set_latency(Some(Duration::from_millis(200)));
+
+        let start = Instant::now();
+        idx.ipfs_add(&mut ipfs, None).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,237 @@
+//! Settings shared by push and fetch
+use failure::Error;
+
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{constants::DEFAULT_MAX_IN_FLIGHT, store::CachePolicy};
+
+/// Knobs controlling how push and fetch talk to a `ContentStore`.
+#[derive(Clone)]
+pub struct TransferOptions {
+    /// The maximum number of objects transferred concurrently; values below 1 are treated as 1
+    pub max_in_flight: usize,
+    /// Receives progress events as the transfer goes
+    pub progress: Option<Arc<dyn Progress>>,
+    /// Stops the transfer at the next object boundary once cancelled
+    pub cancellation: CancellationToken,
+    /// Where pushes keep a `PushJournal` so that `NIPIndex::resume_push()` can pick up after a
+    /// crash, e.g. `PushJournal::dir_for_repo()`. Concurrent pushes must not share a directory.
+    pub journal: Option<PathBuf>,
+    /// Where downloads of immutable data are cached; the repository's `ObjectCache` by default
+    pub cache: CachePolicy,
+}
+
+impl Default for TransferOptions {
+    fn default() -> Self {
+        Self {
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            progress: None,
+            cancellation: CancellationToken::new(),
+            journal: None,
+            cache: CachePolicy::default(),
+        }
+    }
+}
+
+impl fmt::Debug for TransferOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TransferOptions")
+            .field("max_in_flight", &self.max_in_flight)
+            .field("progress", &self.progress.as_ref().map(|_| "..."))
+            .field("cancellation", &self.cancellation)
+            .field("journal", &self.journal)
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Eq, Fail, PartialEq)]
+/// Errors related to the `transfer` module
+pub enum TransferError {
+    /// The transfer was stopped through its `CancellationToken`
+    #[fail(display = "transfer cancelled")]
+    Cancelled,
+}
+
+/// A handle for aborting a push or fetch from another thread or task. Clones share the cancelled
+/// state.
+///
+/// Cancelled transfers stop starting new objects, wait for the ones in flight and fail with
+/// `TransferError::Cancelled`. The index is never modified by a cancelled push; a cancelled fetch
+/// may leave some unreferenced objects in the local repo.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a token that isn't cancelled yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel all transfers using this token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if `cancel()` was called on this token or any of its clones
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fail with `TransferError::Cancelled` if the token is cancelled
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            debug!("Transfer cancelled");
+            return Err(TransferError::Cancelled.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// The stages of a push or fetch, in the order they happen
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TransferPhase {
+    /// Walking the object graph to find out what needs transferring. Fetches download nip
+    /// objects during this phase.
+    Counting,
+    /// Uploading git objects (push)
+    Uploading,
+    /// Downloading git objects (fetch)
+    Downloading,
+}
+
+/// A structured progress report
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProgressEvent {
+    /// `phase` began; `total` is the number of objects it will handle if known upfront
+    #[allow(missing_docs)]
+    Started {
+        phase: TransferPhase,
+        total: Option<usize>,
+    },
+    /// Object number `current` (counting from 1) of `phase` is done; `bytes` were transferred for
+    /// it
+    #[allow(missing_docs)]
+    Object {
+        phase: TransferPhase,
+        current: usize,
+        total: Option<usize>,
+        bytes: u64,
+    },
+    /// `phase` is over after handling `objects` objects and transferring `bytes` bytes in total
+    #[allow(missing_docs)]
+    Finished {
+        phase: TransferPhase,
+        objects: usize,
+        bytes: u64,
+    },
+}
+
+/// A sink for `ProgressEvent`s, e.g. a progress bar. Events may arrive from any thread.
+///
+/// Closures taking a `&ProgressEvent` implement this trait:
+///
+/// ```rust
+/// # extern crate nip_core;
+/// # use nip_core::{ProgressEvent, TransferOptions};
+/// # use std::sync::Arc;
+/// let opts = TransferOptions {
+///     progress: Some(Arc::new(|event: &ProgressEvent| eprintln!("{:?}", event))),
+///     ..Default::default()
+/// };
+/// ```
+pub trait Progress: Send + Sync {
+    /// Handle `event`
+    fn on_event(&self, event: &ProgressEvent);
+}
+
+impl<F: Fn(&ProgressEvent) + Send + Sync> Progress for F {
+    fn on_event(&self, event: &ProgressEvent) {
+        self(event)
+    }
+}
+
+/// Keeps the running totals of a transfer phase and reports them to a `Progress` sink. Phases
+/// with a known total finish by themselves once the last object is counted.
+pub(crate) struct PhaseTracker {
+    phase: TransferPhase,
+    total: Option<usize>,
+    objects: usize,
+    bytes: u64,
+    finished: bool,
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl PhaseTracker {
+    /// Report the start of `phase` to `opts.progress`
+    pub(crate) fn start(
+        phase: TransferPhase,
+        total: Option<usize>,
+        opts: &TransferOptions,
+    ) -> Self {
+        let mut tracker = Self {
+            phase,
+            total,
+            objects: 0,
+            bytes: 0,
+            finished: false,
+            progress: opts.progress.clone(),
+        };
+        tracker.report(ProgressEvent::Started { phase, total });
+        if total == Some(0) {
+            tracker.finish();
+        }
+
+        tracker
+    }
+
+    /// Count one more object that took `bytes` to transfer
+    pub(crate) fn object(&mut self, bytes: u64) {
+        self.objects += 1;
+        self.bytes += bytes;
+        self.report(ProgressEvent::Object {
+            phase: self.phase,
+            current: self.objects,
+            total: self.total,
+            bytes,
+        });
+        if self.total == Some(self.objects) {
+            self.finish();
+        }
+    }
+
+    /// Returns the number of objects counted so far
+    pub(crate) fn objects(&self) -> usize {
+        self.objects
+    }
+
+    /// Report the end of the phase unless that already happened
+    pub(crate) fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        self.report(ProgressEvent::Finished {
+            phase: self.phase,
+            objects: self.objects,
+            bytes: self.bytes,
+        });
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        if let Some(progress) = self.progress.as_ref() {
+            progress.on_event(&event);
+        }
+    }
+}
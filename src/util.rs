@@ -3,14 +3,16 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use env_logger::Builder;
 use failure::Error;
-use futures::Stream;
-use ipfs_api::IpfsClient;
+use git2::Repository;
 use log::LevelFilter;
 use tokio::runtime::current_thread;
 
-use std::env;
+use std::{env, io::Write, path::Path};
 
-use crate::constants::{NIP_HEADER_LEN, NIP_MAGIC, NIP_PROTOCOL_VERSION};
+use crate::{
+    constants::{NIP_HEADER_LEN, NIP_MAGIC, NIP_PROTOCOL_VERSION},
+    store::ContentStore,
+};
 
 /// This helper function initializes logging on the supplied level unless RUST_LOG was specified
 pub fn init_logging(default_lvl: LevelFilter) {
@@ -52,17 +54,48 @@ pub fn gen_nip_header(version: Option<u16>) -> Result<Vec<u8>, Error> {
     Ok(ret)
 }
 
-/// A blocking shortcut to download `hash` from IPFS and return the object's bytes
-pub fn ipfs_cat(hash: &str, ipfs: &mut IpfsClient) -> Result<Vec<u8>, Error> {
-    let req = ipfs.cat(hash).concat2();
+/// A blocking shortcut to download `hash` from `ipfs` and return the object's bytes. Not for use
+/// inside a tokio runtime, where `ContentStore::cat()` can be used directly.
+pub fn ipfs_cat<S: ContentStore>(hash: &str, ipfs: &mut S) -> Result<Vec<u8>, Error> {
+    let req = ipfs.cat(hash);
 
-    Ok((&current_thread::block_on_all(req)?[..]).to_vec())
+    current_thread::block_on_all(req)
 }
 
-/// Returns the underlying IPFS link from an IPNS record
-pub fn ipns_deref(ipns_hash: &str, ipfs: &mut IpfsClient) -> Result<String, Error> {
-    let req = ipfs.name_resolve(Some(&ipns_hash), true, false);
-    let ipfs_hash = current_thread::block_on_all(req)?;
+/// Returns the underlying IPFS link from an IPNS record. Blocking only; inside a tokio runtime
+/// use `ContentStore::name_resolve()` directly.
+pub fn ipns_deref<S: ContentStore>(ipns_hash: &str, ipfs: &mut S) -> Result<String, Error> {
+    let req = ipfs.name_resolve(ipns_hash);
 
-    Ok(format!("/ipfs/{}", ipfs_hash.path))
+    current_thread::block_on_all(req)
+}
+
+/// Open another handle to `repo`. Futures can own the new handle instead of borrowing the
+/// caller's.
+pub fn reopen_repo(repo: &Repository) -> Result<Repository, Error> {
+    Ok(Repository::open(repo.path())?)
+}
+
+/// Write `data` to a temporary file next to `path` and move it in place, so that readers never
+/// observe a half-written file. Every write gets a temporary file of its own, named
+/// `.<file name>.<random>.tmp`, so that concurrent writers can't clobber each other's.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        ".{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    let mut tmp_file = tempfile::Builder::new()
+        .prefix(&prefix)
+        .suffix(".tmp")
+        .tempfile_in(dir)?;
+    tmp_file.write_all(data)?;
+    tmp_file.as_file().sync_all()?;
+    tmp_file.persist(path).map_err(|e| e.error)?;
+
+    Ok(())
 }